@@ -0,0 +1,196 @@
+//! Authentication of named users and per-key-prefix access control lists.
+//!
+//! The ACL file is JSON, listing each user with their password and/or tokens
+//! and the key prefixes they may read or write:
+//!
+//! ```json
+//! {
+//!   "users": [
+//!     {
+//!       "name": "admin",
+//!       "password": "hunter2",
+//!       "grants": [{ "prefix": "", "read": true, "write": true }]
+//!     },
+//!     {
+//!       "name": "cache",
+//!       "tokens": ["8f14e45fceea167a"],
+//!       "grants": [{ "prefix": "cache/", "read": true }]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Any authenticated user may read the server's status, which reveals its
+//! last sequence number and its leader or Raft peers, but no keys.
+//!
+//! Secrets are stored as given, so the file should only be readable by the
+//! user running `kvs-server`.
+
+use crate::{KvStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// The secret a user proves their identity with during the handshake.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Credential {
+    Password(String),
+    Token(String),
+}
+
+/// The kind of access a request needs on a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Access {
+    Read,
+    Write,
+}
+
+/// Grants read and/or write access to every key starting with `prefix`. An
+/// empty prefix matches every key.
+#[derive(Debug, Deserialize)]
+struct Grant {
+    prefix: String,
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    write: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    grants: Vec<Grant>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AclFile {
+    users: Vec<User>,
+}
+
+/// The users allowed to connect to a `KvsServer` and the keys each may access.
+#[derive(Debug)]
+pub struct Acl {
+    users: HashMap<String, User>,
+}
+
+impl Acl {
+    /// Loads the ACL from a JSON file.
+    pub fn load(path: &Path) -> Result<Acl> {
+        let file: AclFile = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+        let mut users = HashMap::new();
+        for user in file.users {
+            if users.contains_key(&user.name) {
+                return Err(KvStoreError::StringError(format!(
+                    "user {} is listed more than once in {}",
+                    user.name,
+                    path.display()
+                )));
+            }
+            users.insert(user.name.clone(), user);
+        }
+
+        Ok(Acl { users })
+    }
+}
+
+/// The authentication state of a single connection.
+///
+/// Without an ACL every request is allowed. With one, requests are denied
+/// until the connection authenticates and then checked against the user's
+/// grants.
+pub(crate) struct Session<'a> {
+    acl: Option<&'a Acl>,
+    user: Option<&'a User>,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn new(acl: Option<&'a Acl>) -> Self {
+        Session { acl, user: None }
+    }
+
+    /// Authenticates the connection as `name`, replacing any previous user.
+    pub(crate) fn authenticate(
+        &mut self,
+        name: &str,
+        credential: &Credential,
+    ) -> std::result::Result<(), String> {
+        let acl = match self.acl {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+
+        self.user = None;
+        let user = acl
+            .users
+            .get(name)
+            .filter(|user| match credential {
+                Credential::Password(p) => user
+                    .password
+                    .as_ref()
+                    .is_some_and(|expected| secrets_eq(expected, p)),
+                Credential::Token(t) => user.tokens.iter().any(|expected| secrets_eq(expected, t)),
+            })
+            .ok_or_else(|| format!("invalid credentials for user {}", name))?;
+
+        self.user = Some(user);
+        Ok(())
+    }
+
+    /// Checks the connection has authenticated as any user, for requests not
+    /// about a key, such as the server's status.
+    pub(crate) fn check_authenticated(&self) -> std::result::Result<(), String> {
+        match (self.acl, self.user) {
+            (Some(_), None) => Err("authentication required".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the connection may access the key.
+    pub(crate) fn check(&self, key: &[u8], access: Access) -> std::result::Result<(), String> {
+        if self.acl.is_none() {
+            return Ok(());
+        }
+
+        let user = self
+            .user
+            .ok_or_else(|| "authentication required".to_string())?;
+
+        let granted = user.grants.iter().any(|g| {
+            key.starts_with(g.prefix.as_bytes())
+                && match access {
+                    Access::Read => g.read,
+                    Access::Write => g.write,
+                }
+        });
+
+        if granted {
+            Ok(())
+        } else {
+            let verb = match access {
+                Access::Read => "read",
+                Access::Write => "write",
+            };
+            Err(format!(
+                "user {} may not {} key {}",
+                user.name,
+                verb,
+                String::from_utf8_lossy(key)
+            ))
+        }
+    }
+}
+
+/// Private helper function to compare secrets in constant time, so the time
+/// taken doesn't reveal how much of a guess was right.
+fn secrets_eq(expected: &str, given: &str) -> bool {
+    let (a, b) = (expected.as_bytes(), given.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
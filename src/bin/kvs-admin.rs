@@ -0,0 +1,326 @@
+#[macro_use]
+extern crate clap;
+extern crate structopt;
+
+use kvs::{
+    read_engine_marker, write_engine_marker, Change, KvStore, KvStoreError, KvsEngine, KvsSnapshot,
+    LogReader, RecoveryTarget, Result, SledKvsEngine,
+};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+
+/// Offline administration of a Key/Value store's data directory. The server
+/// must not be running on the directory.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "kvs-admin",
+    about = "Offline administration of a kvs data directory"
+)]
+enum Opt {
+    /// Prints every record of the log with its offset, length and status
+    #[structopt(name = "dump")]
+    Dump {
+        #[structopt(help = "The data directory", parse(from_os_str))]
+        data: PathBuf,
+    },
+
+    /// Checks every record of the log against its checksum
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(help = "The data directory", parse(from_os_str))]
+        data: PathBuf,
+    },
+
+    /// Counts the live and dead keys and bytes in the log
+    #[structopt(name = "stats")]
+    Stats {
+        #[structopt(help = "The data directory", parse(from_os_str))]
+        data: PathBuf,
+    },
+
+    /// Rewrites the log with only the records of live keys
+    #[structopt(name = "compact")]
+    Compact {
+        #[structopt(help = "The data directory", parse(from_os_str))]
+        data: PathBuf,
+    },
+
+    /// Truncates the log at its first torn or corrupt record
+    #[structopt(name = "repair")]
+    Repair {
+        #[structopt(help = "The data directory", parse(from_os_str))]
+        data: PathBuf,
+
+        #[structopt(
+            long,
+            help = "Also truncates intact records after the damaged one, losing their writes"
+        )]
+        discard: bool,
+    },
+
+    /// Writes the store as of an earlier write to a new directory
+    #[structopt(name = "recover")]
+    Recover {
+        #[structopt(help = "The data directory to recover from", parse(from_os_str))]
+        data: PathBuf,
+
+        #[structopt(
+            help = "The new directory to write the recovered store to",
+            parse(from_os_str)
+        )]
+        dest: PathBuf,
+
+        #[structopt(
+            long,
+            help = "Recovers the store as of the write with the sequence number",
+            value_name = "SEQ",
+            raw(required_unless = r#""time""#, conflicts_with = r#""time""#)
+        )]
+        seq: Option<u64>,
+
+        #[structopt(
+            long,
+            help = "Recovers the store as of the time, e.g. 2024-01-01T12:00:00Z",
+            value_name = "RFC3339",
+            parse(try_from_str = "humantime::parse_rfc3339_weak")
+        )]
+        time: Option<SystemTime>,
+    },
+
+    /// Copies every live key into a new directory of another engine
+    #[structopt(name = "migrate")]
+    Migrate {
+        #[structopt(
+            long,
+            help = "The engine of the source directory",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        from: Engine,
+
+        #[structopt(
+            long,
+            help = "The engine to migrate to",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        to: Engine,
+
+        #[structopt(help = "The data directory to migrate from", parse(from_os_str))]
+        src: PathBuf,
+
+        #[structopt(help = "The empty or new directory to migrate to", parse(from_os_str))]
+        dst: PathBuf,
+    },
+}
+
+// Wraps the enum as a clap enum, as in kvs-server.
+arg_enum! {
+  #[allow(non_camel_case_types)]
+  #[derive(Debug, Clone, Copy)]
+  enum Engine {
+    kvs,
+    sled
+  }
+}
+
+fn main() -> Result<()> {
+    match Opt::from_args() {
+        Opt::Dump { data } => {
+            for record in LogReader::open(&data.join("log.txt"))? {
+                let record = record?;
+                // A damaged binary record may not decode.
+                let json = record.to_json().unwrap_or_else(|e| e.to_string());
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    record.offset, record.len, record.status, json
+                );
+            }
+            Ok(())
+        }
+
+        Opt::Verify { data } => {
+            let damaged = KvStore::verify(&data)?;
+            for record in &damaged {
+                println!(
+                    "offset {}: {} record of {} bytes",
+                    record.offset, record.status, record.len
+                );
+            }
+
+            match damaged.len() {
+                0 => {
+                    println!("Every record is intact");
+                    Ok(())
+                }
+                n => Err(KvStoreError::StringError(format!(
+                    "{} damaged records, run kvs-admin repair",
+                    n
+                ))),
+            }
+        }
+
+        Opt::Stats { data } => {
+            let stats = KvStore::stats(&data)?;
+            println!("seq: {}", stats.seq);
+            println!("log start: {}", stats.log_start);
+            println!("records: {}", stats.records);
+            println!("live keys: {}", stats.live_keys);
+            println!("dead keys: {}", stats.dead_keys);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("dead bytes: {}", stats.dead_bytes);
+            Ok(())
+        }
+
+        Opt::Compact { data } => {
+            let log = data.join("log.txt");
+            let before = fs::metadata(&log)?.len();
+            KvStore::open(&data)?.compact()?;
+            println!(
+                "Compacted the log from {} to {} bytes",
+                before,
+                fs::metadata(&log)?.len()
+            );
+            Ok(())
+        }
+
+        Opt::Repair { data, discard } => {
+            match KvStore::repair(&data, discard)? {
+                Some(repair) => println!(
+                    "Truncated {} bytes at offset {}, including {} intact records",
+                    repair.len, repair.offset, repair.intact
+                ),
+                None => println!("Every record is intact"),
+            }
+            Ok(())
+        }
+
+        Opt::Recover {
+            data,
+            dest,
+            seq,
+            time,
+        } => {
+            let target = match (seq, time) {
+                (Some(seq), _) => RecoveryTarget::Seq(seq),
+                (None, Some(time)) => RecoveryTarget::Timestamp(
+                    time.duration_since(UNIX_EPOCH)
+                        .map_err(|e| KvStoreError::StringError(e.to_string()))?
+                        .as_millis() as u64,
+                ),
+                (None, None) => unreachable!("clap requires --seq or --time"),
+            };
+
+            let snapshot = KvStore::open_at(&data, target)?;
+            let store = snapshot.write_to(&dest)?;
+            println!(
+                "Recovered {} keys as of sequence number {} to {}",
+                snapshot.scan("")?.len(),
+                store.last_seq()?,
+                dest.display()
+            );
+            Ok(())
+        }
+
+        Opt::Migrate { from, to, src, dst } => {
+            match read_engine_marker(&src)? {
+                Some(marker) if marker == from.to_string() => {}
+                Some(marker) => {
+                    return Err(KvStoreError::StringError(format!(
+                        "{} holds {} data, not {}",
+                        src.display(),
+                        marker,
+                        from
+                    )))
+                }
+                None => {
+                    return Err(KvStoreError::StringError(format!(
+                        "{} holds no data",
+                        src.display()
+                    )))
+                }
+            }
+            if dst.exists() && fs::read_dir(&dst)?.next().is_some() {
+                return Err(KvStoreError::StringError(format!(
+                    "The directory to migrate to, {}, isn't empty",
+                    dst.display()
+                )));
+            }
+
+            // A kvs log is replayed read-only, since opening the store would
+            // truncate a torn record and could compact it. Sled has no
+            // read-only mode, but doesn't rewrite the data on open.
+            let (seq, keys) = match from {
+                Engine::kvs => {
+                    let snapshot = KvStore::open_at(&src, RecoveryTarget::Seq(u64::MAX))?;
+                    (snapshot.seq(), snapshot.keys()?)
+                }
+                Engine::sled => SledKvsEngine::open(&src)?.keys()?,
+            };
+            let (count, crc32) = match to {
+                Engine::kvs => migrate(seq, keys, KvStore::open(&dst)?)?,
+                Engine::sled => migrate(seq, keys, SledKvsEngine::open(&dst)?)?,
+            };
+            write_engine_marker(&dst, &to.to_string())?;
+
+            println!(
+                "Migrated {} keys as of sequence number {} to {}, crc32 {:08x}",
+                count,
+                seq,
+                dst.display(),
+                crc32
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Copies the live keys, with their versions and expiry, as of the source's
+/// sequence number into the engine, then checks it holds the same keys and
+/// values. Returns the number of keys and their checksum.
+fn migrate<D: KvsEngine>(seq: u64, mut keys: Vec<Change>, dst: D) -> Result<(usize, u32)> {
+    keys.retain(|change| change.value.is_some() && !change.is_expired());
+    dst.restore(seq, keys.clone())?;
+
+    // A key missing from the destination because it expired since it was
+    // copied is left out of the source's checksum too, rather than failing
+    // verification.
+    let (dst_seq, copied) = dst.keys()?;
+    let present: HashSet<&[u8]> = copied.iter().map(|change| &change.key[..]).collect();
+    keys.retain(|change| present.contains(&change.key[..]) || !change.is_expired());
+
+    let (count, crc32) = checksum(&keys);
+    let (dst_count, dst_crc32) = checksum(&copied);
+    if (count, crc32) != (dst_count, dst_crc32) || dst_seq != seq {
+        return Err(KvStoreError::StringError(format!(
+            "Verification failed: {} keys with crc32 {:08x} in the source, \
+             but {} keys with crc32 {:08x} in the destination",
+            count, crc32, dst_count, dst_crc32
+        )));
+    }
+
+    Ok((count, crc32))
+}
+
+/// Returns the number of keys and the CRC-32 checksum of their keys and
+/// values in key order, each prefixed by its length.
+fn checksum(keys: &[Change]) -> (usize, u32) {
+    let mut pairs: Vec<(&[u8], &[u8])> = keys
+        .iter()
+        .map(|change| (&change.key[..], change.value.as_deref().unwrap_or_default()))
+        .collect();
+    pairs.sort();
+
+    let mut hasher = crc32fast::Hasher::new();
+    for (key, value) in &pairs {
+        for bytes in &[key, value] {
+            hasher.update(&(bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+    }
+
+    (pairs.len(), hasher.finalize())
+}
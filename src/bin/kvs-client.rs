@@ -1,13 +1,59 @@
+#[macro_use]
+extern crate serde_json;
 extern crate structopt;
-use kvs::{KvsClient, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use kvs::{tls, Credential, ExportFormat, KvStoreError, KvsClient, Result, ServerStatus};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::Value;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_FORMAT: &str = "jsonl";
+
+/// The file in the home directory the shell's history is kept in.
+const HISTORY_FILE: &str = ".kvs_history";
+
+/// The most requests `batch` sends before reading their responses.
+const BATCH_WINDOW: usize = 128;
+
+/// The exit code of a usage error, a failed batch command or another failure
+/// on the client's side.
+const EXIT_FAILURE: i32 = 1;
+
+/// The exit code when the key doesn't exist.
+const EXIT_NOT_FOUND: i32 = 2;
+
+/// The exit code when the server can't be reached or the connection fails.
+const EXIT_CONNECTION: i32 = 3;
+
+/// The exit code when the server returns an error.
+const EXIT_SERVER: i32 = 4;
+
+/// The exit code when authentication fails or a request is denied.
+const EXIT_AUTH: i32 = 5;
 
 // TODO: Remove opt struct and just use the clap macro like in:
 // https://github.com/ccdle12/bitcoin-regtest/blob/master/cli/src/bin/regtest-cli.rs
 #[derive(Debug, StructOpt)]
-#[structopt(name = "kvs", about = "A Key/Value store CLI")]
+#[structopt(
+    name = "kvs",
+    about = "A Key/Value store CLI",
+    raw(after_help = r#""EXIT CODES:
+    0    Success
+    1    Bad arguments, a failed batch command or another client-side failure
+    2    Key not found
+    3    The server can't be reached or the connection failed
+    4    The server returned an error
+    5    Authentication failed or the request was denied""#)
+)]
 enum Opt {
     /// Sets a string key/value pair
     #[structopt(name = "set")]
@@ -20,10 +66,13 @@ enum Opt {
 
         #[structopt(
             long,
-            help = "The server address as IP:PORT",
-            raw(default_value = "DEFAULT_LISTEN_ADDR")
+            help = "Expires the key after the duration, e.g. 30s or 5m",
+            parse(try_from_str = "humantime::parse_duration")
         )]
-        addr: String,
+        ttl: Option<Duration>,
+
+        #[structopt(flatten)]
+        conn: ConnectOpt,
     },
 
     /// Gets a string value according to passed string key
@@ -32,12 +81,18 @@ enum Opt {
         #[structopt(help = "The key string of the key/value pair")]
         key: String,
 
-        #[structopt(
-            long,
-            help = "The server address as IP:PORT",
-            raw(default_value = "DEFAULT_LISTEN_ADDR")
-        )]
-        addr: String,
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+
+    /// Gets the time left until a key expires
+    #[structopt(name = "ttl")]
+    Ttl {
+        #[structopt(help = "The key string of the key/value pair")]
+        key: String,
+
+        #[structopt(flatten)]
+        conn: ConnectOpt,
     },
 
     /// Removes the string key/value pair according to the passed string key
@@ -46,36 +101,820 @@ enum Opt {
         #[structopt(help = "The key string of the key/value pair")]
         key: String,
 
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+
+    /// Prints the server's last sequence number and replication or cluster state
+    #[structopt(name = "status")]
+    Status {
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+
+    /// Prints every write to keys starting with a prefix as it's made
+    #[structopt(name = "watch")]
+    Watch {
+        #[structopt(help = "Only prints writes to keys starting with the prefix")]
+        prefix: Option<String>,
+
         #[structopt(
             long,
-            help = "The server address as IP:PORT",
-            raw(default_value = "DEFAULT_LISTEN_ADDR")
+            help = "Prints the writes since the sequence number first",
+            value_name = "SEQ"
+        )]
+        from: Option<u64>,
+
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+
+    /// Backs the server's data up into a directory on the server's machine
+    #[structopt(name = "backup")]
+    Backup {
+        #[structopt(
+            help = "The empty or new directory to write the backup to, relative to the server's --backup-dir"
         )]
-        addr: String,
+        dest: String,
+
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+
+    /// Opens an interactive shell over a single connection
+    #[structopt(name = "shell")]
+    Shell {
+        #[structopt(flatten)]
+        conn: ConnectOpt,
     },
+
+    /// Runs commands read from stdin, one per line, over a single connection
+    #[structopt(
+        name = "batch",
+        raw(
+            after_help = r#""Each line is a command as in the shell, such as `set key value`, or a \
+            request in the server's JSON protocol, such as {\"Get\":{\"key\":\"key\"}}. \
+            One result line is printed per command, JSON for a JSON request or with \
+            --output json. Exits with 1 if any command failed.""#
+        )
+    )]
+    Batch {
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+
+    /// Writes every key/value pair, with its expiry, as JSON Lines or CSV
+    #[structopt(name = "export")]
+    Export {
+        #[structopt(
+            help = "The file to write to, or stdout if not given",
+            parse(from_os_str)
+        )]
+        file: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        format: FormatOpt,
+
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+
+    /// Sets every key/value pair read as JSON Lines or CSV
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(
+            help = "The file to read from, or stdin if not given",
+            parse(from_os_str)
+        )]
+        file: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        format: FormatOpt,
+
+        #[structopt(flatten)]
+        conn: ConnectOpt,
+    },
+}
+
+/// Options for the format and keys of an export or import.
+#[derive(Debug, StructOpt)]
+struct FormatOpt {
+    #[structopt(
+        long,
+        help = "The format of the pairs",
+        value_name = "jsonl|csv",
+        raw(default_value = "DEFAULT_FORMAT")
+    )]
+    format: ExportFormat,
+
+    #[structopt(
+        long,
+        help = "Only the keys starting with the prefix",
+        default_value = ""
+    )]
+    prefix: String,
 }
 
-fn main() -> Result<()> {
-    match Opt::from_args() {
-        Opt::Set { key, value, addr } => {
-            KvsClient::connect(addr)?.set(key, value)?;
+/// How the results of a command are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
 
-            std::process::exit(0);
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output {}, expected text or json", s)),
         }
+    }
+}
+
+/// Options for connecting to the server and printing results, shared by
+/// every command.
+#[derive(Debug, StructOpt)]
+struct ConnectOpt {
+    #[structopt(
+        long,
+        help = "The server address as IP:PORT",
+        raw(default_value = "DEFAULT_LISTEN_ADDR")
+    )]
+    addr: String,
+
+    #[structopt(
+        long = "tls-ca",
+        help = "Connects over TLS, trusting the CA certificates in the PEM file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_ca: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-server-name",
+        help = "The name the server certificate is verified against, defaults to the host in --addr",
+        value_name = "NAME"
+    )]
+    tls_server_name: Option<String>,
+
+    #[structopt(
+        long = "tls-cert",
+        help = "The PEM client certificate chain for servers requiring mutual TLS",
+        value_name = "FILE",
+        parse(from_os_str),
+        raw(requires = r#""tls_key""#)
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-key",
+        help = "The PEM private key for --tls-cert",
+        value_name = "FILE",
+        parse(from_os_str),
+        raw(requires = r#""tls_cert""#)
+    )]
+    tls_key: Option<PathBuf>,
 
-        Opt::Get { key, addr } => {
-            // KvsClient::connect(addr)?.get(key)
-            let res = KvsClient::connect(addr)?.get(key);
-            match res {
-                Ok(v) => match v {
-                    Some(v) => println!("{}", v),
-                    None => println!("Key not found"),
+    #[structopt(
+        long,
+        help = "Authenticates as the user, with --password or --token",
+        value_name = "NAME"
+    )]
+    user: Option<String>,
+
+    #[structopt(long, help = "The password for --user", value_name = "PASSWORD")]
+    password: Option<String>,
+
+    #[structopt(long, help = "The token for --user", value_name = "TOKEN")]
+    token: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Prints results, and errors on stderr, as text or as JSON",
+        value_name = "text|json",
+        default_value = "text"
+    )]
+    output: OutputFormat,
+}
+
+impl ConnectOpt {
+    /// Connects to the server and authenticates if a user was passed.
+    fn connect(&self) -> std::result::Result<KvsClient, Failure> {
+        let credential = match (&self.user, &self.password, &self.token) {
+            (None, _, _) => None,
+            (Some(_), Some(p), None) => Some(Credential::Password(p.clone())),
+            (Some(_), None, Some(t)) => Some(Credential::Token(t.clone())),
+            _ => {
+                return Err(Failure::usage(KvStoreError::StringError(
+                    "--user requires exactly one of --password or --token".to_string(),
+                )))
+            }
+        };
+
+        let mut client = self
+            .open()
+            .map_err(|e| Failure::new(EXIT_CONNECTION, "connection", e))?;
+        if let (Some(user), Some(credential)) = (&self.user, credential) {
+            client.authenticate(user.clone(), credential)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Opens the connection, over TLS if a CA file was passed.
+    fn open(&self) -> Result<KvsClient> {
+        let ca = match &self.tls_ca {
+            Some(ca) => ca,
+            None => return KvsClient::connect(&self.addr),
+        };
+
+        let identity = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        };
+        let config = tls::client_config(ca, identity)?;
+
+        // Strip the port, and the brackets around an IPv6 address, to get the
+        // host.
+        let host = self.addr.rsplitn(2, ':').last().unwrap_or(&self.addr);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = self.tls_server_name.as_ref().map_or(host, String::as_str);
+
+        KvsClient::connect_tls(&self.addr, name, config)
+    }
+}
+
+/// A failed command, with the exit code and the kind of error it's reported
+/// as.
+struct Failure {
+    code: i32,
+    kind: &'static str,
+    error: KvStoreError,
+}
+
+impl Failure {
+    fn new(code: i32, kind: &'static str, error: KvStoreError) -> Self {
+        Failure { code, kind, error }
+    }
+
+    /// A failure on the client's side, such as bad arguments or a file that
+    /// can't be opened.
+    fn usage(error: KvStoreError) -> Self {
+        Failure::new(EXIT_FAILURE, "failure", error)
+    }
+
+    /// Prints the failure on stderr in the output format.
+    fn report(&self, output: OutputFormat) {
+        match output {
+            OutputFormat::Text => eprintln!("error: {}", self.error),
+            OutputFormat::Json => eprintln!(
+                "{}",
+                json!({ "error": self.kind, "message": self.error.to_string() })
+            ),
+        }
+    }
+}
+
+impl From<KvStoreError> for Failure {
+    fn from(error: KvStoreError) -> Self {
+        let (code, kind) = match &error {
+            KvStoreError::KeyNotFoundError => (EXIT_NOT_FOUND, "not_found"),
+            KvStoreError::IOError(_) | KvStoreError::TlsError(_) => (EXIT_CONNECTION, "connection"),
+            KvStoreError::SerdeError(e) if e.is_io() || e.is_eof() => {
+                (EXIT_CONNECTION, "connection")
+            }
+            KvStoreError::AuthenticationError(_) | KvStoreError::PermissionDeniedError(_) => {
+                (EXIT_AUTH, "auth")
+            }
+            _ => (EXIT_SERVER, "server"),
+        };
+        Failure::new(code, kind, error)
+    }
+}
+
+impl Opt {
+    /// The connection options of the command.
+    fn conn(&self) -> &ConnectOpt {
+        match self {
+            Opt::Set { conn, .. }
+            | Opt::Get { conn, .. }
+            | Opt::Ttl { conn, .. }
+            | Opt::Remove { conn, .. }
+            | Opt::Status { conn }
+            | Opt::Watch { conn, .. }
+            | Opt::Backup { conn, .. }
+            | Opt::Shell { conn }
+            | Opt::Batch { conn }
+            | Opt::Export { conn, .. }
+            | Opt::Import { conn, .. } => conn,
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let output = opt.conn().output;
+
+    if let Err(failure) = run(opt) {
+        failure.report(output);
+        std::process::exit(failure.code);
+    }
+}
+
+/// Runs the command, printing its results in the output format.
+fn run(opt: Opt) -> std::result::Result<(), Failure> {
+    let output = opt.conn().output;
+    let json = output == OutputFormat::Json;
+
+    match opt {
+        Opt::Set {
+            key,
+            value,
+            ttl,
+            conn,
+        } => {
+            let mut client = conn.connect()?;
+            let seq = match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, ttl)?,
+                None => client.set(key, value)?,
+            };
+            if json {
+                println!("{}", json!({ "seq": seq }));
+            }
+        }
+
+        Opt::Get { key, conn } => {
+            let value = conn
+                .connect()?
+                .get_bytes(key.as_bytes())?
+                .ok_or(KvStoreError::KeyNotFoundError)?;
+            if json {
+                // A binary value is shown in base64, as in the JSON protocol.
+                let value = match String::from_utf8(value) {
+                    Ok(value) => json!(value),
+                    Err(e) => json!({ "base64": STANDARD.encode(e.into_bytes()) }),
+                };
+                println!("{}", json!({ "key": key, "value": value }));
+            } else {
+                // A binary value is written out as it is.
+                let mut stdout = io::stdout();
+                stdout
+                    .write_all(&value)
+                    .and_then(|()| stdout.write_all(b"\n"))
+                    .map_err(|e| Failure::usage(e.into()))?;
+            }
+        }
+
+        Opt::Ttl { key, conn } => {
+            let ttl = conn.connect()?.ttl(key.clone())?;
+            if json {
+                let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+                println!("{}", json!({ "key": key, "ttl_ms": ttl_ms }));
+            } else {
+                match ttl {
+                    Some(ttl) => println!("{}", humantime::format_duration(ttl)),
+                    None => println!("No expiry"),
+                }
+            }
+        }
+
+        Opt::Status { conn } => {
+            let status = conn.connect()?.status()?;
+            if json {
+                println!("{}", json!(status));
+            } else {
+                print_status(status);
+            }
+        }
+
+        Opt::Shell { conn } => shell(conn.connect()?).map_err(Failure::usage)?,
+
+        Opt::Batch { conn } => {
+            let failed = batch(conn.connect()?, output)?;
+            if failed > 0 {
+                return Err(Failure::usage(KvStoreError::StringError(format!(
+                    "{} commands failed",
+                    failed
+                ))));
+            }
+        }
+
+        Opt::Watch { prefix, from, conn } => {
+            let watch = conn.connect()?.watch(prefix.unwrap_or_default(), from)?;
+            for change in watch {
+                let change = change?;
+                if json {
+                    println!("{}", json!(change));
+                    continue;
+                }
+                let key = String::from_utf8_lossy(&change.key);
+                match change.value {
+                    Some(value) => {
+                        println!(
+                            "{} set {} {}",
+                            change.seq,
+                            key,
+                            String::from_utf8_lossy(&value)
+                        )
+                    }
+                    None => println!("{} rm {}", change.seq, key),
+                }
+            }
+        }
+
+        Opt::Backup { dest, conn } => {
+            let manifest = conn.connect()?.backup(dest)?;
+            if json {
+                println!("{}", json!(manifest));
+                return Ok(());
+            }
+            println!("seq: {}", manifest.seq);
+            for file in manifest.files {
+                println!(
+                    "{}: {} bytes, crc32 {:08x}",
+                    file.name, file.len, file.crc32
+                );
+            }
+        }
+
+        Opt::Remove { key, conn } => {
+            let seq = conn.connect()?.remove(key)?;
+            if json {
+                println!("{}", json!({ "seq": seq }));
+            }
+        }
+
+        Opt::Export { file, format, conn } => {
+            let file = match file {
+                Some(path) => Some(File::create(path).map_err(|e| Failure::usage(e.into()))?),
+                None => None,
+            };
+            let mut client = conn.connect()?;
+            let count = match file {
+                Some(file) => client.export(format.prefix, format.format, file)?,
+                None => client.export(format.prefix, format.format, io::stdout().lock())?,
+            };
+            // stdout may hold the export, so the count goes to stderr.
+            if json {
+                eprintln!("{}", json!({ "exported": count }));
+            } else {
+                eprintln!("Exported {} keys", count);
+            }
+        }
+
+        Opt::Import { file, format, conn } => {
+            let file = match file {
+                Some(path) => Some(File::open(path).map_err(|e| Failure::usage(e.into()))?),
+                None => None,
+            };
+            let mut client = conn.connect()?;
+            let count = match file {
+                Some(file) => client.import(format.prefix, format.format, file)?,
+                None => client.import(format.prefix, format.format, io::stdin().lock())?,
+            };
+            if json {
+                println!("{}", json!({ "imported": count }));
+            } else {
+                println!("Imported {} keys", count);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the status of a server.
+fn print_status(status: ServerStatus) {
+    println!("seq: {}", status.seq);
+    if let Some(replica) = status.replica {
+        println!("leader: {}", replica.leader);
+        println!("connected: {}", replica.connected);
+        println!("leader seq: {}", replica.leader_seq);
+        println!("lag: {}", replica.lag());
+    }
+    if let Some(cluster) = status.cluster {
+        println!("node: {}", cluster.id);
+        println!("role: {:?}", cluster.role);
+        println!("term: {}", cluster.term);
+        match cluster.leader {
+            Some(leader) => println!("leader: {}", leader),
+            None => println!("leader: none"),
+        }
+        println!("commit index: {}", cluster.commit_index);
+    }
+}
+
+/// The commands of the shell, printed by `help`.
+const SHELL_HELP: &str = "\
+get KEY               Gets the value of a key
+set KEY VALUE [TTL]   Sets a key, expiring after the TTL if given, e.g. 30s
+rm KEY                Removes a key
+ttl KEY               Gets the time left until a key expires
+scan [PREFIX]         Lists every key starting with the prefix, and its value
+stats                 Prints the server's status
+help                  Prints this help
+exit                  Leaves the shell
+
+Words with spaces can be quoted, e.g. set key \"a value\"";
+
+/// Reads commands from the terminal and runs them over the connection until
+/// the user exits, printing each result and how long it took. History is
+/// kept between sessions in `~/.kvs_history`.
+fn shell(mut client: KvsClient) -> Result<()> {
+    let readline_err = |e: ReadlineError| KvStoreError::StringError(e.to_string());
+    let mut editor = DefaultEditor::new().map_err(readline_err)?;
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // There's no history yet on the first session.
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_err(e)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor
+            .add_history_entry(line.as_str())
+            .map_err(readline_err)?;
+
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        match words[0].as_str() {
+            "exit" | "quit" => break,
+            "help" => {
+                println!("{}", SHELL_HELP);
+                continue;
+            }
+            _ => {}
+        }
+
+        let start = Instant::now();
+        match run_shell_command(&mut client, words) {
+            Ok(()) => {}
+            Err(e) => println!("error: {}", e),
+        }
+        println!("({:.3}ms)", start.elapsed().as_secs_f64() * 1000.0);
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history).map_err(readline_err)?;
+    }
+    Ok(())
+}
+
+/// Runs a command of the shell, given as its words, and prints its result.
+fn run_shell_command(client: &mut KvsClient, words: Vec<String>) -> Result<()> {
+    let usage = || KvStoreError::StringError(format!("Bad arguments to {}, see help", words[0]));
+
+    match (words[0].as_str(), &words[1..]) {
+        ("get", [key]) => match client.get(key.clone()) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) | Err(KvStoreError::KeyNotFoundError) => println!("Key not found"),
+            Err(e) => return Err(e),
+        },
+        ("set", [key, value]) => {
+            let seq = client.set(key.clone(), value.clone())?;
+            println!("OK, seq {}", seq);
+        }
+        ("set", [key, value, ttl]) => {
+            let ttl = humantime::parse_duration(ttl)
+                .map_err(|e| KvStoreError::StringError(e.to_string()))?;
+            let seq = client.set_with_ttl(key.clone(), value.clone(), ttl)?;
+            println!("OK, seq {}", seq);
+        }
+        ("rm", [key]) => {
+            let seq = client.remove(key.clone())?;
+            println!("OK, seq {}", seq);
+        }
+        ("ttl", [key]) => match client.ttl(key.clone())? {
+            Some(ttl) => println!("{}", humantime::format_duration(ttl)),
+            None => println!("No expiry"),
+        },
+        ("scan", args) if args.len() <= 1 => {
+            let pairs = client.scan(args.first().cloned().unwrap_or_default())?;
+            for (key, value) in &pairs {
+                println!("{} {}", key, value);
+            }
+            println!("{} keys", pairs.len());
+        }
+        ("stats", []) | ("status", []) => print_status(client.status()?),
+        ("get", _) | ("set", _) | ("rm", _) | ("ttl", _) | ("scan", _) | ("stats", _) => {
+            return Err(usage())
+        }
+        (command, _) => {
+            return Err(KvStoreError::StringError(format!(
+                "Unknown command {}, see help",
+                command
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a line into words on whitespace. A word may be double quoted to
+/// hold whitespace, with a backslash escaping the next character.
+fn split_words(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Ok(words);
+        }
+
+        let mut word = String::new();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' => match chars.next() {
+                    Some(c) => word.push(c),
+                    None => return Err("A line can't end with a backslash".to_string()),
                 },
-                Err(e) => println!("{}", e),
+                c if c.is_whitespace() && !quoted => break,
+                c => word.push(c),
             }
-            std::process::exit(0);
         }
+        if quoted {
+            return Err("Unterminated quote".to_string());
+        }
+        words.push(word);
+    }
+}
 
-        Opt::Remove { key, addr } => KvsClient::connect(addr)?.remove(key),
+/// A text command of a batch, which decides how its response is printed.
+#[derive(Clone, Copy)]
+enum BatchCommand {
+    Get,
+    Set,
+    Remove,
+    Ttl,
+    Scan,
+}
+
+/// A line of a batch, once sent.
+enum BatchLine {
+    /// A request sent, from a text command or, if `None`, a JSON request.
+    Sent(Option<BatchCommand>),
+
+    /// A line that couldn't be sent, and why.
+    Invalid(String),
+}
+
+/// Runs the commands read from stdin over the connection, pipelining up to
+/// `BATCH_WINDOW` requests before reading their responses, and prints a
+/// result line per command. With JSON output every response is printed as
+/// is. Returns the number of commands that failed.
+fn batch(mut client: KvsClient, output: OutputFormat) -> Result<usize> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut failed = 0;
+
+    loop {
+        let mut window = Vec::new();
+        while window.len() < BATCH_WINDOW {
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let sent = if line.starts_with('{') {
+                client.send_raw(line).map(|_| None)
+            } else {
+                batch_request(line)
+                    .and_then(|(request, command)| client.send_raw(&request).map(|_| Some(command)))
+            };
+            window.push(match sent {
+                Ok(command) => BatchLine::Sent(command),
+                Err(e) => BatchLine::Invalid(e.to_string()),
+            });
+        }
+        if window.is_empty() {
+            return Ok(failed);
+        }
+
+        for line in window {
+            let (result, ok) = match (line, output) {
+                (BatchLine::Sent(command), OutputFormat::Text) => {
+                    batch_result(client.receive_raw()?, command)
+                }
+                (BatchLine::Sent(_), OutputFormat::Json) => {
+                    batch_result(client.receive_raw()?, None)
+                }
+                (BatchLine::Invalid(e), OutputFormat::Text) => (format!("error: {}", e), false),
+                (BatchLine::Invalid(e), OutputFormat::Json) => {
+                    (json!({ "Err": e }).to_string(), false)
+                }
+            };
+            if !ok {
+                failed += 1;
+            }
+            writeln!(out, "{}", result)?;
+        }
+        out.flush()?;
     }
 }
+
+/// Turns a text command of a batch into a request in the server's JSON
+/// protocol.
+fn batch_request(line: &str) -> Result<(String, BatchCommand)> {
+    let words = split_words(line).map_err(KvStoreError::StringError)?;
+    let (request, command) = match (words[0].as_str(), &words[1..]) {
+        ("get", [key]) => (json!({ "Get": { "key": key } }), BatchCommand::Get),
+        ("set", [key, value]) => (
+            json!({ "Set": { "key": key, "value": value } }),
+            BatchCommand::Set,
+        ),
+        ("set", [key, value, ttl]) => {
+            let ttl = humantime::parse_duration(ttl)
+                .map_err(|e| KvStoreError::StringError(e.to_string()))?;
+            (
+                json!({ "Set": { "key": key, "value": value, "ttl": ttl } }),
+                BatchCommand::Set,
+            )
+        }
+        ("rm", [key]) => (json!({ "Remove": { "key": key } }), BatchCommand::Remove),
+        ("ttl", [key]) => (json!({ "Ttl": { "key": key } }), BatchCommand::Ttl),
+        ("scan", []) => (json!({ "Scan": { "prefix": "" } }), BatchCommand::Scan),
+        ("scan", [prefix]) => (json!({ "Scan": { "prefix": prefix } }), BatchCommand::Scan),
+        (command, _) => {
+            return Err(KvStoreError::StringError(format!(
+                "Unknown command or bad arguments: {}",
+                command
+            )))
+        }
+    };
+
+    Ok((request.to_string(), command))
+}
+
+/// Returns the result line of a response to a batch command, and whether the
+/// command succeeded. A response to a JSON request is printed as is.
+fn batch_result(response: Value, command: Option<BatchCommand>) -> (String, bool) {
+    let (variant, payload) = match response.as_object().and_then(|o| o.iter().next()) {
+        Some((variant, payload)) => (variant.to_string(), payload.clone()),
+        None => (String::new(), Value::Null),
+    };
+    let ok = variant == "Ok";
+
+    let command = match command {
+        Some(command) => command,
+        None => return (response.to_string(), ok),
+    };
+
+    let result = match (variant.as_str(), command) {
+        // A binary value is shown as its tagged base64 object, as in the JSON
+        // protocol.
+        ("Ok", BatchCommand::Get) => match payload {
+            Value::String(value) => value,
+            Value::Null => "Key not found".to_string(),
+            value => value.to_string(),
+        },
+        ("Ok", BatchCommand::Set) | ("Ok", BatchCommand::Remove) => format!("OK, seq {}", payload),
+        ("Ok", BatchCommand::Ttl) => match serde_json::from_value::<Option<Duration>>(payload) {
+            Ok(Some(ttl)) => humantime::format_duration(ttl).to_string(),
+            _ => "No expiry".to_string(),
+        },
+        ("Ok", BatchCommand::Scan) => payload.to_string(),
+        ("Err", BatchCommand::Get) | ("Err", BatchCommand::Ttl)
+            if payload.as_str() == Some(&KvStoreError::KeyNotFoundError.to_string()) =>
+        {
+            "Key not found".to_string()
+        }
+        ("Denied", _) => format!(
+            "error: Permission denied: {}",
+            payload.as_str().unwrap_or_default()
+        ),
+        ("Redirect", _) => format!(
+            "error: Not the leader, send writes to {}",
+            payload.as_str().unwrap_or_default()
+        ),
+        _ => format!("error: {}", payload.as_str().unwrap_or_default()),
+    };
+
+    (result, ok)
+}
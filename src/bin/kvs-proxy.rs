@@ -0,0 +1,171 @@
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate structopt;
+
+use kvs::{tls, Credential, KvStoreError, KvsProxy, Result};
+use log::LevelFilter;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Default listening address for the proxy.
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:4100";
+
+/// Default number of connections to keep open to each server.
+const DEFAULT_POOL_SIZE: &str = "4";
+
+/// Runs a proxy in front of one or more Key/Value Store servers.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "kvs-proxy",
+    about = "The proxy cli for the kvs.",
+    after_help = "Clients that authenticate are passed through to the servers as their own \
+                  user, over connections of their own. Other clients share the pooled \
+                  connections, authenticated as --backend-user if given."
+)]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the listening adress",
+        value_name = "IP:PORT",
+        raw(default_value = "DEFAULT_LISTEN_ADDR"),
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+
+    #[structopt(
+        long,
+        help = "The servers to forward to, sharding keys across them if there are several",
+        value_name = "HOST:PORT,...",
+        raw(required = "true", use_delimiter = "true")
+    )]
+    backend: Vec<String>,
+
+    #[structopt(
+        long = "pool-size",
+        help = "The most connections to keep open to each server",
+        value_name = "N",
+        raw(default_value = "DEFAULT_POOL_SIZE")
+    )]
+    pool_size: usize,
+
+    #[structopt(
+        long = "tls-cert",
+        help = "Serves TLS using the PEM certificate chain, requires --tls-key",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-key",
+        help = "The PEM private key for --tls-cert",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-client-ca",
+        help = "Requires clients to present a certificate signed by a CA in the PEM file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(
+        long = "backend-tls-ca",
+        help = "Connects to the servers over TLS, trusting the CA certificates in the PEM file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    backend_tls_ca: Option<PathBuf>,
+
+    #[structopt(
+        long = "backend-tls-cert",
+        help = "The PEM client certificate chain for servers requiring mutual TLS",
+        value_name = "FILE",
+        parse(from_os_str),
+        raw(requires_all = r#"&["backend_tls_key", "backend_tls_ca"]"#)
+    )]
+    backend_tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "backend-tls-key",
+        help = "The PEM private key for --backend-tls-cert",
+        value_name = "FILE",
+        parse(from_os_str),
+        raw(requires = r#""backend_tls_cert""#)
+    )]
+    backend_tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "backend-user",
+        help = "Authenticates to the servers as the user for clients that don't authenticate themselves",
+        value_name = "NAME"
+    )]
+    backend_user: Option<String>,
+
+    #[structopt(
+        long = "backend-password",
+        help = "The password for --backend-user",
+        value_name = "PASSWORD"
+    )]
+    backend_password: Option<String>,
+
+    #[structopt(
+        long = "backend-token",
+        help = "The token for --backend-user",
+        value_name = "TOKEN"
+    )]
+    backend_token: Option<String>,
+}
+
+fn main() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let opt = Opt::from_args();
+    info!("kvs-proxy {}", env!("CARGO_PKG_VERSION"));
+    info!("Listening on {}", opt.addr);
+    info!("Forwarding to {}", opt.backend.join(", "));
+
+    let mut proxy = KvsProxy::new(opt.backend.clone(), opt.pool_size)?;
+    match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => {
+            info!("Serving TLS");
+            let config = tls::server_config(cert, key, opt.tls_client_ca.as_deref())?;
+            proxy = proxy.with_tls(config);
+        }
+        (None, None) if opt.tls_client_ca.is_none() => {}
+        _ => {
+            return Err(KvStoreError::StringError(
+                "--tls-cert and --tls-key are required to enable TLS".to_string(),
+            ))
+        }
+    }
+    if let Some(ca) = &opt.backend_tls_ca {
+        let identity = match (&opt.backend_tls_cert, &opt.backend_tls_key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        };
+        proxy = proxy.with_backend_tls(tls::client_config(ca, identity)?);
+    }
+    match (&opt.backend_user, &opt.backend_password, &opt.backend_token) {
+        (None, None, None) => {}
+        (Some(user), Some(p), None) => {
+            proxy = proxy.with_backend_credential(user.clone(), Credential::Password(p.clone()));
+        }
+        (Some(user), None, Some(t)) => {
+            proxy = proxy.with_backend_credential(user.clone(), Credential::Token(t.clone()));
+        }
+        _ => {
+            return Err(KvStoreError::StringError(
+                "--backend-user requires exactly one of --backend-password or --backend-token"
+                    .to_string(),
+            ))
+        }
+    }
+
+    proxy.run(opt.addr)
+}
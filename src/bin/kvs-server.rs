@@ -6,10 +6,16 @@ extern crate log;
 extern crate stderrlog;
 extern crate structopt;
 
-use kvs::{KvStore, KvsEngine, KvsServer, Result};
+use kvs::{
+    read_engine_marker, tls, write_engine_marker, Acl, Credential, KvStore, KvStoreError,
+    KvsClient, KvsClientBuilder, KvsEngine, KvsServer, Result, SledKvsEngine,
+};
 use log::LevelFilter;
+use rustls::ServerConfig;
 use std::env;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
 
 /// Default listening address for the server.
@@ -37,15 +43,166 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+
+    #[structopt(
+        long = "tls-cert",
+        help = "Serves TLS using the PEM certificate chain, requires --tls-key",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-key",
+        help = "The PEM private key for --tls-cert",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-client-ca",
+        help = "Requires clients to present a certificate signed by a CA in the PEM file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Requires clients to authenticate as a user in the JSON ACL file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    acl: Option<PathBuf>,
+
+    #[structopt(
+        long = "replica-of",
+        help = "Replicates the leader at the address, serving reads and redirecting writes to it",
+        value_name = "HOST:PORT"
+    )]
+    replica_of: Option<String>,
+
+    #[structopt(
+        long = "leader-tls-ca",
+        help = "Connects to --replica-of over TLS, trusting the CA certificates in the PEM file",
+        value_name = "FILE",
+        parse(from_os_str),
+        raw(requires = r#""replica_of""#)
+    )]
+    leader_tls_ca: Option<PathBuf>,
+
+    #[structopt(
+        long = "leader-tls-server-name",
+        help = "The name the leader certificate is verified against, defaults to the host in --replica-of",
+        value_name = "NAME",
+        raw(requires = r#""leader_tls_ca""#)
+    )]
+    leader_tls_server_name: Option<String>,
+
+    #[structopt(
+        long = "leader-tls-cert",
+        help = "The PEM client certificate chain for a leader requiring mutual TLS",
+        value_name = "FILE",
+        parse(from_os_str),
+        raw(requires_all = r#"&["leader_tls_key", "leader_tls_ca"]"#)
+    )]
+    leader_tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "leader-tls-key",
+        help = "The PEM private key for --leader-tls-cert",
+        value_name = "FILE",
+        parse(from_os_str),
+        raw(requires = r#""leader_tls_cert""#)
+    )]
+    leader_tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "leader-user",
+        help = "Authenticates to --replica-of as the user, with --leader-password or --leader-token",
+        value_name = "NAME",
+        raw(requires = r#""replica_of""#)
+    )]
+    leader_user: Option<String>,
+
+    #[structopt(
+        long = "leader-password",
+        help = "The password for --leader-user",
+        value_name = "PASSWORD"
+    )]
+    leader_password: Option<String>,
+
+    #[structopt(
+        long = "leader-token",
+        help = "The token for --leader-user",
+        value_name = "TOKEN"
+    )]
+    leader_token: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Runs as a node of the Raft cluster of the servers at the addresses, including --addr",
+        value_name = "IP:PORT,...",
+        raw(use_delimiter = "true", conflicts_with = r#""replica_of""#),
+        parse(try_from_str)
+    )]
+    cluster: Vec<SocketAddr>,
+
+    #[structopt(
+        long = "peer-user",
+        help = "Authenticates to the other --cluster nodes as the user, required with --acl",
+        value_name = "NAME",
+        raw(requires = r#""cluster""#)
+    )]
+    peer_user: Option<String>,
+
+    #[structopt(
+        long = "peer-password",
+        help = "The password for --peer-user",
+        value_name = "PASSWORD"
+    )]
+    peer_password: Option<String>,
+
+    #[structopt(
+        long = "peer-token",
+        help = "The token for --peer-user",
+        value_name = "TOKEN"
+    )]
+    peer_token: Option<String>,
+
+    #[structopt(
+        long = "restore-from",
+        help = "Rebuilds the data directory from the backup before serving",
+        value_name = "DIR",
+        parse(from_os_str),
+        raw(conflicts_with = r#""cluster""#)
+    )]
+    restore_from: Option<PathBuf>,
+
+    #[structopt(
+        long = "backup-dir",
+        help = "Allows clients to back up into new directories under DIR",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "archive-logs",
+        help = "Keeps every log generation replaced by compaction, for point-in-time recovery"
+    )]
+    archive_logs: bool,
 }
 
 // Wraps the enum as a clap enum. Implements the function ::variants().
 // Allows the enum to be used in the struct to use the enum as a cli value.
 arg_enum! {
   #[allow(non_camel_case_types)]
-  #[derive(Debug)]
+  #[derive(Debug, Clone, Copy)]
   enum Engine {
-    kvs
+    kvs,
+    sled
   }
 }
 
@@ -57,14 +214,170 @@ fn main() -> Result<()> {
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Listening on {}", opt.addr);
 
-    match opt.engine.unwrap_or(DEFAULT_ENGINE) {
-        Engine::kvs => run_with_engine(KvStore::open(&env::current_dir()?)?, opt.addr),
+    // The directory's marker picks the engine if none is given, and refuses
+    // another engine than the one whose data the directory holds.
+    let dir = env::current_dir()?;
+    let engine = match (opt.engine, read_engine_marker(&dir)?) {
+        (Some(engine), Some(marker)) if engine.to_string() != marker => {
+            return Err(KvStoreError::StringError(format!(
+                "The data directory holds {} data, not {}",
+                marker, engine
+            )))
+        }
+        (None, Some(marker)) => marker.parse().map_err(KvStoreError::StringError)?,
+        (Some(engine), _) => engine,
+        (None, None) => DEFAULT_ENGINE,
+    };
+    info!("Engine {}", engine);
+    if let Engine::sled = engine {
+        if opt.restore_from.is_some() || opt.archive_logs {
+            return Err(KvStoreError::StringError(
+                "--restore-from and --archive-logs need the kvs engine".to_string(),
+            ));
+        }
+    }
+
+    if let Some(backup) = &opt.restore_from {
+        let manifest = KvStore::restore_from(backup, &dir)?;
+        info!(
+            "Restored from {} at sequence {}",
+            backup.display(),
+            manifest.seq
+        );
+    }
+
+    match engine {
+        Engine::kvs => {
+            let store = KvStore::open(&dir)?;
+            if opt.archive_logs {
+                info!("Archiving compacted logs");
+                store.enable_archive()?;
+            }
+            write_engine_marker(&dir, "kvs")?;
+            run_with_engine(store, &opt)
+        }
+        Engine::sled => {
+            let engine = SledKvsEngine::open(&dir)?;
+            write_engine_marker(&dir, "sled")?;
+            run_with_engine(engine, &opt)
+        }
     }
 }
 
+/// Internal helper function that loads the TLS config if the cert and key
+/// were both passed.
+fn tls_config(opt: &Opt) -> Result<Option<Arc<ServerConfig>>> {
+    match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Ok(Some(tls::server_config(
+            cert,
+            key,
+            opt.tls_client_ca.as_deref(),
+        )?)),
+        (None, None) if opt.tls_client_ca.is_none() => Ok(None),
+        _ => Err(KvStoreError::StringError(
+            "--tls-cert and --tls-key are required to enable TLS".to_string(),
+        )),
+    }
+}
+
+/// Internal helper function that builds the connection to the leader, over
+/// TLS if a CA file was passed and authenticated if a user was.
+fn leader_client(opt: &Opt, leader: &str) -> Result<KvsClientBuilder> {
+    let mut builder = KvsClient::builder(leader.to_string());
+
+    if let Some(ca) = &opt.leader_tls_ca {
+        let identity = match (&opt.leader_tls_cert, &opt.leader_tls_key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        };
+
+        // Strip the port, and the brackets around an IPv6 address, to get the
+        // host.
+        let host = leader.rsplitn(2, ':').last().unwrap_or(leader);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = opt.leader_tls_server_name.as_deref().unwrap_or(host);
+        builder = builder.with_tls(name.to_string(), tls::client_config(ca, identity)?);
+    }
+
+    let (user, credential) = match (&opt.leader_user, &opt.leader_password, &opt.leader_token) {
+        (None, None, None) => return Ok(builder),
+        (Some(user), Some(p), None) => (user.clone(), Credential::Password(p.clone())),
+        (Some(user), None, Some(t)) => (user.clone(), Credential::Token(t.clone())),
+        _ => {
+            return Err(KvStoreError::StringError(
+                "--leader-user requires exactly one of --leader-password or --leader-token"
+                    .to_string(),
+            ))
+        }
+    };
+
+    Ok(builder.with_credential(user, credential))
+}
+
 /// Internal helper function that runs a KvsServer given the trait KvsEngine
 /// and runs the server. Purely for readability in the main function.
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+fn run_with_engine<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    let mut server = KvsServer::new(engine);
+
+    if let Some(config) = tls_config(opt)? {
+        info!("TLS enabled");
+        server = server.with_tls(config);
+    }
+
+    if let Some(path) = &opt.acl {
+        info!("Loading ACL from {}", path.display());
+        server = server.with_acl(Acl::load(path)?);
+    }
+
+    if let Some(dir) = &opt.backup_dir {
+        info!("Allowing backups into {}", dir.display());
+        server = server.with_backup_dir(dir.clone());
+    }
+
+    if let Some(leader) = &opt.replica_of {
+        info!("Replicating from {}", leader);
+        server = server.with_replica_of_client(leader_client(opt, leader)?);
+    }
+
+    if !opt.cluster.is_empty() {
+        let id = opt
+            .cluster
+            .iter()
+            .position(|addr| *addr == opt.addr)
+            .ok_or_else(|| {
+                KvStoreError::StringError("--cluster must include --addr".to_string())
+            })?;
+        info!(
+            "Running as node {} of a cluster of {}",
+            id,
+            opt.cluster.len()
+        );
+
+        let peers = opt.cluster.iter().map(SocketAddr::to_string).collect();
+        let dir = env::current_dir()?;
+        server = match (&opt.peer_user, &opt.peer_password, &opt.peer_token) {
+            (None, None, None) if opt.acl.is_some() => {
+                return Err(KvStoreError::StringError(
+                    "--cluster with --acl requires --peer-user".to_string(),
+                ))
+            }
+            (None, None, None) => server.with_cluster(peers, id, &dir)?,
+            (Some(user), Some(p), None) => {
+                let credential = Credential::Password(p.clone());
+                server.with_cluster_as(peers, id, &dir, user.clone(), credential)?
+            }
+            (Some(user), None, Some(t)) => {
+                let credential = Credential::Token(t.clone());
+                server.with_cluster_as(peers, id, &dir, user.clone(), credential)?
+            }
+            _ => {
+                return Err(KvStoreError::StringError(
+                    "--peer-user requires exactly one of --peer-password or --peer-token"
+                        .to_string(),
+                ))
+            }
+        };
+    }
+
+    server.run(opt.addr)
 }
@@ -0,0 +1,342 @@
+//! Serde helpers for keys and values, which are arbitrary bytes.
+//!
+//! Use with `#[serde(with = "crate::bytes")]`. In a human-readable format
+//! such as JSON, bytes that are valid UTF-8 are written as a string, so text
+//! reads as it always has, and other bytes as base64 tagged as such,
+//! `{"base64":"iVBORw=="}`. In a binary format such as MessagePack they're
+//! always written as raw bytes. Any of these is read back, as are the arrays
+//! of numbers binary values were once written as in JSON.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str;
+
+/// The key of the object a binary value is written as in JSON.
+const BASE64_TAG: &str = "base64";
+
+pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    Bytes(bytes).serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    // A human-readable format may hold a string, a tagged object or an array.
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(BytesVisitor)
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// For an optional key or value.
+pub(crate) mod option {
+    use super::{ByteBuf, Bytes};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bytes.as_deref().map(Bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<ByteBuf>::deserialize(deserializer)?.map(|buf| buf.0))
+    }
+}
+
+/// For a list of key/value pairs.
+pub(crate) mod pairs {
+    use super::{ByteBuf, Bytes};
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(pairs.len()))?;
+        for (key, value) in pairs {
+            seq.serialize_element(&(Bytes(key), Bytes(value)))?;
+        }
+        seq.end()
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Pairs, D::Error> {
+        Ok(Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| (key.0, value.0))
+            .collect())
+    }
+}
+
+/// For the keys read by a transaction, with their versions.
+pub(crate) mod reads {
+    use super::{ByteBuf, Bytes};
+    use crate::Version;
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    type Reads = Vec<(Vec<u8>, Version)>;
+
+    pub(crate) fn serialize<S: Serializer>(
+        reads: &[(Vec<u8>, Version)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(reads.len()))?;
+        for (key, version) in reads {
+            seq.serialize_element(&(Bytes(key), version))?;
+        }
+        seq.end()
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Reads, D::Error> {
+        Ok(Vec::<(ByteBuf, Version)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, version)| (key.0, version))
+            .collect())
+    }
+}
+
+/// For an optional value with its version.
+pub(crate) mod versioned {
+    use super::{ByteBuf, Bytes};
+    use crate::Version;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        read: &(Option<Vec<u8>>, Version),
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        (read.0.as_deref().map(Bytes), read.1).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<(Option<Vec<u8>>, Version), D::Error> {
+        let (value, version) = <(Option<ByteBuf>, Version)>::deserialize(deserializer)?;
+        Ok((value.map(|buf| buf.0), version))
+    }
+}
+
+/// For the outcome of a conditional write.
+pub(crate) mod outcome {
+    use super::{ByteBuf, Bytes};
+    use crate::CasOutcome;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        outcome: &CasOutcome<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let outcome = match outcome {
+            CasOutcome::Swapped(seq) => CasOutcome::Swapped(*seq),
+            CasOutcome::Mismatch(value) => CasOutcome::Mismatch(value.as_deref().map(Bytes)),
+        };
+        outcome.serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<CasOutcome<Vec<u8>>, D::Error> {
+        Ok(CasOutcome::<ByteBuf>::deserialize(deserializer)?.map(|buf| buf.0))
+    }
+}
+
+/// Bytes to serialize, as a string if the format is human-readable and they
+/// are valid UTF-8.
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(self.0);
+        }
+
+        match str::from_utf8(self.0) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(BASE64_TAG, &STANDARD.encode(self.0))?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// Bytes deserialized from a string, raw bytes, a base64 object or an array
+/// of numbers.
+struct ByteBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer).map(ByteBuf)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, bytes, a base64 object or an array of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Vec<u8>, E> {
+        Ok(v.into_bytes())
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<u8>, A::Error> {
+        match map.next_entry::<String, String>()? {
+            Some((tag, encoded)) if tag == BASE64_TAG && map.next_key::<String>()?.is_none() => {
+                STANDARD.decode(encoded).map_err(de::Error::custom)
+            }
+            _ => Err(de::Error::custom(
+                "expected an object with a single base64 string",
+            )),
+        }
+    }
+}
+
+/// A value of any shape read from a self-describing format, such as a
+/// response forwarded by the proxy or a log record shown as JSON. Binary
+/// values are kept as bytes, so the value can be written back out in any
+/// format as `serialize` would write them.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AnyValue {
+    Unit,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Seq(Vec<AnyValue>),
+    Map(Vec<(AnyValue, AnyValue)>),
+}
+
+impl Serialize for AnyValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            AnyValue::Unit => serializer.serialize_unit(),
+            AnyValue::Bool(v) => serializer.serialize_bool(*v),
+            AnyValue::I64(v) => serializer.serialize_i64(*v),
+            AnyValue::U64(v) => serializer.serialize_u64(*v),
+            AnyValue::F64(v) => serializer.serialize_f64(*v),
+            AnyValue::Str(v) => serializer.serialize_str(v),
+            AnyValue::Bytes(v) => Bytes(v).serialize(serializer),
+            AnyValue::Seq(values) => serializer.collect_seq(values),
+            AnyValue::Map(entries) => {
+                serializer.collect_map(entries.iter().map(|(key, value)| (key, value)))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AnyValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(AnyValueVisitor)
+    }
+}
+
+struct AnyValueVisitor;
+
+impl<'de> Visitor<'de> for AnyValueVisitor {
+    type Value = AnyValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<AnyValue, E> {
+        Ok(AnyValue::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<AnyValue, E> {
+        Ok(AnyValue::I64(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<AnyValue, E> {
+        Ok(AnyValue::U64(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<AnyValue, E> {
+        Ok(AnyValue::F64(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<AnyValue, E> {
+        Ok(AnyValue::Str(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<AnyValue, E> {
+        Ok(AnyValue::Str(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<AnyValue, E> {
+        Ok(AnyValue::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<AnyValue, E> {
+        Ok(AnyValue::Bytes(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<AnyValue, E> {
+        Ok(AnyValue::Unit)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<AnyValue, E> {
+        Ok(AnyValue::Unit)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<AnyValue, D::Error> {
+        AnyValue::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<AnyValue, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(AnyValue::Seq(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<AnyValue, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(AnyValue::Map(entries))
+    }
+}
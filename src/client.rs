@@ -1,66 +1,969 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvStoreError, Result};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use crate::auth::Credential;
+use crate::bytes::AnyValue;
+use crate::common::{
+    AuthResponse, BackupResponse, BatchResponse, CasResponse, CommitResponse, FormatResponse,
+    GetResponse, GetVersionedResponse, RemoveResponse, ReplicateResponse, ReplicationMessage,
+    Request, ScanPageResponse, ScanResponse, SetResponse, StatusResponse, TtlResponse,
+    WatchResponse,
+};
+use crate::engines::{into_string, into_string_outcome, into_strings, TransactionState};
+use crate::export::{self, ExportFormat};
+use crate::replication::ServerStatus;
+use crate::tls::{self, Stream};
+use crate::wire::{WireFormat, WireReader, WireWriter};
+use crate::{
+    BackupManifest, CasOutcome, Change, Codec, KvStoreError, KvsPool, Result, Version, WriteBatch,
+};
+use rustls::ClientConfig;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::iter;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The most requests a pipeline sends before reading their responses.
+const PIPELINE_WINDOW: usize = 128;
+
+/// The connections a pool keeps open at most, unless set on the builder.
+const DEFAULT_POOL_SIZE: usize = 8;
+
+/// The times an idempotent request is retried, unless set on the builder.
+const DEFAULT_RETRIES: u32 = 3;
+
+/// The wait before the first retry, doubling with each retry after, unless
+/// set on the builder.
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a pooled connection may sit idle before it's checked on reuse,
+/// unless set on the builder.
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
 /// Key Value store client that reads and writes to a Key Value store server.
+///
+/// Requests are sent in MessagePack, so binary keys and values are sent as
+/// they are, once the server agrees to it ahead of the first request. Against
+/// a server that only speaks JSON the client keeps to JSON.
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: WireReader<Stream>,
+    writer: WireWriter<Stream>,
+
+    /// Whether the wire format has been agreed with the server.
+    negotiated: bool,
 }
 
 impl KvsClient {
     /// Connects to a server given an address.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let reader = TcpStream::connect(addr)?;
+        KvsClient::from_stream(Stream::Plain(TcpStream::connect(addr)?))
+    }
 
+    /// Connects to a server over TLS given an address. The server certificate
+    /// must be valid for `server_name`, a host name or IP address.
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        let name = tls::server_name(server_name)?;
+        KvsClient::from_stream(Stream::client(TcpStream::connect(addr)?, name, config)?)
+    }
+
+    /// Returns a builder for connections to the server at the address, with
+    /// timeouts, TLS or authentication, or for a pool of them.
+    pub fn builder(addr: String) -> KvsClientBuilder {
+        KvsClientBuilder::new(addr)
+    }
+
+    /// Private helper function to split a connection into a reader and writer.
+    fn from_stream(reader: Stream) -> Result<Self> {
         // Creates reference to the same stream but handled independently.
         let writer = reader.try_clone()?;
 
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(reader)),
-            writer: BufWriter::new(writer),
+            reader: WireReader::new(reader),
+            writer: WireWriter::new(writer),
+            negotiated: false,
         })
     }
 
-    /// Sets a key value pair at the server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
+    /// Private helper function to buffer a request, first asking the server
+    /// to switch to MessagePack if it's the connection's first request.
+    fn send(&mut self, request: &Request) -> Result<()> {
+        if !self.negotiated {
+            self.negotiated = true;
+            self.writer.write(&Request::Format {
+                format: WireFormat::MessagePack,
+            })?;
+
+            // A server that answers with `FormatResponse::Err`, not speaking
+            // MessagePack, is spoken to in JSON.
+            if let FormatResponse::Ok(format) = self.receive()? {
+                self.reader.set_format(format);
+                self.writer.set_format(format);
+            }
+        }
 
-        serde_json::to_writer(&mut self.writer, &request)?;
+        self.writer.write(request)
+    }
+
+    /// Private helper function to read the response to the earliest request
+    /// sent, after flushing the requests sent.
+    fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
         self.writer.flush()?;
+        self.reader.read()
+    }
 
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(s) => Err(KvStoreError::StringError(s)),
+    /// Authenticates the connection as a user. Servers with an ACL deny every
+    /// other request until this succeeds.
+    pub fn authenticate(&mut self, user: String, credential: Credential) -> Result<()> {
+        self.send(&Request::Auth { user, credential })?;
+        match self.receive::<AuthResponse>()? {
+            AuthResponse::Ok(_) => Ok(()),
+            AuthResponse::Err(e) => Err(KvStoreError::AuthenticationError(e)),
         }
     }
 
+    /// Sets a key value pair at the server, returning the sequence number of
+    /// the write.
+    pub fn set(&mut self, key: String, value: String) -> Result<u64> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Sets a key value pair at the server that expires after the `ttl`,
+    /// returning the sequence number of the write.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<u64> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
     /// Get a value according to a key from the server.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if the value isn't
+    /// UTF-8. Use `get_bytes` for binary values.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
+        into_string(self.get_bytes(key.as_bytes())?)
+    }
 
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
+    /// Gets the time left until a key expires from the server, or None if it
+    /// never expires.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.as_bytes())
+    }
+
+    /// Sets a binary key value pair at the server, returning the sequence
+    /// number of the write.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<u64> {
+        self.send_set(key, value, None)
+    }
+
+    /// Sets a binary key value pair at the server that expires after the
+    /// `ttl`, returning the sequence number of the write.
+    pub fn set_bytes_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<u64> {
+        self.send_set(key, value, Some(ttl))
+    }
+
+    /// Private helper function to send a set request with an optional ttl.
+    fn send_set(&mut self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<u64> {
+        let request = Request::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl,
+        };
+
+        self.send(&request)?;
+        set_result(self.receive::<SetResponse>()?)
+    }
+
+    /// Get a value according to a binary key from the server.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.send(&Request::Get { key: key.to_vec() })?;
+        get_result(self.receive::<GetResponse>()?)
+    }
+
+    /// Gets the time left until a binary key expires from the server, or None
+    /// if it never expires.
+    pub fn ttl_bytes(&mut self, key: &[u8]) -> Result<Option<Duration>> {
+        self.send(&Request::Ttl { key: key.to_vec() })?;
+        ttl_result(self.receive::<TtlResponse>()?)
+    }
+
+    /// Sets a key to `value` encoded with the codec at the server, returning
+    /// the sequence number of the write.
+    pub fn set_typed<T: Serialize + ?Sized, C: Codec>(
+        &mut self,
+        key: String,
+        value: &T,
+        codec: C,
+    ) -> Result<u64> {
+        self.set_bytes(key.as_bytes(), &codec.encode(value)?)
+    }
+
+    /// Get a value according to a key from the server, decoded with the codec
+    /// it was set with.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::DecodeError` is returned if the value isn't a
+    /// `T` encoded with the codec.
+    pub fn get_typed<T: DeserializeOwned, C: Codec>(
+        &mut self,
+        key: String,
+        codec: C,
+    ) -> Result<Option<T>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies every write in the batch atomically at the server, returning
+    /// the sequence number of the last write.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        self.send(&Request::Batch { batch })?;
+        match self.receive::<BatchResponse>()? {
+            BatchResponse::Ok(r) => Ok(r),
+            BatchResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            BatchResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+            BatchResponse::Redirect(leader) => Err(KvStoreError::RedirectError(leader)),
+        }
+    }
+
+    /// Sets the key to `new` at the server only if its current value is
+    /// `expected`, as one atomic operation.
+    ///
+    /// `None` as `expected` means the key must not exist, and `None` as `new`
+    /// removes the key. On a mismatch the current value is returned, so the
+    /// caller can retry.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned on a mismatch if
+    /// the current value isn't UTF-8.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<CasOutcome> {
+        into_string_outcome(self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.as_ref().map(String::as_bytes),
+        )?)
+    }
+
+    /// Sets the key at the server only if it doesn't already exist.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<CasOutcome> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Sets the binary key to `new` at the server only if its current value
+    /// is `expected`, as one atomic operation. See `compare_and_swap`.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        let request = Request::CompareAndSwap {
+            key: key.to_vec(),
+            expected: expected.map(<[u8]>::to_vec),
+            new: new.map(<[u8]>::to_vec),
+        };
+
+        self.send(&request)?;
+        match self.receive::<CasResponse>()? {
+            CasResponse::Ok(r) => Ok(r),
+            CasResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            CasResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+            CasResponse::Redirect(leader) => Err(KvStoreError::RedirectError(leader)),
+        }
+    }
+
+    /// Sets the binary key at the server only if it doesn't already exist.
+    pub fn set_if_absent_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<CasOutcome<Vec<u8>>> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Starts an optimistic transaction at the server. See
+    /// `kvs::Transaction` for how reads, writes and conflicts are handled.
+    pub fn begin(&mut self) -> ClientTransaction<'_> {
+        ClientTransaction {
+            client: self,
+            state: TransactionState::default(),
+        }
+    }
+
+    /// Private helper function to get a value with its version for a
+    /// transaction.
+    fn get_versioned(&mut self, key: &[u8]) -> Result<(Option<Vec<u8>>, Version)> {
+        self.send(&Request::GetVersioned { key: key.to_vec() })?;
+        match self.receive::<GetVersionedResponse>()? {
+            GetVersionedResponse::Ok(r) => Ok(r),
+            GetVersionedResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            GetVersionedResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+        }
+    }
+
+    /// Private helper function to commit a transaction.
+    fn commit(&mut self, reads: Vec<(Vec<u8>, Version)>, batch: WriteBatch) -> Result<u64> {
+        self.send(&Request::Commit { reads, batch })?;
+        match self.receive::<CommitResponse>()? {
+            CommitResponse::Ok(r) => Ok(r),
+            CommitResponse::Conflict(key) => Err(KvStoreError::TransactionConflictError(key)),
+            CommitResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            CommitResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+            CommitResponse::Redirect(leader) => Err(KvStoreError::RedirectError(leader)),
+        }
+    }
+
+    /// Gets every key starting with `prefix` at the server, and its value, in
+    /// key order.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if a key or value
+    /// isn't UTF-8. Use `scan_bytes` for binary keys and values.
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        into_strings(self.scan_bytes(prefix.as_bytes())?)
+    }
+
+    /// Gets every key starting with the binary `prefix` at the server, and
+    /// its value, in key order.
+    pub fn scan_bytes(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.send(&Request::Scan {
+            prefix: prefix.to_vec(),
+        })?;
+        match self.receive::<ScanResponse>()? {
+            ScanResponse::Ok(r) => Ok(r),
+            ScanResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            ScanResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+        }
+    }
+
+    /// Gets up to `limit` keys starting with the binary `prefix` at the
+    /// server, from the first at or after `start`, in key order. Each key is
+    /// returned as the change that set it, with its version and expiry.
+    pub fn scan_page_bytes(
+        &mut self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<Vec<Change>> {
+        self.send(&Request::ScanPage {
+            prefix: prefix.to_vec(),
+            start: start.to_vec(),
+            limit,
+        })?;
+        match self.receive::<ScanPageResponse>()? {
+            ScanPageResponse::Ok(r) => Ok(r),
+            ScanPageResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            ScanPageResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+        }
+    }
+
+    /// Writes every key starting with `prefix` at the server, its value and
+    /// when it expires, to `writer` in the format, in key order, a page of
+    /// keys at a time. Returns the number of keys written.
+    pub fn export<W: Write>(
+        &mut self,
+        prefix: String,
+        format: ExportFormat,
+        writer: W,
+    ) -> Result<u64> {
+        let prefix = prefix.as_bytes();
+        export::write_pages(prefix, format, writer, |start, limit| {
+            self.scan_page_bytes(prefix, start, limit)
+        })
+    }
+
+    /// Sets every key starting with `prefix` read from `reader` in the format
+    /// at the server, in batches as they're read. Returns the number of keys
+    /// set.
+    pub fn import<R: Read>(
+        &mut self,
+        prefix: String,
+        format: ExportFormat,
+        reader: R,
+    ) -> Result<u64> {
+        export::import(reader, format, &prefix, |batch| self.write_batch(batch))
+    }
+
+    /// Subscribes to every write to keys starting with `prefix` at the server,
+    /// turning the connection into a stream of changes.
+    ///
+    /// With `from`, the writes since that sequence number are received first.
+    /// Without it, only writes made after subscribing are. The stream ends if
+    /// the client falls too far behind the server's writes.
+    pub fn watch(self, prefix: String, from: Option<u64>) -> Result<Watch> {
+        self.watch_bytes(prefix.as_bytes(), from)
+    }
+
+    /// Subscribes to every write to keys starting with the binary `prefix` at
+    /// the server, like `watch`.
+    pub fn watch_bytes(mut self, prefix: &[u8], from: Option<u64>) -> Result<Watch> {
+        self.send(&Request::Watch {
+            prefix: prefix.to_vec(),
+            from,
+        })?;
+        match self.receive::<WatchResponse>()? {
+            WatchResponse::Ok(_) => Ok(Watch { client: self }),
+            WatchResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            WatchResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+        }
+    }
+
+    /// Gets the status of the server, including its replication lag if it's
+    /// a replica.
+    pub fn status(&mut self) -> Result<ServerStatus> {
+        self.send(&Request::Status)?;
+        match self.receive::<StatusResponse>()? {
+            StatusResponse::Ok(r) => Ok(r),
+            StatusResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            StatusResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+        }
+    }
+
+    /// Backs the server's data up into a directory on the server's machine,
+    /// which must be empty or not exist, returning the backup's manifest.
+    ///
+    /// `dest` is relative to the directory the server allows backups into,
+    /// and may not lead out of it.
+    pub fn backup(&mut self, dest: String) -> Result<BackupManifest> {
+        self.send(&Request::Backup { dest })?;
+        match self.receive::<BackupResponse>()? {
+            BackupResponse::Ok(r) => Ok(r),
+            BackupResponse::Err(e) => Err(KvStoreError::StringError(e)),
+            BackupResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+        }
+    }
+
+    /// Sends a request in the server's JSON protocol, such as
+    /// `{"Get":{"key":"key1"}}`, without waiting for its response. Requests
+    /// are buffered, so many can be sent before their responses are read in
+    /// order with `receive_raw`, saving a round trip each. They're sent in the
+    /// connection's wire format, whatever it is.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned, and nothing sent, if the request isn't valid or
+    /// would turn the connection into a stream, like a watch.
+    pub fn send_raw(&mut self, request: &str) -> Result<()> {
+        let request: Request = serde_json::from_str(request)?;
+        match request {
+            Request::Watch { .. }
+            | Request::Replicate
+            | Request::Format { .. }
+            | Request::AppendEntries(_)
+            | Request::RequestVote(_)
+            | Request::InstallSnapshot(_) => Err(KvStoreError::StringError(
+                "Only requests with a single response can be sent raw".to_string(),
+            )),
+            _ => self.send(&request),
+        }
+    }
+
+    /// Reads the response to the earliest request sent with `send_raw` whose
+    /// response hasn't been read, after flushing the requests sent. Binary
+    /// values are returned as base64 objects, `{"base64":"iVBORw=="}`.
+    pub fn receive_raw(&mut self) -> Result<Value> {
+        let value: AnyValue = self.receive()?;
+        Ok(serde_json::to_value(value)?)
+    }
 
-        let res = GetResponse::deserialize(&mut self.reader)?;
-        match res {
-            GetResponse::Ok(r) => Ok(r),
-            GetResponse::Err(_) => Err(KvStoreError::KeyNotFoundError),
+    /// Starts replicating from the server, turning the connection into a
+    /// stream of replication messages.
+    pub(crate) fn replicate(mut self) -> Result<impl Iterator<Item = Result<ReplicationMessage>>> {
+        self.send(&Request::Replicate)?;
+        match self.receive::<ReplicateResponse>()? {
+            ReplicateResponse::Ok(_) => {}
+            ReplicateResponse::Err(e) => return Err(KvStoreError::StringError(e)),
+            ReplicateResponse::Denied(e) => return Err(KvStoreError::PermissionDeniedError(e)),
         }
+
+        Ok(iter::from_fn(move || self.reader.next()))
     }
 
-    /// Removes a kv pair.
+    /// Removes a kv pair, returning the sequence number of the write.
+    pub fn remove(&mut self, key: String) -> Result<u64> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Removes a binary key, returning the sequence number of the write.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<u64> {
+        self.send(&Request::Remove { key: key.to_vec() })?;
+        remove_result(self.receive::<RemoveResponse>()?)
+    }
+
+    /// Starts a pipeline of requests on the connection. See `Pipeline`.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            pending: VecDeque::new(),
+            replies: Vec::new(),
+        }
+    }
+}
+
+/// Private helper function to turn the response to a set into its result.
+fn set_result(response: SetResponse) -> Result<u64> {
+    match response {
+        SetResponse::Ok(seq) => Ok(seq),
+        SetResponse::Err(s) => Err(KvStoreError::StringError(s)),
+        SetResponse::Denied(s) => Err(KvStoreError::PermissionDeniedError(s)),
+        SetResponse::Redirect(leader) => Err(KvStoreError::RedirectError(leader)),
+    }
+}
+
+/// Private helper function to turn the response to a get into its result.
+fn get_result(response: GetResponse) -> Result<Option<Vec<u8>>> {
+    match response {
+        GetResponse::Ok(r) => Ok(r),
+        GetResponse::Err(e) => Err(response_error(e)),
+        GetResponse::Denied(s) => Err(KvStoreError::PermissionDeniedError(s)),
+    }
+}
+
+/// Private helper function to turn the response to a ttl into its result.
+fn ttl_result(response: TtlResponse) -> Result<Option<Duration>> {
+    match response {
+        TtlResponse::Ok(r) => Ok(r),
+        TtlResponse::Err(e) => Err(response_error(e)),
+        TtlResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+    }
+}
+
+/// Private helper function to turn the response to a remove into its result.
+fn remove_result(response: RemoveResponse) -> Result<u64> {
+    match response {
+        RemoveResponse::Ok(r) => Ok(r),
+        RemoveResponse::Err(e) => Err(response_error(e)),
+        RemoveResponse::Denied(e) => Err(KvStoreError::PermissionDeniedError(e)),
+        RemoveResponse::Redirect(leader) => Err(KvStoreError::RedirectError(leader)),
+    }
+}
+
+/// Private helper function to turn the error message of a response into an
+/// error, recognising a missing key so it can be told from a failure of the
+/// server.
+fn response_error(e: String) -> KvStoreError {
+    if e == KvStoreError::KeyNotFoundError.to_string() {
+        KvStoreError::KeyNotFoundError
+    } else {
+        KvStoreError::StringError(e)
+    }
+}
+
+/// The kind of a request sent by a pipeline, which decides how its response
+/// is read.
+enum Pending {
+    Set,
+    Get,
+    Ttl,
+    Remove,
+}
+
+/// The result of a request sent by a pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// The sequence number of a set or remove.
+    Written(u64),
+
+    /// The value of a get.
+    Value(Option<String>),
+
+    /// The time left until a key expires, or `None` if it never expires.
+    Ttl(Option<Duration>),
+}
+
+/// A pipeline of requests on a connection, started with
+/// `KvsClient::pipeline`.
+///
+/// Requests are sent without waiting for their responses, which are read in
+/// the order the requests were queued, so a bulk load doesn't wait a round
+/// trip per request. At most `PIPELINE_WINDOW` requests are in flight at once,
+/// so neither side blocks writing while the other does.
+///
+/// ```no_run
+/// # use kvs::{KvsClient, Result};
+/// # fn main() -> Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// let mut pipeline = client.pipeline();
+/// for i in 0..10_000 {
+///     pipeline.set(format!("key{}", i), format!("value{}", i))?;
+/// }
+/// pipeline.get("key0".to_owned())?;
+/// let replies = pipeline.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Dropping a pipeline without finishing it reads and discards the responses
+/// still in flight, so the connection can still be used.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    pending: VecDeque<Pending>,
+    replies: Vec<Result<Reply>>,
+}
+
+impl Pipeline<'_> {
+    /// Queues a set of a key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.send(
+            Request::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                ttl: None,
+            },
+            Pending::Set,
+        )
+    }
+
+    /// Queues a set of a key that expires after the `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.send(
+            Request::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                ttl: Some(ttl),
+            },
+            Pending::Set,
+        )
+    }
+
+    /// Queues a get of a key.
+    pub fn get(&mut self, key: String) -> Result<()> {
+        self.send(
+            Request::Get {
+                key: key.into_bytes(),
+            },
+            Pending::Get,
+        )
+    }
+
+    /// Queues a get of the time left until a key expires.
+    pub fn ttl(&mut self, key: String) -> Result<()> {
+        self.send(
+            Request::Ttl {
+                key: key.into_bytes(),
+            },
+            Pending::Ttl,
+        )
+    }
+
+    /// Queues a remove of a key.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
+        self.send(
+            Request::Remove {
+                key: key.into_bytes(),
+            },
+            Pending::Remove,
+        )
+    }
+
+    /// Returns the number of requests queued, whether or not their responses
+    /// have been read yet.
+    pub fn len(&self) -> usize {
+        self.replies.len() + self.pending.len()
+    }
+
+    /// Returns true if no requests have been queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the responses still in flight and returns the result of every
+    /// request, in the order they were queued.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the connection fails. A request the server
+    /// rejects, such as a get of a missing key, fails on its own with the
+    /// error its `KvsClient` method would return.
+    pub fn finish(mut self) -> Result<Vec<Result<Reply>>> {
+        while !self.pending.is_empty() {
+            self.receive()?;
+        }
+        Ok(std::mem::take(&mut self.replies))
+    }
+
+    /// Private helper function to send a request, first reading the earliest
+    /// response if the window is full.
+    fn send(&mut self, request: Request, pending: Pending) -> Result<()> {
+        if self.pending.len() == PIPELINE_WINDOW {
+            self.receive()?;
+        }
+
+        self.client.send(&request)?;
+        self.pending.push_back(pending);
+        Ok(())
+    }
 
-        match RemoveResponse::deserialize(&mut self.reader)? {
-            RemoveResponse::Ok(r) => Ok(r),
-            RemoveResponse::Err(e) => Err(KvStoreError::StringError(e)),
+    /// Private helper function to read the response to the earliest request
+    /// in flight.
+    fn receive(&mut self) -> Result<()> {
+        let pending = match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let client = &mut self.client;
+        let reply = match pending {
+            Pending::Set => set_result(client.receive()?).map(Reply::Written),
+            Pending::Get => get_result(client.receive()?)
+                .and_then(into_string)
+                .map(Reply::Value),
+            Pending::Ttl => ttl_result(client.receive()?).map(Reply::Ttl),
+            Pending::Remove => remove_result(client.receive()?).map(Reply::Written),
+        };
+        self.replies.push(reply);
+        Ok(())
+    }
+}
+
+impl Drop for Pipeline<'_> {
+    /// Reads the responses still in flight, so they aren't taken as the
+    /// responses to the client's next requests.
+    fn drop(&mut self) {
+        while !self.pending.is_empty() {
+            if self.receive().is_err() {
+                break;
+            }
         }
     }
 }
+
+/// A stream of the writes to keys under a prefix, returned by
+/// `KvsClient::watch`. Ends when the server closes the connection.
+pub struct Watch {
+    client: KvsClient,
+}
+
+impl Iterator for Watch {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        self.client.reader.next()
+    }
+}
+
+/// An optimistic transaction at the server, started with `KvsClient::begin`.
+///
+/// Writes are buffered on the client and sent with the versions of every key
+/// read when the transaction commits.
+pub struct ClientTransaction<'a> {
+    client: &'a mut KvsClient,
+    state: TransactionState,
+}
+
+impl ClientTransaction<'_> {
+    /// Gets the value of a key as seen by the transaction.
+    ///
+    /// Returns `None` if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if the value isn't
+    /// UTF-8. Use `get_bytes` for binary values.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        into_string(self.get_bytes(key.as_bytes())?)
+    }
+
+    /// Gets the value of a binary key as seen by the transaction.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.state.lookup(key) {
+            return Ok(value);
+        }
+
+        let read = self.client.get_versioned(key)?;
+        Ok(self.state.record_read(key.to_vec(), read))
+    }
+
+    /// Buffers a set of a key until commit.
+    pub fn set(&mut self, key: String, value: String) {
+        self.state.set(key.as_bytes(), value.as_bytes());
+    }
+
+    /// Buffers a set of a binary key until commit.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.state.set(key, value);
+    }
+
+    /// Buffers a remove of a key until commit.
+    pub fn remove(&mut self, key: String) {
+        self.state.remove(key.as_bytes());
+    }
+
+    /// Buffers a remove of a binary key until commit.
+    pub fn remove_bytes(&mut self, key: &[u8]) {
+        self.state.remove(key);
+    }
+
+    /// Commits the transaction's writes atomically at the server, returning
+    /// the sequence number of the last write.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::TransactionConflictError` is returned if a key
+    /// read by the transaction was written since.
+    pub fn commit(self) -> Result<u64> {
+        let (reads, batch) = self.state.into_commit();
+        self.client.commit(reads, batch)
+    }
+}
+
+impl Drop for KvsClient {
+    /// Closes the connection cleanly so the server doesn't treat it as
+    /// truncated.
+    fn drop(&mut self) {
+        if self.writer.flush().is_ok() {
+            let _ = self.writer.get_ref().shutdown();
+        }
+    }
+}
+
+/// Builds connections to a server with timeouts, TLS or authentication, or a
+/// `KvsPool` of them. Created with `KvsClient::builder`.
+///
+/// Without timeouts, like `KvsClient::connect`, a server that stops
+/// responding blocks the client forever.
+#[derive(Clone)]
+pub struct KvsClientBuilder {
+    addr: String,
+    tls: Option<(String, Arc<ClientConfig>)>,
+    credential: Option<(String, Credential)>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    pub(crate) pool_size: usize,
+    pub(crate) retries: u32,
+    pub(crate) backoff: Duration,
+    pub(crate) health_check_interval: Duration,
+}
+
+impl KvsClientBuilder {
+    fn new(addr: String) -> Self {
+        KvsClientBuilder {
+            addr,
+            tls: None,
+            credential: None,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            pool_size: DEFAULT_POOL_SIZE,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+        }
+    }
+
+    /// Connects over TLS, verifying the server certificate against
+    /// `server_name`, a host name or IP address.
+    pub fn with_tls(mut self, server_name: String, config: Arc<ClientConfig>) -> Self {
+        self.tls = Some((server_name, config));
+        self
+    }
+
+    /// Authenticates every connection as the user.
+    pub fn with_credential(mut self, user: String, credential: Credential) -> Self {
+        self.credential = Some((user, credential));
+        self
+    }
+
+    /// Fails a connection attempt that takes longer than the timeout.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails a request whose response takes longer than the timeout to read.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails a request that takes longer than the timeout to write.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Keeps up to `size` connections open in a pool.
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    /// Retries a failed idempotent request of a pool, or a failed connection
+    /// attempt, up to `retries` times, waiting `backoff` before the first
+    /// retry and twice as long before each retry after.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Checks a pooled connection that's been idle for at least the interval
+    /// before reusing it, replacing it if the server no longer answers.
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Opens a connection to the server.
+    pub fn connect(&self) -> Result<KvsClient> {
+        let stream = match self.connect_timeout {
+            Some(timeout) => connect_timeout(&self.addr, timeout)?,
+            None => TcpStream::connect(&self.addr)?,
+        };
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+
+        let stream = match &self.tls {
+            Some((name, config)) => {
+                Stream::client(stream, tls::server_name(name)?, config.clone())?
+            }
+            None => Stream::Plain(stream),
+        };
+        let mut client = KvsClient::from_stream(stream)?;
+
+        if let Some((user, credential)) = &self.credential {
+            client.authenticate(user.clone(), credential.clone())?;
+        }
+        Ok(client)
+    }
+
+    /// Creates a pool of connections to the server. Connections are opened
+    /// as they're needed.
+    pub fn build_pool(self) -> Result<KvsPool> {
+        KvsPool::new(self)
+    }
+
+    /// Returns the address of the server.
+    pub(crate) fn addr(&self) -> &str {
+        &self.addr
+    }
+}
+
+/// Private helper function to connect to the first address the host resolves
+/// to that answers within the timeout.
+fn connect_timeout(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(match last_err {
+        Some(e) => e.into(),
+        None => KvStoreError::StringError(format!("{} didn't resolve to any address", addr)),
+    })
+}
@@ -0,0 +1,69 @@
+//! Codecs turning typed values into the bytes stored, for the `set_typed` and
+//! `get_typed` helpers of engines and clients.
+//!
+//! A value must be read back with the codec it was written with. JSON is
+//! readable by any client, bincode is the most compact, and MessagePack sits
+//! in between. Implement `Codec` to store values in another format.
+
+use crate::{KvStoreError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// A format typed values are stored in.
+pub trait Codec {
+    /// Encodes the value into the bytes to store.
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes the value from the bytes stored.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::DecodeError` is returned if the bytes aren't a
+    /// `T` in the format.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// Stores values as JSON.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| KvStoreError::DecodeError(e.to_string()))
+    }
+}
+
+/// Stores values as bincode. Bincode isn't self-describing, so types that
+/// need to know what's next to deserialize, such as untagged enums, can't be
+/// read back.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| KvStoreError::StringError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|e| KvStoreError::DecodeError(e.to_string()))
+    }
+}
+
+/// Stores values as MessagePack, with struct fields named so fields can be
+/// added with `#[serde(default)]`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).map_err(|e| KvStoreError::DecodeError(e.to_string()))
+    }
+}
@@ -1,26 +1,374 @@
+use crate::auth::Credential;
+use crate::raft::{AppendEntries, InstallSnapshot, RequestVote};
+use crate::replication::ServerStatus;
+use crate::wire::WireFormat;
+use crate::{
+    BackupManifest, CasOutcome, Change, KvStoreError, KvsEngine, Result, Version, WriteBatch,
+};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Auth {
+        user: String,
+        credential: Credential,
+    },
+    /// Switches the connection to the wire format after the response, which
+    /// is sent in JSON.
+    Format {
+        format: WireFormat,
+    },
+    Get {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+        #[serde(default)]
+        ttl: Option<Duration>,
+    },
+    Remove {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Ttl {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    CompareAndSwap {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes::option")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "crate::bytes::option")]
+        new: Option<Vec<u8>>,
+    },
+    GetVersioned {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Commit {
+        #[serde(with = "crate::bytes::reads")]
+        reads: Vec<(Vec<u8>, Version)>,
+        batch: WriteBatch,
+    },
+    Scan {
+        #[serde(with = "crate::bytes")]
+        prefix: Vec<u8>,
+    },
+    ScanPage {
+        #[serde(with = "crate::bytes")]
+        prefix: Vec<u8>,
+        #[serde(with = "crate::bytes")]
+        start: Vec<u8>,
+        limit: usize,
+    },
+    /// Turns the connection into a stream of `Change`s, sent after an `Ok`
+    /// response.
+    Watch {
+        #[serde(with = "crate::bytes")]
+        prefix: Vec<u8>,
+        from: Option<u64>,
+    },
+    /// Turns the connection into a stream of `ReplicationMessage`s, sent
+    /// after an `Ok` response.
+    Replicate,
+    Status,
+    /// Backs the server's data up into a directory on the server's machine.
+    Backup {
+        dest: String,
+    },
+    /// Raft requests between the nodes of a cluster.
+    AppendEntries(AppendEntries),
+    RequestVote(RequestVote),
+    InstallSnapshot(InstallSnapshot),
+}
+
+impl Request {
+    /// Returns whether the request writes, which replicas redirect to the
+    /// leader.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Remove { .. }
+            | Request::Batch { .. }
+            | Request::CompareAndSwap { .. }
+            | Request::Commit { .. } => true,
+            Request::Auth { .. }
+            | Request::Format { .. }
+            | Request::Get { .. }
+            | Request::Ttl { .. }
+            | Request::GetVersioned { .. }
+            | Request::Scan { .. }
+            | Request::ScanPage { .. }
+            | Request::Watch { .. }
+            | Request::Replicate
+            | Request::Status
+            | Request::Backup { .. }
+            | Request::AppendEntries(_)
+            | Request::RequestVote(_)
+            | Request::InstallSnapshot(_) => false,
+        }
+    }
+}
+
+/// A write to an engine, as made by a server directly or committed through
+/// the Raft log of a cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WriteCommand {
+    Set {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+        /// When the key expires, in milliseconds since the unix epoch, so
+        /// every node expires it at the same time.
+        expires_at: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    CompareAndSwap {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes::option")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "crate::bytes::option")]
+        new: Option<Vec<u8>>,
+    },
+    Commit {
+        #[serde(with = "crate::bytes::reads")]
+        reads: Vec<(Vec<u8>, Version)>,
+        batch: WriteBatch,
+    },
+}
+
+/// The result of applying a `WriteCommand`.
+#[derive(Debug)]
+pub enum Applied {
+    /// The sequence number of the last write made.
+    Seq(u64),
+    Cas(CasOutcome<Vec<u8>>),
+}
+
+impl WriteCommand {
+    /// Applies the write to the engine.
+    pub fn apply<E: KvsEngine>(self, engine: &E) -> Result<Applied> {
+        match self {
+            WriteCommand::Set {
+                key,
+                value,
+                expires_at: Some(expires_at),
+            } => {
+                let ttl = Duration::from_millis(expires_at.saturating_sub(now_millis()));
+                engine
+                    .set_bytes_with_ttl(&key, &value, ttl)
+                    .map(Applied::Seq)
+            }
+            WriteCommand::Set { key, value, .. } => {
+                engine.set_bytes(&key, &value).map(Applied::Seq)
+            }
+            WriteCommand::Remove { key } => engine.remove_bytes(&key).map(Applied::Seq),
+            WriteCommand::Batch { batch } => engine.write_batch(batch).map(Applied::Seq),
+            WriteCommand::CompareAndSwap { key, expected, new } => engine
+                .compare_and_swap_bytes(&key, expected.as_deref(), new.as_deref())
+                .map(Applied::Cas),
+            WriteCommand::Commit { reads, batch } => engine.commit(reads, batch).map(Applied::Seq),
+        }
+    }
+}
+
+impl Applied {
+    pub fn into_seq(self) -> Result<u64> {
+        match self {
+            Applied::Seq(seq) => Ok(seq),
+            Applied::Cas(_) => Err(KvStoreError::StringError(
+                "Expected a sequence number".to_string(),
+            )),
+        }
+    }
+
+    pub fn into_cas(self) -> Result<CasOutcome<Vec<u8>>> {
+        match self {
+            Applied::Cas(outcome) => Ok(outcome),
+            Applied::Seq(_) => Err(KvStoreError::StringError(
+                "Expected a compare and swap outcome".to_string(),
+            )),
+        }
+    }
+}
+
+/// Returns when a key set now with the ttl expires, in milliseconds since the
+/// unix epoch. A ttl too long to represent never expires in practice.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_millis().saturating_add(ttl)
+}
+
+/// Returns the current time in milliseconds since the unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FormatResponse {
+    Ok(WireFormat),
+    Err(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
+    Ok(#[serde(with = "crate::bytes::option")] Option<Vec<u8>>),
     Err(String),
+    Denied(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
-    Ok(()),
+    Ok(u64),
     Err(String),
+    Denied(String),
+    Redirect(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
+    Ok(u64),
+    Err(String),
+    Denied(String),
+    Redirect(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TtlResponse {
+    Ok(Option<Duration>),
+    Err(String),
+    Denied(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchResponse {
+    Ok(u64),
+    Err(String),
+    Denied(String),
+    Redirect(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CasResponse {
+    Ok(#[serde(with = "crate::bytes::outcome")] CasOutcome<Vec<u8>>),
+    Err(String),
+    Denied(String),
+    Redirect(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetVersionedResponse {
+    Ok(#[serde(with = "crate::bytes::versioned")] (Option<Vec<u8>>, Version)),
+    Err(String),
+    Denied(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(#[serde(with = "crate::bytes::pairs")] Vec<(Vec<u8>, Vec<u8>)>),
+    Err(String),
+    Denied(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanPageResponse {
+    Ok(Vec<Change>),
+    Err(String),
+    Denied(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
     Ok(()),
     Err(String),
+    Denied(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CommitResponse {
+    Ok(u64),
+    Conflict(String),
+    Err(String),
+    Denied(String),
+    Redirect(String),
+}
+
+/// The response of a replica to any write. It deserializes as the `Redirect`
+/// variant of every write's response, holding the leader's address.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RedirectResponse {
+    Redirect(String),
+}
+
+/// The response of a proxy to a request it can't forward. It deserializes as
+/// the `Err` variant of every response.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicateResponse {
+    Ok(()),
+    Err(String),
+    Denied(String),
+}
+
+/// A message from the leader to a replica after a `Replicate` request.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicationMessage {
+    /// Every live key as of the write `seq`, sent first.
+    Snapshot { seq: u64, keys: Vec<Change> },
+
+    /// A write made on the leader, with every change it made, and the sequence
+    /// number of the leader's last write when sent. A replica applies the
+    /// changes as one write, so a batch is never seen half applied.
+    Write {
+        changes: Vec<Change>,
+        leader_seq: u64,
+    },
+
+    /// Sent when the leader has made no write for a while, with the sequence
+    /// number of its last write.
+    Heartbeat { leader_seq: u64 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatusResponse {
+    Ok(ServerStatus),
+    Err(String),
+    Denied(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BackupResponse {
+    Ok(BackupManifest),
+    Err(String),
+    Denied(String),
 }
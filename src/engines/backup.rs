@@ -0,0 +1,162 @@
+use crate::common::now_millis;
+use crate::{KvStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+/// The name of the manifest in a backup directory, written last so only a
+/// complete backup has one.
+const MANIFEST: &str = "manifest.json";
+
+/// The manifest of a backup, describing every file copied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// The sequence number of the last write in the backup.
+    pub seq: u64,
+
+    /// When the backup was taken, in milliseconds since the unix epoch.
+    pub created_at: u64,
+
+    pub files: Vec<BackupFile>,
+}
+
+/// A file in a backup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// The name of the file, the same in the backup and the data directory.
+    pub name: String,
+
+    pub len: u64,
+
+    /// The CRC-32 checksum of the file's contents.
+    pub crc32: u32,
+}
+
+impl BackupManifest {
+    /// Loads the manifest of the backup in the directory.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let bytes = fs::read(dir.join(MANIFEST)).map_err(|e| {
+            KvStoreError::StringError(format!("{} isn't a backup: {}", dir.display(), e))
+        })?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Checks every file of the backup in the directory has the length and
+    /// checksum in the manifest.
+    pub fn verify(&self, dir: &Path) -> Result<()> {
+        for file in &self.files {
+            let (len, crc32) = checksum(File::open(dir.join(&file.name))?, u64::MAX)?;
+            if len != file.len || crc32 != file.crc32 {
+                return Err(KvStoreError::StringError(format!(
+                    "{} in the backup is corrupt",
+                    file.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies the first `len` bytes of each open file into the directory under
+/// its name, then writes the manifest. The directory must be empty or not
+/// exist.
+pub(crate) fn write(
+    dest: &Path,
+    seq: u64,
+    files: Vec<(String, File, u64)>,
+) -> Result<BackupManifest> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvStoreError::StringError(format!(
+            "The backup directory {} isn't empty",
+            dest.display()
+        )));
+    }
+
+    let mut manifest = BackupManifest {
+        seq,
+        created_at: now_millis(),
+        files: Vec::new(),
+    };
+
+    for (name, file, len) in files {
+        let mut writer = BufWriter::new(File::create(dest.join(&name))?);
+        io::copy(&mut file.take(len), &mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        let (len, crc32) = checksum(File::open(dest.join(&name))?, len)?;
+        manifest.files.push(BackupFile { name, len, crc32 });
+    }
+
+    write_atomically(&dest.join(MANIFEST), &serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+/// Verifies the backup in `src` and copies its files into the data directory
+/// `dest`, which must not already hold a log.
+pub(crate) fn restore(src: &Path, dest: &Path) -> Result<BackupManifest> {
+    let manifest = BackupManifest::load(src)?;
+    manifest.verify(src)?;
+
+    fs::create_dir_all(dest)?;
+    for file in &manifest.files {
+        let target = dest.join(&file.name);
+        if fs::metadata(&target).is_ok_and(|meta| meta.len() > 0) {
+            return Err(KvStoreError::StringError(format!(
+                "{} already exists, move it away to restore over it",
+                target.display()
+            )));
+        }
+    }
+
+    // Each file is copied beside its target and renamed into place, so a
+    // crash leaves either no file or the whole file.
+    for file in &manifest.files {
+        let target = dest.join(&file.name);
+        let tmp_path = target.with_extension("tmp");
+        fs::copy(src.join(&file.name), &tmp_path)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &target)?;
+    }
+
+    Ok(manifest)
+}
+
+/// Private helper function to return the length and CRC-32 checksum of up to
+/// `max` bytes of a file.
+fn checksum(file: File, max: u64) -> Result<(u64, u32)> {
+    let mut reader = BufReader::new(file.take(max));
+    let mut hasher = crc32fast::Hasher::new();
+    let mut len = 0;
+
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.update(buf);
+
+        let read = buf.len();
+        len += read as u64;
+        reader.consume(read);
+    }
+
+    Ok((len, hasher.finalize()))
+}
+
+/// Private helper function to write a file beside its path and rename it
+/// into place, so a crash leaves either no file or the whole file.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A single write in a `WriteBatch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Remove {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+}
+
+/// A group of sets and removes that are applied atomically, either all of them
+/// or none.
+///
+/// Writes are applied in the order they were added, so a later write to a key
+/// wins. Removing a key that doesn't exist is a no-op rather than an error.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("from".to_owned(), "90".to_owned())
+///     .set("to".to_owned(), "10".to_owned())
+///     .remove("pending".to_owned());
+/// assert_eq!(batch.len(), 3);
+/// ```
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds a set of a key to the batch.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Adds a set of a key that expires after the `ttl` to the batch.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> &mut Self {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    /// Adds a remove of a key to the batch.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Adds a set of a binary key to the batch.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl: None,
+        });
+        self
+    }
+
+    /// Adds a set of a binary key that expires after the `ttl` to the batch.
+    pub fn set_bytes_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            ttl: Some(ttl),
+        });
+        self
+    }
+
+    /// Adds a remove of a binary key to the batch.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key: key.to_vec() });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the keys written by the batch.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.ops.iter().map(|op| match op {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key.as_slice(),
+        })
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
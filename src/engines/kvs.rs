@@ -1,32 +1,307 @@
-use crate::KvsEngine;
-use crate::{KvStoreError, Result};
+use super::record::{self, LogReader, LogRecord, RecordFormat, RecordStatus};
+use super::{
+    backup, BackupManifest, BatchOp, CasOutcome, Change, KvsSnapshot, Replication, Version,
+    WATCH_BUFFER,
+};
+use crate::common::{expires_at, now_millis};
+use crate::{KvStoreError, KvsEngine, Result, WriteBatch};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File, OpenOptions};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{BufReader, LineWriter};
+use std::io::{BufWriter, LineWriter};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// The number of bytes of stale records in the log that triggers a compaction.
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// The directory, in the store's directory, that log generations replaced by
+/// compaction are kept in when archiving is enabled.
+const ARCHIVE_DIR: &str = "archive";
 
 /// Command is an enum with each possible command of the database. Each enum
 /// command will be serialized to a log file and used as the basis for populating/
 /// updating an in-memory key/value store.
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Every set and remove is persisted with a sequence number, which increases
+/// by one with each write, and the wall-clock time it was written. Logs written
+/// before sequence numbers existed have them assigned in order on replay.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+        /// When the key expires, in milliseconds since the unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        seq: u64,
+        /// When the write was made, in milliseconds since the unix epoch.
+        #[serde(default)]
+        timestamp: u64,
+    },
+    Get {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(default)]
+        seq: u64,
+        /// When the write was made, in milliseconds since the unix epoch.
+        #[serde(default)]
+        timestamp: u64,
+    },
+    /// The commands of a `WriteBatch`, written as a single record so they're
+    /// replayed all or nothing.
+    Batch { commands: Vec<Command> },
+    /// Written at the start of a compacted log. Every write after `seq` is in
+    /// the log, but only the live keys of the writes up to it.
+    Compacted {
+        seq: u64,
+        /// When the log was compacted, in milliseconds since the unix epoch.
+        #[serde(default)]
+        timestamp: u64,
+    },
+    /// The sequence number of the last write, written at the end of a log
+    /// compacted before compaction wrote `Compacted`. Only read on replay.
+    LastSeq { seq: u64 },
+}
+
+impl Command {
+    /// Returns a set command, given its sequence number and timestamp when
+    /// appended to the log.
+    fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            seq: 0,
+            timestamp: 0,
+        }
+    }
+
+    /// Returns a remove command, given its sequence number and timestamp when
+    /// appended to the log.
+    fn remove(key: Vec<u8>) -> Command {
+        Command::Remove {
+            key,
+            seq: 0,
+            timestamp: 0,
+        }
+    }
+
+    /// Gives every set and remove in the command the next sequence number
+    /// after `last` and the timestamp `now`.
+    fn stamp(&mut self, last: &mut u64, now: u64) {
+        match self {
+            Command::Set { seq, timestamp, .. } | Command::Remove { seq, timestamp, .. } => {
+                *last += 1;
+                *seq = *last;
+                *timestamp = now;
+            }
+            Command::Batch { commands } => {
+                for cmd in commands {
+                    cmd.stamp(last, now);
+                }
+            }
+            Command::Get { .. } | Command::Compacted { .. } | Command::LastSeq { .. } => {}
+        }
+    }
+
+    /// Returns whether every key and value in the command is UTF-8, so it can
+    /// be written as JSON without spelling bytes out as numbers.
+    fn is_text(&self) -> bool {
+        match self {
+            Command::Set { key, value, .. } => {
+                std::str::from_utf8(key).is_ok() && std::str::from_utf8(value).is_ok()
+            }
+            Command::Get { key } | Command::Remove { key, .. } => std::str::from_utf8(key).is_ok(),
+            Command::Batch { commands } => commands.iter().all(Command::is_text),
+            Command::Compacted { .. } | Command::LastSeq { .. } => true,
+        }
+    }
+
+    /// Frames the command as a log record, a line of JSON if it's text and a
+    /// binary frame of MessagePack otherwise.
+    fn encode(&self) -> Result<Vec<u8>> {
+        if self.is_text() {
+            Ok(record::encode(&serde_json::to_string(self)?).into_bytes())
+        } else {
+            Ok(record::encode_binary(&rmp_serde::to_vec_named(self)?))
+        }
+    }
+
+    /// Calls `f` with every set and remove in the command as a change, in
+    /// order. Writes logged without a sequence number are numbered after
+    /// `last`, which is moved to the last write.
+    fn changes(self, last: &mut u64, f: &mut impl FnMut(Change)) {
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                seq,
+                timestamp,
+            } => {
+                *last = next_seq(*last, seq);
+                f(Change {
+                    seq: *last,
+                    timestamp,
+                    key,
+                    value: Some(value),
+                    expires_at,
+                });
+            }
+            Command::Remove {
+                key,
+                seq,
+                timestamp,
+            } => {
+                *last = next_seq(*last, seq);
+                f(Change {
+                    seq: *last,
+                    timestamp,
+                    key,
+                    value: None,
+                    expires_at: None,
+                });
+            }
+            Command::Batch { commands } => {
+                for cmd in commands {
+                    cmd.changes(last, f);
+                }
+            }
+            Command::Compacted { seq, .. } | Command::LastSeq { seq } => *last = (*last).max(seq),
+            Command::Get { .. } => {}
+        }
+    }
+}
+
+/// A value in the in-memory store.
+struct Entry {
+    value: Vec<u8>,
+
+    /// When the key expires, in milliseconds since the unix epoch.
+    expires_at: Option<u64>,
+
+    /// The length in bytes of the record in the log that set this value, which
+    /// becomes stale once the key is overwritten, removed or expires.
+    len: u64,
+
+    /// The sequence number of the write that set this value, which changes on
+    /// every write to the key.
+    version: u64,
+
+    /// When the write that set this value was made, in milliseconds since the
+    /// unix epoch.
+    timestamp: u64,
+}
+
+/// A subscriber to the writes to keys starting with a prefix.
+struct Watcher {
+    prefix: Vec<u8>,
+
+    /// The sequence number of the first write to send.
+    from: u64,
+
+    sender: SyncSender<Change>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+/// A value overwritten, removed or expired while a snapshot could still see
+/// it.
+struct OldVersion {
+    entry: Entry,
+
+    /// The sequence number of the write that superseded this value. Snapshots
+    /// pinned before it still see the value.
+    superseded_at: u64,
 }
 
-/// The `KvStore` stores a key/value pair of strings.
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
+///
+/// Key/value pairs are stored in a `BTreeMap` in memory and every write is
+/// appended to a log on disk, which is replayed on open. Keys may be set with
+/// a time to live, after which they are no longer returned.
 ///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to
-/// disk.
+/// The store is cheap to clone and every clone shares the same data. Reads
+/// share a lock and each write, including the read of a conditional write,
+/// holds the write lock, so writes are atomic with respect to each other.
+#[derive(Clone)]
 pub struct KvStore {
+    inner: Arc<RwLock<KvStoreInner>>,
+}
+
+/// The state of a `KvStore`, behind its lock.
+struct KvStoreInner {
     /// Store is the in memory key/value store.
-    store: HashMap<String, String>,
+    store: BTreeMap<Vec<u8>, Entry>,
+
+    /// Superseded values of each key that a snapshot can still see, kept
+    /// until the last snapshot that can see them is dropped.
+    history: BTreeMap<Vec<u8>, Vec<OldVersion>>,
+
+    /// The number of live snapshots pinned to each sequence number.
+    snapshots: BTreeMap<u64, usize>,
+
+    /// The sequence number of the remove of each key removed since the log
+    /// was last compacted, so a key removed after being set again still has a
+    /// new version.
+    removed: BTreeMap<Vec<u8>, u64>,
 
     /// The path to the logs folder, containing the log of events for the DB.
     path_buf: PathBuf,
+
+    /// The number of bytes of stale records in the log, reclaimed by
+    /// compaction.
+    uncompacted: u64,
+
+    /// The sequence number of the last write applied.
+    seq: u64,
+
+    /// The sequence number of the first write the log holds every write
+    /// since. Earlier writes were compacted away.
+    log_start: u64,
+
+    /// The subscribers to writes, sent each write as it's applied.
+    watchers: Vec<Watcher>,
+
+    /// The replicas subscribed to writes, sent every change of each write
+    /// together.
+    replicas: Vec<SyncSender<Vec<Change>>>,
+
+    /// Whether log generations replaced by compaction are archived rather
+    /// than deleted.
+    archive: bool,
+}
+
+/// Macro to write a command to a file handler. Evaluates to the number of bytes
+/// written.
+macro_rules! write_cmd {
+    ($command:expr, $file_handler:expr) => {{
+        let c = $command;
+        let f = $file_handler;
+
+        let cmd = c.encode()?;
+
+        LineWriter::new(f).write_all(&cmd)?;
+
+        Ok(cmd.len() as u64)
+    } as Result<u64>};
 }
 
 impl KvStore {
@@ -53,87 +328,1151 @@ impl KvStore {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path_buf)?;
 
         // Create the kv store.
-        let mut store = HashMap::new();
+        let mut inner = KvStoreInner::new(path_buf);
 
-        // Open the log file and deserialize to the in-memory store.
-        for line in BufReader::new(file_handler).lines() {
-            let cmd: Command = serde_json::from_str(&line?)?;
+        // Open the log file and deserialize to the in-memory store. Each record
+        // is framed by a trailing newline.
+        for record in LogReader::new(&file_handler) {
+            let record = record?;
 
-            if let Command::Set { key, value } = &cmd {
-                store.insert(key.to_string(), value.to_string());
-            };
+            // A record without its newline was torn by a crash mid-write, so
+            // it's dropped rather than half applied and the log truncated to
+            // the last whole record.
+            if record.status == RecordStatus::Torn {
+                file_handler.set_len(record.offset)?;
+                break;
+            }
+
+            inner.apply(parse(&record)?, record.len);
+        }
+
+        // Keys that expired while the store was closed are dropped now and
+        // their records reclaimed at the next compaction.
+        let now = now_millis();
+        let mut expired = 0;
+        inner.store.retain(|_, entry| {
+            if entry.is_expired(now) {
+                expired += entry.len;
+            }
+            !entry.is_expired(now)
+        });
+        inner.uncompacted += expired;
+
+        Ok(KvStore {
+            inner: Arc::new(RwLock::new(inner)),
+        })
+    }
+
+    /// Rebuilds the data directory at `path` from the backup in `backup`,
+    /// after checking every file against the backup's manifest. The data
+    /// directory must not already hold a log.
+    pub fn restore_from(backup: &Path, path: &Path) -> Result<BackupManifest> {
+        backup::restore(backup, path)
+    }
+
+    /// Archives every log generation replaced by compaction from now on, in
+    /// the `archive` directory beside the log, so the store can be recovered
+    /// as of any write since with `open_at`. Archived logs are never deleted.
+    pub fn enable_archive(&self) -> Result<()> {
+        let mut inner = self.inner.write()?;
+        create_dir_all(inner.path_buf.with_file_name(ARCHIVE_DIR))?;
+        inner.archive = true;
+
+        Ok(())
+    }
+
+    /// Opens a read-only view of the store at `path` as of the target, from
+    /// its archived and live logs. Nothing in `path` is changed.
+    ///
+    /// The view is replayed from the last log generation that starts at or
+    /// before the target, up to the last write at or before the target.
+    /// Keys expire as of the last write replayed. `Snapshot::write_to` makes
+    /// a new store from the view.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the target is before every log generation
+    /// kept, such as a target before the first compaction without archiving.
+    pub fn open_at(path: &Path, target: RecoveryTarget) -> Result<Snapshot> {
+        let log = path.join("log.txt");
+        let archive = path.join(ARCHIVE_DIR);
+
+        let mut generations = Vec::new();
+        if archive.is_dir() {
+            for entry in fs::read_dir(&archive)? {
+                generations.push(entry?.path());
+            }
+            generations.sort();
+        }
+        generations.push(log);
+
+        let mut chosen = None;
+        for generation in generations {
+            let (seq, timestamp) = log_start(&generation)?;
+            if !target.is_before(seq, timestamp) {
+                chosen = Some(generation);
+            }
+        }
+        let chosen = chosen.ok_or_else(|| {
+            KvStoreError::StringError(format!(
+                "No log of {} goes back to {}",
+                path.display(),
+                target
+            ))
+        })?;
+
+        // A record torn by a crash mid-write is the last in the log and is
+        // skipped, as on open.
+        let mut inner = KvStoreInner::new(chosen.clone());
+        let mut at = 0;
+        for record in LogReader::open(&chosen)? {
+            let record = record?;
+            if record.status == RecordStatus::Torn {
+                break;
+            }
+
+            let cmd = parse(&record)?;
+            let mut seq = inner.seq;
+            let mut timestamp = at;
+            cmd.clone().changes(&mut seq, &mut |change| {
+                timestamp = timestamp.max(change.timestamp)
+            });
+            if seq > inner.seq && target.is_before(seq, timestamp) {
+                break;
+            }
+
+            inner.apply(cmd, record.len);
+            at = timestamp;
+        }
+
+        let seq = inner.seq;
+        *inner.snapshots.entry(seq).or_insert(0) += 1;
+        if let RecoveryTarget::Timestamp(timestamp) = target {
+            at = timestamp;
+        }
+
+        Ok(Snapshot {
+            inner: Arc::new(RwLock::new(inner)),
+            seq,
+            at,
+        })
+    }
+
+    /// Rewrites the log with only the records of live keys, reclaiming the
+    /// space of overwritten, removed and expired keys.
+    ///
+    /// Compaction runs automatically once enough of the log is stale.
+    pub fn compact(&self) -> Result<()> {
+        self.inner.write()?.compact()
+    }
+
+    /// Counts the live and dead keys and bytes in the log of the store at
+    /// `path`, without opening the store. Nothing in `path` is changed.
+    ///
+    /// A dead key has records in the log but has been removed or has expired.
+    /// Dead bytes are every byte of the log but the records of live keys,
+    /// which compaction reclaims.
+    pub fn stats(path: &Path) -> Result<LogStats> {
+        let mut inner = KvStoreInner::new(path.join("log.txt"));
+        let mut keys = BTreeSet::new();
+        let mut stats = LogStats::default();
+
+        for record in LogReader::open(&inner.path_buf)? {
+            let record = record?;
+            if record.status == RecordStatus::Torn {
+                break;
+            }
+
+            let cmd = parse(&record)?;
+            let mut seq = inner.seq;
+            cmd.clone().changes(&mut seq, &mut |change| {
+                keys.insert(change.key);
+            });
+            inner.apply(cmd, record.len);
+
+            stats.records += 1;
+            stats.dead_bytes += record.len;
+        }
+
+        let now = now_millis();
+        for entry in inner.store.values().filter(|entry| !entry.is_expired(now)) {
+            stats.live_keys += 1;
+            stats.live_bytes += entry.len;
+        }
+        stats.dead_keys = keys.len() as u64 - stats.live_keys;
+        stats.dead_bytes -= stats.live_bytes;
+        stats.seq = inner.seq;
+        stats.log_start = inner.log_start;
+
+        Ok(stats)
+    }
+
+    /// Checks every record in the log of the store at `path` against its
+    /// checksum, returning the damaged records. Nothing in `path` is changed.
+    ///
+    /// A record written without a checksum is damaged if it isn't a valid
+    /// record.
+    pub fn verify(path: &Path) -> Result<Vec<LogRecord>> {
+        let mut damaged = Vec::new();
+        for record in LogReader::open(&path.join("log.txt"))? {
+            let record = check(record?);
+            if !record.status.is_intact() {
+                damaged.push(record);
+            }
+        }
+
+        Ok(damaged)
+    }
+
+    /// Truncates the log of the store at `path` at its first record that's
+    /// damaged, as found by `verify`, so the store opens again. Returns what was
+    /// truncated, or `None` if every record is intact.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned, and nothing truncated, if intact records follow
+    /// the first damaged one, unless `discard` is set. Those writes are lost
+    /// too, so consider recovering from a backup instead.
+    pub fn repair(path: &Path, discard: bool) -> Result<Option<Repair>> {
+        let log = path.join("log.txt");
+        let mut repair: Option<Repair> = None;
+
+        for record in LogReader::open(&log)? {
+            let record = check(record?);
+            match repair.as_mut() {
+                Some(repair) => {
+                    repair.len += record.len;
+                    if record.status.is_intact() {
+                        repair.intact += 1;
+                    }
+                }
+                None if !record.status.is_intact() => {
+                    repair = Some(Repair {
+                        offset: record.offset,
+                        len: record.len,
+                        intact: 0,
+                    })
+                }
+                None => {}
+            }
+        }
+
+        if let Some(repair) = &repair {
+            if repair.intact > 0 && !discard {
+                return Err(KvStoreError::StringError(format!(
+                    "{} intact records follow the damaged record at offset {}, \
+                     discard them to repair the log",
+                    repair.intact, repair.offset
+                )));
+            }
+
+            let file = OpenOptions::new().write(true).open(&log)?;
+            file.set_len(repair.offset)?;
+            file.sync_all()?;
+        }
+
+        Ok(repair)
+    }
+}
+
+/// The counts of live and dead keys and bytes in a log, from `KvStore::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogStats {
+    /// The sequence number of the last write in the log.
+    pub seq: u64,
+
+    /// The sequence number of the first write the log holds every write
+    /// since. Earlier writes were compacted away.
+    pub log_start: u64,
+
+    pub records: u64,
+    pub live_keys: u64,
+    pub dead_keys: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+/// What `KvStore::repair` truncated from a log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repair {
+    /// The offset of the first damaged record, which the log was truncated
+    /// at.
+    pub offset: u64,
+
+    /// The number of bytes truncated.
+    pub len: u64,
+
+    /// The number of intact records truncated after the damaged one.
+    pub intact: usize,
+}
+
+/// The point in a store's history to recover it to with `KvStore::open_at`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryTarget {
+    /// Just after the write with the sequence number.
+    Seq(u64),
+
+    /// Just after the last write made at or before the time, in milliseconds
+    /// since the unix epoch.
+    Timestamp(u64),
+}
+
+impl RecoveryTarget {
+    /// Private helper function to return whether the target is before the
+    /// write with the sequence number and timestamp.
+    fn is_before(self, seq: u64, timestamp: u64) -> bool {
+        match self {
+            RecoveryTarget::Seq(target) => target < seq,
+            RecoveryTarget::Timestamp(target) => target < timestamp,
+        }
+    }
+}
 
-            if let Command::Remove { key } = &cmd {
-                store.remove(key);
+impl fmt::Display for RecoveryTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecoveryTarget::Seq(seq) => write!(f, "sequence number {}", seq),
+            RecoveryTarget::Timestamp(timestamp) => write!(f, "timestamp {}", timestamp),
+        }
+    }
+}
+
+/// A read-only, point-in-time view of a `KvStore`, returned by
+/// `KvsEngine::snapshot`.
+///
+/// A snapshot is pinned to the sequence number of the last write before it
+/// was taken and sees every key as of that write, including keys that have
+/// since been overwritten or removed. Keys expire as of when the snapshot was
+/// taken.
+pub struct Snapshot {
+    inner: Arc<RwLock<KvStoreInner>>,
+
+    /// The sequence number of the last write the snapshot sees.
+    seq: u64,
+
+    /// When the snapshot was taken, in milliseconds since the unix epoch.
+    at: u64,
+}
+
+impl KvsSnapshot for Snapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .inner
+            .read()?
+            .entry_at(key, self.seq, self.at)
+            .map(|entry| entry.value.clone()))
+    }
+
+    fn scan_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .changes(prefix)?
+            .into_iter()
+            .filter_map(|change| Some((change.key, change.value?)))
+            .collect())
+    }
+
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Snapshot {
+    /// Writes every key of the snapshot, with its sequence number, timestamp
+    /// and expiry, as a new store at `path`, which must not already hold a
+    /// log. The new store continues from the snapshot's sequence number.
+    pub fn write_to(&self, path: &Path) -> Result<KvStore> {
+        if fs::metadata(path.join("log.txt")).is_ok_and(|meta| meta.len() > 0) {
+            return Err(KvStoreError::StringError(format!(
+                "{} already holds a store",
+                path.display()
+            )));
+        }
+
+        let store = KvStore::open(path)?;
+        store.restore(self.seq, self.changes(b"")?)?;
+        Ok(store)
+    }
+
+    /// Returns every key of the snapshot, in key order, as the write that set
+    /// it.
+    pub fn keys(&self) -> Result<Vec<Change>> {
+        self.changes(b"")
+    }
+
+    /// Private helper function to return every key starting with `prefix` as
+    /// of the snapshot, in key order, as the write that set it.
+    fn changes(&self, prefix: &[u8]) -> Result<Vec<Change>> {
+        let inner = self.inner.read()?;
+        let range = (Bound::Included(prefix), Bound::Unbounded);
+
+        // A key may be in the store, the history of superseded values, or
+        // both.
+        let keys: BTreeSet<&Vec<u8>> = inner
+            .store
+            .range::<[u8], _>(range)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .chain(
+                inner
+                    .history
+                    .range::<[u8], _>(range)
+                    .map(|(key, _)| key)
+                    .take_while(|key| key.starts_with(prefix)),
+            )
+            .collect();
+
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                inner.entry_at(key, self.seq, self.at).map(|entry| Change {
+                    seq: entry.version,
+                    timestamp: entry.timestamp,
+                    key: key.clone(),
+                    value: Some(entry.value.clone()),
+                    expires_at: entry.expires_at,
+                })
+            })
+            .collect())
+    }
+}
+
+impl Drop for Snapshot {
+    /// Unpins the snapshot, dropping the old values no other snapshot can see.
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.write() {
+            inner.release(self.seq);
+        }
+    }
+}
+
+impl KvStoreInner {
+    /// Private helper function to return every live key, as the change that
+    /// last set it, in the order they were written.
+    fn live_keys(&self) -> Vec<Change> {
+        let now = now_millis();
+        let mut keys: Vec<Change> = self
+            .store
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| Change {
+                seq: entry.version,
+                timestamp: entry.timestamp,
+                key: key.clone(),
+                value: Some(entry.value.clone()),
+                expires_at: entry.expires_at,
+            })
+            .collect();
+        keys.sort_by_key(|change| change.seq);
+
+        keys
+    }
+
+    fn new(path_buf: PathBuf) -> Self {
+        KvStoreInner {
+            store: BTreeMap::new(),
+            history: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+            removed: BTreeMap::new(),
+            path_buf,
+            uncompacted: 0,
+            seq: 0,
+            log_start: 0,
+            watchers: Vec::new(),
+            replicas: Vec::new(),
+            archive: false,
+        }
+    }
+
+    /// Private helper function to rewrite the log with only the records of
+    /// live keys.
+    fn compact(&mut self) -> Result<()> {
+        // Expired keys are dropped from the log, but kept in memory for the
+        // snapshots taken before they expired.
+        let now = now_millis();
+        let expired: Vec<Vec<u8>> = self
+            .store
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(old) = self.store.remove(&key) {
+                self.retire(key, old, self.seq + 1);
+            }
+        }
+
+        // Write the new log beside the old one and swap it in, so a crash
+        // mid-compaction leaves the old log intact.
+        let compact_path = self.path_buf.with_extension("compact");
+        let mut writer = BufWriter::new(File::create(&compact_path)?);
+
+        let header = Command::Compacted {
+            seq: self.seq,
+            timestamp: now,
+        };
+        writer.write_all(&header.encode()?)?;
+
+        // Live keys are written in the order they were last written, keeping
+        // their sequence numbers and timestamps.
+        let mut entries: Vec<_> = self.store.iter_mut().collect();
+        entries.sort_by_key(|(_, entry)| entry.version);
+
+        for (key, entry) in entries {
+            let cmd = Command::Set {
+                key: key.clone(),
+                value: entry.value.clone(),
+                expires_at: entry.expires_at,
+                seq: entry.version,
+                timestamp: entry.timestamp,
             };
+
+            let record = cmd.encode()?;
+            writer.write_all(&record)?;
+            entry.len = record.len() as u64;
         }
 
-        Ok(KvStore { store, path_buf })
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        // The old log is linked into the archive before it's replaced, so a
+        // crash in between leaves it in both places rather than neither.
+        if self.archive {
+            let archived = archive_path(&self.path_buf, self.log_start.saturating_sub(1));
+            if archived.exists() {
+                fs::remove_file(&archived)?;
+            }
+            fs::hard_link(&self.path_buf, &archived)?;
+        }
+        fs::rename(&compact_path, &self.path_buf)?;
+        self.uncompacted = 0;
+        self.log_start = self.seq + 1;
+        self.removed.clear();
+
+        Ok(())
     }
 
     /// Private helper function to return a file handler as read only to the log.
     fn log_file(&self) -> Result<File> {
         Ok(OpenOptions::new().append(true).open(&self.path_buf)?)
     }
-}
 
-/// Macro to write a command to a file handler.
-macro_rules! write_cmd {
-    ($command:expr, $file_handler:expr) => {{
-        let c = $command;
-        let f = $file_handler;
+    /// Private helper function to stamp a command with the next sequence
+    /// numbers, append it to the log and apply it to the in-memory store.
+    /// Returns the sequence number of the last write in the command.
+    fn append(&mut self, mut cmd: Command) -> Result<u64> {
+        let mut last = self.seq;
+        cmd.stamp(&mut last, now_millis());
+
+        self.append_stamped(cmd)
+    }
+
+    /// Private helper function to append a command whose writes already have
+    /// sequence numbers and apply it to the in-memory store.
+    fn append_stamped(&mut self, cmd: Command) -> Result<u64> {
+        let len = write_cmd!(&cmd, self.log_file()?)?;
+        let mut changes = Vec::new();
+        if !self.replicas.is_empty() {
+            let mut last = self.seq;
+            cmd.clone()
+                .changes(&mut last, &mut |change| changes.push(change));
+        }
+        self.apply(cmd, len);
+        if !changes.is_empty() {
+            self.replicas
+                .retain(|sender| sender.try_send(changes.clone()).is_ok());
+        }
+        self.maybe_compact()?;
+
+        Ok(self.seq)
+    }
+
+    /// Private helper function to apply a command, replayed from or just
+    /// written to the log, to the in-memory store. `len` is the length in bytes
+    /// of the command's record, which is counted as stale once superseded.
+    ///
+    /// Every set and remove takes its sequence number as the version of the
+    /// key, and is sent to the watchers of the key.
+    fn apply(&mut self, cmd: Command, len: u64) {
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                seq,
+                timestamp,
+            } => {
+                let version = next_seq(self.seq, seq);
+                self.seq = self.seq.max(version);
+                if !self.watchers.is_empty() {
+                    self.notify(Change {
+                        seq: version,
+                        timestamp,
+                        key: key.clone(),
+                        value: Some(value.clone()),
+                        expires_at,
+                    });
+                }
+
+                let entry = Entry {
+                    value,
+                    expires_at,
+                    len,
+                    version,
+                    timestamp,
+                };
+                self.removed.remove(&key);
+                if let Some(old) = self.store.insert(key.clone(), entry) {
+                    self.uncompacted += old.len;
+                    self.retire(key, old, version);
+                }
+            }
+            Command::Remove {
+                key,
+                seq,
+                timestamp,
+            } => {
+                let version = next_seq(self.seq, seq);
+                self.seq = self.seq.max(version);
+                if !self.watchers.is_empty() {
+                    self.notify(Change {
+                        seq: version,
+                        timestamp,
+                        key: key.clone(),
+                        value: None,
+                        expires_at: None,
+                    });
+                }
+
+                self.uncompacted += len;
+                self.removed.insert(key.clone(), version);
+                if let Some(old) = self.store.remove(&key) {
+                    self.uncompacted += old.len;
+                    self.retire(key, old, version);
+                }
+            }
+            Command::Batch { commands } => {
+                // The record's length is shared evenly between its commands. It
+                // only decides when to compact, so it needn't be exact.
+                let share = len / commands.len().max(1) as u64;
+                for cmd in commands {
+                    self.apply(cmd, share);
+                }
+            }
+            Command::Compacted { seq, .. } => {
+                self.seq = self.seq.max(seq);
+                self.log_start = seq + 1;
+            }
+            Command::LastSeq { seq } => {
+                self.seq = self.seq.max(seq);
+                self.uncompacted += len;
+            }
+            Command::Get { .. } => self.uncompacted += len,
+        }
+    }
+
+    /// Private helper function to send a change to the watchers of its key,
+    /// dropping the watchers that have unsubscribed or fallen too far behind.
+    fn notify(&mut self, change: Change) {
+        self.watchers.retain(|watcher| {
+            change.seq < watcher.from
+                || !change.key.starts_with(&watcher.prefix)
+                || watcher.sender.try_send(change.clone()).is_ok()
+        });
+    }
+
+    /// Private helper function to read back from the log every write since
+    /// `from` to keys starting with `prefix`, for a new watcher.
+    fn replay_changes(&self, prefix: &[u8], from: u64) -> Result<Vec<Change>> {
+        if from < self.log_start {
+            return Err(KvStoreError::CompactedError(from));
+        }
+
+        let mut replayed = Vec::new();
+        let mut last = 0;
+        for record in LogReader::open(&self.path_buf)? {
+            parse(&record?)?.changes(&mut last, &mut |change| {
+                if change.seq >= from && change.key.starts_with(prefix) {
+                    replayed.push(change);
+                }
+            });
+        }
+
+        Ok(replayed)
+    }
+
+    /// Private helper function to write every set and remove in the batch as a
+    /// single log record.
+    fn append_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        if batch.is_empty() {
+            return Ok(self.seq);
+        }
+
+        let commands = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value, ttl } => Command::set(key, value, ttl.map(expires_at)),
+                BatchOp::Remove { key } => Command::remove(key),
+            })
+            .collect();
 
-        let mut cmd = serde_json::to_string(&c)?;
-        cmd.push('\n');
+        self.append(Command::Batch { commands })
+    }
 
-        LineWriter::new(f).write(cmd.as_bytes())?;
+    /// Private helper function to compact the log once enough of it is stale.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
 
         Ok(())
-    } as Result<()>};
+    }
+
+    /// Private helper function to keep a superseded value in the history if a
+    /// snapshot pinned between the write that set it and the write that
+    /// superseded it can still see it.
+    fn retire(&mut self, key: Vec<u8>, entry: Entry, superseded_at: u64) {
+        if self
+            .snapshots
+            .range(entry.version..superseded_at)
+            .next()
+            .is_some()
+        {
+            self.history.entry(key).or_default().push(OldVersion {
+                entry,
+                superseded_at,
+            });
+        }
+    }
+
+    /// Private helper function to unpin a snapshot and drop the old values no
+    /// remaining snapshot can see.
+    fn release(&mut self, seq: u64) {
+        if let Some(count) = self.snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&seq);
+            }
+        }
+
+        let snapshots = &self.snapshots;
+        self.history.retain(|_, versions| {
+            versions.retain(|old| {
+                snapshots
+                    .range(old.entry.version..old.superseded_at)
+                    .next()
+                    .is_some()
+            });
+            !versions.is_empty()
+        });
+    }
+
+    /// Private helper function to return the entry of a key as seen by a
+    /// snapshot pinned to `seq` and taken at `at`.
+    fn entry_at(&self, key: &[u8], seq: u64, at: u64) -> Option<&Entry> {
+        let entry = match self.store.get(key) {
+            Some(entry) if entry.version <= seq => Some(entry),
+            _ => self.history.get(key).and_then(|versions| {
+                versions
+                    .iter()
+                    .find(|old| old.entry.version <= seq && seq < old.superseded_at)
+                    .map(|old| &old.entry)
+            }),
+        };
+
+        entry.filter(|entry| !entry.is_expired(at))
+    }
+
+    /// Private helper function to return the version of a key. A key that
+    /// doesn't exist is at the version of its remove, or of the last
+    /// compaction, which forgets removes.
+    fn version(&self, key: &[u8]) -> Version {
+        match self.store.get(key) {
+            Some(entry) if entry.is_expired(now_millis()) => Version::Absent(entry.version),
+            Some(entry) => Version::Set(entry.version),
+            None => Version::Absent(
+                self.removed
+                    .get(key)
+                    .cloned()
+                    .unwrap_or_else(|| self.log_start.saturating_sub(1)),
+            ),
+        }
+    }
+
+    /// Private helper function to return the entry of a key that hasn't
+    /// expired.
+    fn live_entry(&self, key: &[u8]) -> Option<&Entry> {
+        self.store
+            .get(key)
+            .filter(|entry| !entry.is_expired(now_millis()))
+    }
 }
 
 impl KvsEngine for KvStore {
+    type Snapshot = Snapshot;
+
     /// Retrieves the value of the key/pair given a key as an arguement.
     ///
     /// Returns None, if the key doesn't exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        match self.store.get(&key).cloned() {
-            Some(v) => Ok(Some(v.to_string())),
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.read()?.live_entry(key) {
+            Some(entry) => Ok(Some(entry.value.clone())),
             None => Err(KvStoreError::KeyNotFoundError),
         }
     }
 
-    /// Sets a string value according to a key.
+    /// Sets a value according to a key.
     /// If the key already exists the value will be overwritten.
     ///
     /// TODO: Figure out the failing doc test that has been removed. Use the
     /// course-examples/ for reference.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let set_cmd = Command::Set { key, value };
-        write_cmd!(&set_cmd, self.log_file()?)?;
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<u64> {
+        self.inner
+            .write()?
+            .append(Command::set(key.to_vec(), value.to_vec(), None))
+    }
+
+    /// Sets a value according to a key, expiring after the ttl.
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<u64> {
+        self.inner.write()?.append(Command::set(
+            key.to_vec(),
+            value.to_vec(),
+            Some(expires_at(ttl)),
+        ))
+    }
 
-        if let Command::Set { key, value } = set_cmd {
-            self.store.insert(key, value);
+    /// Returns the time left until the key expires, or None if it never
+    /// expires.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        match self.inner.read()?.live_entry(key) {
+            Some(entry) => Ok(entry
+                .expires_at
+                .map(|t| Duration::from_millis(t.saturating_sub(now_millis())))),
+            None => Err(KvStoreError::KeyNotFoundError),
         }
+    }
 
-        Ok(())
+    /// Removes a key/value pair given a key.
+    fn remove_bytes(&self, key: &[u8]) -> Result<u64> {
+        let mut inner = self.inner.write()?;
+        if inner.live_entry(key).is_none() {
+            return Err(KvStoreError::KeyNotFoundError);
+        }
+
+        inner.append(Command::remove(key.to_vec()))
+    }
+
+    /// Writes every set and remove in the batch as a single log record.
+    fn write_batch(&self, batch: WriteBatch) -> Result<u64> {
+        self.inner.write()?.append_batch(batch)
+    }
+
+    /// Returns the value of the key with the sequence number of the last write
+    /// to it.
+    fn get_versioned_bytes(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Version)> {
+        let inner = self.inner.read()?;
+        let value = inner.live_entry(key).map(|entry| entry.value.clone());
+
+        Ok((value, inner.version(key)))
+    }
+
+    /// Opens the log and notes its length under the lock, then copies it
+    /// without the lock. The log is only ever appended to, and compaction
+    /// replaces it with a new file, so the open log's first bytes don't change.
+    fn backup(&self, dest: &Path) -> Result<BackupManifest> {
+        let (seq, file, len) = {
+            let inner = self.inner.read()?;
+            let file = File::open(&inner.path_buf)?;
+            let len = file.metadata()?.len();
+            (inner.seq, file, len)
+        };
+
+        backup::write(dest, seq, vec![("log.txt".to_string(), file, len)])
+    }
+
+    fn scan_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let inner = self.inner.read()?;
+        let now = now_millis();
+
+        Ok(inner
+            .store
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect())
+    }
+
+    fn scan_page_bytes(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<Vec<Change>> {
+        let inner = self.inner.read()?;
+        let now = now_millis();
+
+        Ok(inner
+            .store
+            .range::<[u8], _>((Bound::Included(start.max(prefix)), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .take(limit)
+            .map(|(key, entry)| Change {
+                seq: entry.version,
+                timestamp: entry.timestamp,
+                key: key.clone(),
+                value: Some(entry.value.clone()),
+                expires_at: entry.expires_at,
+            })
+            .collect())
     }
 
-    /// Removes a key/value pair given a string key.
-    fn remove(&mut self, key: String) -> Result<()> {
-        let cmd = Command::Remove { key };
-        write_cmd!(&cmd, &self.log_file()?)?;
+    /// Checks no key read by the transaction has been written since, then
+    /// writes the transaction's writes as a single log record, all under the
+    /// write lock.
+    fn commit(&self, reads: Vec<(Vec<u8>, Version)>, batch: WriteBatch) -> Result<u64> {
+        let mut inner = self.inner.write()?;
 
-        if let Command::Remove { key } = cmd {
-            match self.store.remove(&key) {
-                Some(_x) => return Ok(()),
-                None => return Err(KvStoreError::KeyNotFoundError),
+        for (key, version) in reads {
+            if inner.version(&key) != version {
+                return Err(KvStoreError::TransactionConflictError(
+                    String::from_utf8_lossy(&key).into_owned(),
+                ));
             }
+        }
+
+        inner.append_batch(batch)
+    }
+
+    /// Compares the current value of the key with `expected` and swaps in
+    /// `new` if they match, all under the write lock.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        let mut inner = self.inner.write()?;
+
+        let current = inner.live_entry(key).map(|entry| entry.value.clone());
+        if current.as_deref() != expected {
+            return Ok(CasOutcome::Mismatch(current));
+        }
+
+        let seq = match new {
+            Some(value) => inner.append(Command::set(key.to_vec(), value.to_vec(), None))?,
+            None if current.is_some() => inner.append(Command::remove(key.to_vec()))?,
+            None => inner.seq,
         };
 
+        Ok(CasOutcome::Swapped(seq))
+    }
+
+    /// Pins the snapshot to the last write. The values a snapshot can see are
+    /// kept in memory after they're overwritten or removed, until the
+    /// snapshot is dropped.
+    ///
+    /// Example:
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, KvsSnapshot, Result};
+    /// # fn main() -> Result<()> {
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path())?;
+    /// store.set("key1".to_owned(), "value1".to_owned())?;
+    ///
+    /// let snapshot = store.snapshot()?;
+    /// store.set("key1".to_owned(), "value2".to_owned())?;
+    /// assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn snapshot(&self) -> Result<Snapshot> {
+        let mut inner = self.inner.write()?;
+        let seq = inner.seq;
+        *inner.snapshots.entry(seq).or_insert(0) += 1;
+
+        Ok(Snapshot {
+            inner: self.inner.clone(),
+            seq,
+            at: now_millis(),
+        })
+    }
+
+    /// Returns the sequence number of the last write applied.
+    fn last_seq(&self) -> Result<u64> {
+        Ok(self.inner.read()?.seq)
+    }
+
+    /// Collects the live keys and registers a watcher for every later write,
+    /// all under the write lock so no write is missed or sent twice.
+    fn keys(&self) -> Result<(u64, Vec<Change>)> {
+        let inner = self.inner.read()?;
+        Ok((inner.seq, inner.live_keys()))
+    }
+
+    fn replicate(&self) -> Result<Replication> {
+        let mut inner = self.inner.write()?;
+        let keys = inner.live_keys();
+
+        let (sender, changes) = mpsc::sync_channel(WATCH_BUFFER);
+        inner.replicas.push(sender);
+
+        Ok(Replication {
+            seq: inner.seq,
+            keys,
+            changes,
+        })
+    }
+
+    /// Replaces the store with the keys and rewrites the log to hold only
+    /// them, as a compaction would.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned while a snapshot is open, since the keys' versions
+    /// needn't follow on from the store's and the snapshot's view can't be
+    /// kept.
+    fn restore(&self, seq: u64, keys: Vec<Change>) -> Result<()> {
+        let mut inner = self.inner.write()?;
+        if !inner.snapshots.is_empty() {
+            return Err(KvStoreError::StringError(
+                "The store can't be restored while a snapshot is open".to_string(),
+            ));
+        }
+
+        inner.history.clear();
+        inner.store = keys
+            .into_iter()
+            .filter_map(|change| {
+                let entry = Entry {
+                    value: change.value?,
+                    expires_at: change.expires_at,
+                    len: 0,
+                    version: change.seq,
+                    timestamp: change.timestamp,
+                };
+                Some((change.key, entry))
+            })
+            .collect();
+        inner.seq = seq;
+
+        inner.compact()
+    }
+
+    /// Writes the changes as a single log record with their own sequence
+    /// numbers and timestamps, skipping the changes at or before the last
+    /// write applied.
+    fn apply_changes(&self, changes: Vec<Change>) -> Result<()> {
+        let mut inner = self.inner.write()?;
+        let seq = inner.seq;
+
+        let mut commands: Vec<Command> = changes
+            .into_iter()
+            .filter(|change| change.seq > seq)
+            .map(|change| match change.value {
+                Some(value) => Command::Set {
+                    key: change.key,
+                    value,
+                    expires_at: change.expires_at,
+                    seq: change.seq,
+                    timestamp: change.timestamp,
+                },
+                None => Command::Remove {
+                    key: change.key,
+                    seq: change.seq,
+                    timestamp: change.timestamp,
+                },
+            })
+            .collect();
+        let cmd = match commands.len() {
+            0 => return Ok(()),
+            1 => commands.remove(0),
+            _ => Command::Batch { commands },
+        };
+        inner.append_stamped(cmd)?;
+
         Ok(())
     }
+
+    /// Replays the writes since `from` from the log and registers the
+    /// watcher, all under the write lock so no write is missed or sent twice.
+    /// The replayed writes don't count towards `WATCH_BUFFER`.
+    fn watch_bytes(&self, prefix: &[u8], from: Option<u64>) -> Result<Receiver<Change>> {
+        let mut inner = self.inner.write()?;
+
+        let prefix = prefix.to_vec();
+        let replayed = match from {
+            Some(from) => inner.replay_changes(&prefix, from)?,
+            None => Vec::new(),
+        };
+        let (sender, receiver) = mpsc::sync_channel(replayed.len() + WATCH_BUFFER);
+        for change in replayed {
+            // The channel has room for every replayed change.
+            let _ = sender.try_send(change);
+        }
+        let from = from.unwrap_or(inner.seq + 1);
+
+        inner.watchers.push(Watcher {
+            prefix,
+            from,
+            sender,
+        });
+
+        Ok(receiver)
+    }
+}
+
+/// Private helper function to return the path a log generation starting after
+/// the write `seq` is archived at, beside the live log. Zero-padded so the
+/// generations sort in order.
+fn archive_path(log: &Path, seq: u64) -> PathBuf {
+    log.with_file_name(ARCHIVE_DIR)
+        .join(format!("log-{:020}.txt", seq))
+}
+
+/// Private helper function to return the sequence number and time a log
+/// generation starts from, read from its compaction header, or zero for a log
+/// that was never compacted.
+fn log_start(log: &Path) -> Result<(u64, u64)> {
+    match LogReader::open(log)?.next().transpose()? {
+        Some(first) => match parse(&first) {
+            Ok(Command::Compacted { seq, timestamp }) => Ok((seq, timestamp)),
+            _ => Ok((0, 0)),
+        },
+        None => Ok((0, 0)),
+    }
+}
+
+/// Private helper function to mark a record written without a checksum as
+/// corrupt if it isn't a valid record.
+fn check(mut record: LogRecord) -> LogRecord {
+    if record.status == RecordStatus::Unchecked && parse(&record).is_err() {
+        record.status = RecordStatus::Corrupt;
+    }
+
+    record
+}
+
+/// Private helper function to parse the command of a record read back from
+/// the log, unless it fails its checksum.
+fn parse(record: &LogRecord) -> Result<Command> {
+    if record.status == RecordStatus::Corrupt {
+        return Err(KvStoreError::CorruptRecordError(record.offset));
+    }
+
+    match record.format {
+        RecordFormat::Json => Ok(serde_json::from_slice(&record.data)?),
+        RecordFormat::MessagePack => Ok(rmp_serde::from_slice(&record.data)?),
+    }
+}
+
+/// Private helper function to return the sequence number of a write logged
+/// with `seq`, numbering a write logged without one after `last`.
+fn next_seq(last: u64, seq: u64) -> u64 {
+    if seq == 0 {
+        last + 1
+    } else {
+        seq
+    }
 }
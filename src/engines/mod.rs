@@ -1,27 +1,479 @@
 //! This module provies the key value storage engines.
 
-use crate::Result;
+use crate::common::now_millis;
+use crate::export::{self, ExportFormat};
+use crate::{Codec, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
 
 /// Trait (interface) for the key value storage engine.
-pub trait KvsEngine {
+///
+/// Keys and values are arbitrary bytes. The `String` methods are conveniences
+/// on top of the byte methods, for keys and values that are text.
+///
+/// An engine is shared between the server's connections by cloning it, so
+/// every clone must refer to the same data and writes must be safe to call
+/// concurrently.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// The read-only view returned by `snapshot`.
+    type Snapshot: KvsSnapshot;
+
+    /// Sets the value of a key, both arbitrary bytes.
+    ///
+    /// If the key already exists then the value will be overwritten. Returns
+    /// the sequence number of the write.
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<u64>;
+
+    /// Sets the value of a key that expires after the `ttl`.
+    ///
+    /// Once expired the key behaves as if it was removed. Returns the sequence
+    /// number of the write.
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<u64>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the key does not exist.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the time left until a key expires.
+    ///
+    /// Returns `None` if the key was set without a ttl.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>>;
+
+    /// Removes a given key, returning the sequence number of the write.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvsError::KeyNotFound` is returned if a key does not exist.
+    fn remove_bytes(&self, key: &[u8]) -> Result<u64>;
+
+    /// Returns every key starting with `prefix`, and its value, in key order.
+    fn scan_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns up to `limit` live keys starting with `prefix`, from the first
+    /// at or after `start`, in key order. Each key is returned as the change
+    /// that set it, with its version and expiry. Scans a keyspace too large
+    /// to hold at once a page at a time.
+    fn scan_page_bytes(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<Vec<Change>>;
+
     /// Sets value of a key - all strings.
     ///
-    /// If the key already exists then the value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    /// If the key already exists then the value will be overwritten. Returns
+    /// the sequence number of the write.
+    fn set(&self, key: String, value: String) -> Result<u64> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Sets value of a key that expires after the `ttl`.
+    ///
+    /// Once expired the key behaves as if it was removed. Returns the sequence
+    /// number of the write.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<u64> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
 
     /// Gets the value of a given key.
     ///
     /// Returns `None` if the key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if the value isn't
+    /// UTF-8. Use `get_bytes` for binary values.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        into_string(self.get_bytes(key.as_bytes())?)
+    }
+
+    /// Gets the time left until a key expires.
+    ///
+    /// Returns `None` if the key was set without a ttl.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.as_bytes())
+    }
 
-    /// Removes a given key.
+    /// Removes a given key, returning the sequence number of the write.
     ///
     /// # Errors
     ///
     /// An error `KvsError::KeyNotFound` is returned if a key does not exist.
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<u64> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Returns every key starting with `prefix`, and its value, in key order.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if a key or value
+    /// isn't UTF-8. Use `scan_bytes` for binary keys and values.
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        into_strings(self.scan_bytes(prefix.as_bytes())?)
+    }
+
+    /// Sets the value of a key to `value` encoded with the codec, returning
+    /// the sequence number of the write.
+    fn set_typed<T: Serialize + ?Sized, C: Codec>(
+        &self,
+        key: String,
+        value: &T,
+        codec: C,
+    ) -> Result<u64> {
+        self.set_bytes(key.as_bytes(), &codec.encode(value)?)
+    }
+
+    /// Gets the value of a key decoded with the codec it was set with.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::KeyNotFoundError` is returned if the key does
+    /// not exist, as both engines' `get_bytes` do. An error
+    /// `KvStoreError::DecodeError` is returned if the value isn't a `T`
+    /// encoded with the codec.
+    fn get_typed<T: DeserializeOwned, C: Codec>(&self, key: String, codec: C) -> Result<Option<T>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies every write in the batch atomically.
+    ///
+    /// Either all of the writes are persisted or, on error or crash, none of
+    /// them are. Returns the sequence number of the last write in the batch.
+    fn write_batch(&self, batch: WriteBatch) -> Result<u64>;
+
+    /// Sets the key to `new` only if its current value is `expected`, as one
+    /// atomic operation, all arbitrary bytes.
+    ///
+    /// `None` as `expected` means the key must not exist, and `None` as `new`
+    /// removes the key.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasOutcome<Vec<u8>>>;
+
+    /// Gets the value of a key with its version, which changes every time the
+    /// key is written, removes included.
+    ///
+    /// The value is `None` if the key does not exist.
+    fn get_versioned_bytes(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Version)>;
+
+    /// Applies the batch atomically only if every key in `reads` is still at
+    /// the version given. Returns the sequence number of the last write.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::TransactionConflictError` is returned, and
+    /// nothing is written, if any key read has changed.
+    fn commit(&self, reads: Vec<(Vec<u8>, Version)>, batch: WriteBatch) -> Result<u64>;
+
+    /// Subscribes to every write to keys starting with the binary `prefix`,
+    /// received in order until the receiver is dropped.
+    ///
+    /// With `from`, the writes since that sequence number are received first.
+    /// Without it, only writes made after subscribing are. A watcher that falls
+    /// more than `WATCH_BUFFER` writes behind is unsubscribed, and its
+    /// receiver disconnects once drained.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::CompactedError` is returned if writes since
+    /// `from` have been compacted out of the log.
+    fn watch_bytes(&self, prefix: &[u8], from: Option<u64>) -> Result<Receiver<Change>>;
+
+    /// Sets the binary key only if it doesn't already exist, as one atomic
+    /// operation.
+    fn set_if_absent_bytes(&self, key: &[u8], value: &[u8]) -> Result<CasOutcome<Vec<u8>>> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Sets the key to `new` only if its current value is `expected`, as one
+    /// atomic operation.
+    ///
+    /// `None` as `expected` means the key must not exist, and `None` as `new`
+    /// removes the key.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned on a mismatch if
+    /// the current value isn't UTF-8.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<CasOutcome> {
+        into_string_outcome(self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.as_ref().map(String::as_bytes),
+        )?)
+    }
+
+    /// Sets the key only if it doesn't already exist, as one atomic operation.
+    fn set_if_absent(&self, key: String, value: String) -> Result<CasOutcome> {
+        into_string_outcome(self.set_if_absent_bytes(key.as_bytes(), value.as_bytes())?)
+    }
+
+    /// Gets the value of a key with its version.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if the value isn't
+    /// UTF-8. Use `get_versioned_bytes` for binary values.
+    fn get_versioned(&self, key: String) -> Result<(Option<String>, Version)> {
+        let (value, version) = self.get_versioned_bytes(key.as_bytes())?;
+        Ok((into_string(value)?, version))
+    }
+
+    /// Subscribes to every write to keys starting with `prefix`, received in
+    /// order until the receiver is dropped. See `watch_bytes`.
+    fn watch(&self, prefix: String, from: Option<u64>) -> Result<Receiver<Change>> {
+        self.watch_bytes(prefix.as_bytes(), from)
+    }
+
+    /// Returns a read-only view of the engine as of now, pinned to the last
+    /// write, which later writes don't change.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Returns the sequence number of the last write.
+    fn last_seq(&self) -> Result<u64>;
+
+    /// Returns every live key, as the change that last set it, with the
+    /// sequence number of the last write they include.
+    fn keys(&self) -> Result<(u64, Vec<Change>)>;
+
+    /// Returns every live key, to bootstrap a replica from, and subscribes to
+    /// every write made after them, like a watcher of every key.
+    fn replicate(&self) -> Result<Replication>;
+
+    /// Replaces every key with `keys`, as of the write `seq`, to bootstrap a
+    /// replica.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if a snapshot whose view the restore would change
+    /// is still open.
+    fn restore(&self, seq: u64, keys: Vec<Change>) -> Result<()>;
+
+    /// Applies a write made on the leader to a replica as one write, with
+    /// every change it made, keeping their sequence numbers and timestamps.
+    fn apply_changes(&self, changes: Vec<Change>) -> Result<()>;
+
+    /// Copies the log, as of the last write, into the directory with a
+    /// manifest of its files and their checksums. The directory must be empty
+    /// or not exist. Writes carry on while the copy is made.
+    fn backup(&self, dest: &Path) -> Result<BackupManifest>;
+
+    /// Starts an optimistic transaction.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Writes every live key starting with `prefix`, its value and when it
+    /// expires, to `writer` in the format, in key order, a page of keys at a
+    /// time. Returns the number of keys written.
+    fn export<W: Write>(&self, prefix: String, format: ExportFormat, writer: W) -> Result<u64> {
+        let prefix = prefix.as_bytes();
+        export::write_pages(prefix, format, writer, |start, limit| {
+            self.scan_page_bytes(prefix, start, limit)
+        })
+    }
+
+    /// Sets every key starting with `prefix` read from `reader` in the
+    /// format, in batches as they're read. Returns the number of keys set.
+    fn import<R: Read>(&self, prefix: String, format: ExportFormat, reader: R) -> Result<u64> {
+        export::import(reader, format, &prefix, |batch| self.write_batch(batch))
+    }
+}
+
+/// A read-only, point-in-time view of an engine, returned by
+/// `KvsEngine::snapshot`.
+///
+/// A snapshot sees every key as of the last write before it was taken, and
+/// keys expire as of when it was taken.
+pub trait KvsSnapshot: Send + 'static {
+    /// Gets the value of a binary key as of the snapshot.
+    ///
+    /// Returns `None` if the key didn't exist.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Returns every key starting with the binary `prefix`, and its value, as
+    /// of the snapshot, in key order.
+    fn scan_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns the sequence number of the last write the snapshot sees.
+    fn seq(&self) -> u64;
+
+    /// Gets the value of a key as of the snapshot.
+    ///
+    /// Returns `None` if the key didn't exist.
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        into_string(self.get_bytes(key.as_bytes())?)
+    }
+
+    /// Returns every key starting with `prefix`, and its value, as of the
+    /// snapshot, in key order.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        into_strings(self.scan_bytes(prefix.as_bytes())?)
+    }
+}
+
+/// The outcome of a conditional write, holding values of type `V`: `String`
+/// for the `String` methods and `Vec<u8>` for the byte methods.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CasOutcome<V = String> {
+    /// The key held the expected value and the write was made. Holds the
+    /// sequence number of the write, or of the last write if removing a key
+    /// that didn't exist wrote nothing.
+    Swapped(u64),
+
+    /// The key didn't hold the expected value so nothing was written. Holds
+    /// the current value, so the caller can retry without another read.
+    Mismatch(Option<V>),
+}
+
+impl<V> CasOutcome<V> {
+    /// Converts the current value of a mismatch with `f`.
+    pub(crate) fn map<W>(self, f: impl FnOnce(V) -> W) -> CasOutcome<W> {
+        match self {
+            CasOutcome::Swapped(seq) => CasOutcome::Swapped(seq),
+            CasOutcome::Mismatch(value) => CasOutcome::Mismatch(value.map(f)),
+        }
+    }
+}
+
+/// The version of a key, as read by a transaction and checked again when it
+/// commits.
+///
+/// A key that doesn't exist still has a version, so a key set and removed
+/// again between the read and the commit is seen to have changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Version {
+    /// The key holds the value set by the write with this sequence number.
+    Set(u64),
+
+    /// The key doesn't exist. Holds the sequence number of the last write to
+    /// it, or of the point the engine last forgot its removes, whichever is
+    /// later.
+    Absent(u64),
+}
+
+/// The number of changes a watcher may fall behind the writes by. A watcher
+/// further behind is unsubscribed, ending its stream, so a slow watcher can't
+/// hold every write in memory.
+pub(crate) const WATCH_BUFFER: usize = 1024;
+
+/// A write to a key, as received by a watcher.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// The sequence number of the write.
+    pub seq: u64,
+
+    /// When the write was made, in milliseconds since the unix epoch.
+    pub timestamp: u64,
+
+    /// The key written.
+    #[serde(with = "crate::bytes")]
+    pub key: Vec<u8>,
+
+    /// The value set, or `None` if the key was removed.
+    #[serde(with = "crate::bytes::option")]
+    pub value: Option<Vec<u8>>,
+
+    /// When the key set expires, in milliseconds since the unix epoch.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl Change {
+    /// Returns whether the key set has expired by now.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= now_millis())
+    }
+}
+
+/// Converts an optional value of bytes into a string.
+pub(crate) fn into_string(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    Ok(value.map(String::from_utf8).transpose()?)
+}
+
+/// Converts the outcome of a conditional write of bytes into strings.
+pub(crate) fn into_string_outcome(outcome: CasOutcome<Vec<u8>>) -> Result<CasOutcome> {
+    match outcome {
+        CasOutcome::Swapped(seq) => Ok(CasOutcome::Swapped(seq)),
+        CasOutcome::Mismatch(value) => Ok(CasOutcome::Mismatch(into_string(value)?)),
+    }
+}
+
+/// Converts key/value pairs of bytes into strings.
+pub(crate) fn into_strings(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect()
+}
+
+/// The live keys of an engine, returned by `KvsEngine::replicate`, with the
+/// writes made after them.
+pub struct Replication {
+    /// The sequence number of the last write included in `keys`.
+    pub seq: u64,
+
+    /// Every live key, as the change that last set it.
+    pub keys: Vec<Change>,
+
+    /// Every write after `seq`, each with every change it made, such as all
+    /// the sets and removes of a batch.
+    pub changes: Receiver<Vec<Change>>,
+}
+
+/// The file in a data directory naming the engine whose data it holds.
+const ENGINE_MARKER: &str = "engine";
+
+/// Returns the name of the engine whose data is in the directory, from the
+/// marker written by `write_engine_marker`, or `None` for a directory without
+/// data.
+///
+/// A directory with a log but no marker was written before markers existed,
+/// so holds `kvs` data.
+pub fn read_engine_marker(dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(dir.join(ENGINE_MARKER)) {
+        Ok(engine) => Ok(Some(engine.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Ok(Some("kvs".to_string()).filter(|_| dir.join("log.txt").exists()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Marks the directory as holding the data of the engine, so it isn't opened
+/// with another.
+pub fn write_engine_marker(dir: &Path, engine: &str) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(ENGINE_MARKER), engine)?;
+    Ok(())
 }
 
+mod backup;
+mod batch;
 mod kvs;
+mod record;
+mod sled;
+mod transaction;
 
-pub use self::kvs::KvStore;
+pub use self::backup::{BackupFile, BackupManifest};
+pub(crate) use self::batch::BatchOp;
+pub use self::batch::WriteBatch;
+pub use self::kvs::{KvStore, LogStats, RecoveryTarget, Repair, Snapshot};
+pub use self::record::{LogReader, LogRecord, RecordFormat, RecordStatus};
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+pub use self::transaction::Transaction;
+pub(crate) use self::transaction::TransactionState;
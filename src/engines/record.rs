@@ -0,0 +1,272 @@
+//! The framing of the records in a store's log.
+//!
+//! Each record is a line holding a JSON command, a tab and the CRC-32 of the
+//! JSON in hex. JSON never holds a raw tab, so the checksum is always after
+//! the last one. Records written before checksums existed have none and are
+//! read without one.
+//!
+//! A command whose keys or values aren't all UTF-8 is written as a binary
+//! frame instead, so its bytes are kept as they are: a zero byte, which never
+//! starts a line of JSON, then the length of the MessagePack command and the
+//! CRC-32 of that length, then the command and its CRC-32. Lengths and
+//! checksums are 4 bytes, big-endian. The length has a checksum of its own so
+//! a damaged length is caught before it's used to find the next record.
+
+use crate::bytes::AnyValue;
+use crate::Result;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+/// A record read back from a store's log by `LogReader`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// The offset in bytes of the record from the start of the log.
+    pub offset: u64,
+
+    /// The length in bytes of the record, including its framing.
+    pub len: u64,
+
+    pub format: RecordFormat,
+
+    /// The command of the record, without its framing.
+    pub data: Vec<u8>,
+
+    pub status: RecordStatus,
+}
+
+impl LogRecord {
+    /// Returns the command of the record as JSON, for display. Binary values
+    /// are shown as base64 objects.
+    pub fn to_json(&self) -> Result<String> {
+        match self.format {
+            RecordFormat::Json => Ok(String::from_utf8_lossy(&self.data).into_owned()),
+            RecordFormat::MessagePack => {
+                let value: AnyValue = rmp_serde::from_slice(&self.data)?;
+                Ok(serde_json::to_string(&value)?)
+            }
+        }
+    }
+}
+
+/// How the command of a record is encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    /// A line of JSON.
+    Json,
+
+    /// A binary frame of MessagePack.
+    MessagePack,
+}
+
+/// Whether a record read back from a log is intact.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordStatus {
+    /// The record matches its checksum.
+    Ok,
+
+    /// The record was written without a checksum, so can't be checked.
+    Unchecked,
+
+    /// The record doesn't match its checksum.
+    Corrupt,
+
+    /// The record has no trailing newline, or its frame ends early, so was cut
+    /// short by a crash mid-write. Only the last record of a log can be torn.
+    Torn,
+}
+
+impl RecordStatus {
+    /// Returns whether the record can be replayed.
+    pub fn is_intact(self) -> bool {
+        match self {
+            RecordStatus::Ok | RecordStatus::Unchecked => true,
+            RecordStatus::Corrupt | RecordStatus::Torn => false,
+        }
+    }
+}
+
+impl fmt::Display for RecordStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            RecordStatus::Ok => "ok",
+            RecordStatus::Unchecked => "unchecked",
+            RecordStatus::Corrupt => "corrupt",
+            RecordStatus::Torn => "torn",
+        };
+        f.write_str(status)
+    }
+}
+
+/// Reads the records of a log in order, with their offsets, checking each
+/// against its checksum.
+pub struct LogReader<R> {
+    reader: BufReader<R>,
+    offset: u64,
+    buf: Vec<u8>,
+}
+
+impl LogReader<File> {
+    /// Opens the log at the path for reading.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(LogReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(reader: R) -> Self {
+        LogReader {
+            reader: BufReader::new(reader),
+            offset: 0,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let binary = match self.reader.fill_buf() {
+            Ok([]) => return None,
+            Ok(buf) => buf[0] == FRAME_MARKER,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        self.buf.clear();
+        let record = if binary {
+            self.read_frame()
+        } else {
+            self.reader
+                .read_until(b'\n', &mut self.buf)
+                .map(|len| decode(self.offset, len as u64, &self.buf))
+        };
+
+        match record {
+            Ok(record) => {
+                self.offset += record.len;
+                Some(Ok(record))
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl<R: Read> LogReader<R> {
+    /// Private helper function to read a binary frame and check its
+    /// checksums.
+    fn read_frame(&mut self) -> std::io::Result<LogRecord> {
+        let mut record = LogRecord {
+            offset: self.offset,
+            len: 0,
+            format: RecordFormat::MessagePack,
+            data: Vec::new(),
+            status: RecordStatus::Torn,
+        };
+
+        (&mut self.reader)
+            .take(FRAME_HEADER_LEN)
+            .read_to_end(&mut self.buf)?;
+        record.len = self.buf.len() as u64;
+        if record.len < FRAME_HEADER_LEN {
+            return Ok(record);
+        }
+
+        // Without a length to trust, the rest of the log can't be split into
+        // records, so it's taken as one damaged record.
+        let (len, checksum) = (&self.buf[1..5], be_u32(&self.buf[5..9]));
+        if crc32fast::hash(len) != checksum {
+            record.len += std::io::copy(&mut self.reader, &mut std::io::sink())?;
+            record.status = RecordStatus::Corrupt;
+            return Ok(record);
+        }
+
+        let len = u64::from(be_u32(len));
+        self.buf.clear();
+        (&mut self.reader)
+            .take(len + 4)
+            .read_to_end(&mut self.buf)?;
+        record.len += self.buf.len() as u64;
+        if (self.buf.len() as u64) < len + 4 {
+            return Ok(record);
+        }
+
+        let checksum = be_u32(&self.buf[len as usize..]);
+        self.buf.truncate(len as usize);
+        record.status = if crc32fast::hash(&self.buf) == checksum {
+            RecordStatus::Ok
+        } else {
+            RecordStatus::Corrupt
+        };
+        record.data = std::mem::take(&mut self.buf);
+
+        Ok(record)
+    }
+}
+
+/// The first byte of a binary frame.
+const FRAME_MARKER: u8 = 0;
+
+/// The length in bytes of a binary frame's marker, length and length
+/// checksum.
+const FRAME_HEADER_LEN: u64 = 9;
+
+/// Frames the JSON of a command as a record, with its checksum and newline.
+pub(crate) fn encode(json: &str) -> String {
+    format!("{}\t{:08x}\n", json, crc32fast::hash(json.as_bytes()))
+}
+
+/// Frames the MessagePack of a command as a binary record, with its length
+/// and checksums.
+pub(crate) fn encode_binary(data: &[u8]) -> Vec<u8> {
+    let len = (data.len() as u32).to_be_bytes();
+
+    let mut frame = Vec::with_capacity(data.len() + FRAME_HEADER_LEN as usize + 4);
+    frame.push(FRAME_MARKER);
+    frame.extend_from_slice(&len);
+    frame.extend_from_slice(&crc32fast::hash(&len).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame.extend_from_slice(&crc32fast::hash(data).to_be_bytes());
+    frame
+}
+
+/// Private helper function to read a big-endian `u32` from 4 bytes.
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Private helper function to split a line read from a log into its JSON and
+/// checksum, and check one against the other.
+fn decode(offset: u64, len: u64, line: &[u8]) -> LogRecord {
+    let (line, status) = match line.split_last() {
+        Some((b'\n', line)) => (line, None),
+        _ => (line, Some(RecordStatus::Torn)),
+    };
+
+    let (json, status) = match line.iter().rposition(|&b| b == b'\t') {
+        Some(tab) => {
+            let (json, checksum) = (&line[..tab], &line[tab + 1..]);
+            let matches = std::str::from_utf8(checksum)
+                .ok()
+                .and_then(|checksum| u32::from_str_radix(checksum, 16).ok())
+                == Some(crc32fast::hash(json));
+            let checked = if matches {
+                RecordStatus::Ok
+            } else {
+                RecordStatus::Corrupt
+            };
+            (json, status.unwrap_or(checked))
+        }
+        None => (line, status.unwrap_or(RecordStatus::Unchecked)),
+    };
+
+    LogRecord {
+        offset,
+        len,
+        format: RecordFormat::Json,
+        data: json.to_vec(),
+        status,
+    }
+}
@@ -0,0 +1,628 @@
+use super::{
+    BackupManifest, BatchOp, CasOutcome, Change, KvsSnapshot, Replication, Version, WATCH_BUFFER,
+};
+use crate::common::{expires_at, now_millis};
+use crate::{KvStoreError, KvsEngine, Result, WriteBatch};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionError};
+use sled::{Db, Transactional, Tree};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The name of the tree holding the engine's own state, beside the keys.
+const META_TREE: &str = "meta";
+
+/// The key, in the meta tree, of the sequence number of the last write.
+const SEQ_KEY: &[u8] = b"seq";
+
+/// The name of the tree holding the sequence number of the remove of each key
+/// removed since the tombstones were last cleared.
+const TOMBSTONES_TREE: &str = "tombstones";
+
+/// The key, in the meta tree, of the sequence number of the last write when
+/// the tombstones were last cleared, the version of a key without one.
+const FLOOR_KEY: &[u8] = b"tombstone_floor";
+
+/// The number of tombstones kept before they're cleared.
+const MAX_TOMBSTONES: usize = 10_000;
+
+/// How long `open` waits for the lock on a database whose last handle was
+/// just dropped. sled's background threads release it shortly after.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A Key/Value store backed by the sled embedded database.
+///
+/// Each key is stored with the sequence number and time of the write that set
+/// it, and when it expires. Writes are made one at a time under a lock, each
+/// in a single sled transaction with the new sequence number, so a crash
+/// leaves either all of a write or none of it.
+///
+/// sled keeps no history of writes, so a watch can't start from an earlier
+/// write and the engine can't be backed up. Export its keys instead.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    meta: Tree,
+    tombstones: Tree,
+    state: Arc<Mutex<State>>,
+}
+
+/// A read-only, point-in-time view of a `SledKvsEngine`, returned by
+/// `KvsEngine::snapshot`.
+///
+/// sled keeps no old versions of keys, so the snapshot holds a copy of every
+/// live key and its value, taken under the engine's lock. Taking one costs
+/// memory and time in proportion to the size of the database.
+pub struct SledSnapshot {
+    seq: u64,
+    pairs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(key).cloned())
+    }
+
+    fn scan_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .pairs
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+/// The state of a `SledKvsEngine` shared by its clones, behind its lock.
+struct State {
+    /// The sequence number of the last write applied.
+    seq: u64,
+
+    /// The version of a removed key without a tombstone.
+    floor: u64,
+
+    /// The number of tombstones in the tombstones tree.
+    tombstones: usize,
+
+    /// The subscribers to writes, sent each write as it's applied.
+    watchers: Vec<Watcher>,
+
+    /// The replicas subscribed to writes, sent every change of each write
+    /// together.
+    replicas: Vec<SyncSender<Vec<Change>>>,
+}
+
+/// A subscriber to the writes to keys starting with a prefix.
+struct Watcher {
+    prefix: Vec<u8>,
+    sender: SyncSender<Change>,
+}
+
+/// A value as stored in sled, in MessagePack. Records written before binary
+/// values were supported are JSON.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(with = "crate::bytes")]
+    value: Vec<u8>,
+
+    /// The sequence number of the write that set this value.
+    version: u64,
+
+    /// When the write that set this value was made, in milliseconds since the
+    /// unix epoch.
+    timestamp: u64,
+
+    /// When the key expires, in milliseconds since the unix epoch.
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+impl Record {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+
+    /// A JSON record is an object, which starts with a brace where a
+    /// MessagePack record starts with a map marker.
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes.first() {
+            Some(b'{') => Ok(serde_json::from_slice(bytes)?),
+            _ => Ok(rmp_serde::from_slice(bytes)?),
+        }
+    }
+}
+
+impl SledKvsEngine {
+    /// Opens the sled database in the directory, creating it if it doesn't
+    /// exist.
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = open_db(path)?;
+        let meta = db.open_tree(META_TREE)?;
+        let tombstones = db.open_tree(TOMBSTONES_TREE)?;
+        let seq = read_seq(&meta, SEQ_KEY)?;
+        let floor = read_seq(&meta, FLOOR_KEY)?;
+
+        Ok(SledKvsEngine {
+            db,
+            meta,
+            state: Arc::new(Mutex::new(State {
+                seq,
+                floor,
+                tombstones: tombstones.len(),
+                watchers: Vec::new(),
+                replicas: Vec::new(),
+            })),
+            tombstones,
+        })
+    }
+
+    /// Private helper function to return every live key, as the change that
+    /// last set it, in the order they were written. The state must be locked
+    /// so no write lands in between.
+    fn live_keys(&self) -> Result<Vec<Change>> {
+        let now = now_millis();
+        let mut keys = Vec::new();
+        for item in self.db.iter() {
+            let (key, bytes) = item?;
+            let record = Record::decode(&bytes)?;
+            if !record.is_expired(now) {
+                keys.push(Change {
+                    seq: record.version,
+                    timestamp: record.timestamp,
+                    key: key.to_vec(),
+                    value: Some(record.value),
+                    expires_at: record.expires_at,
+                });
+            }
+        }
+        keys.sort_by_key(|change| change.seq);
+
+        Ok(keys)
+    }
+
+    /// Private helper function to return the version of a key. A key that
+    /// doesn't exist is at the version of its tombstone, or of when the
+    /// tombstones were last cleared.
+    fn version(&self, state: &State, key: &[u8]) -> Result<Version> {
+        if let Some(bytes) = self.db.get(key)? {
+            let record = Record::decode(&bytes)?;
+            return Ok(if record.is_expired(now_millis()) {
+                Version::Absent(record.version)
+            } else {
+                Version::Set(record.version)
+            });
+        }
+
+        match self.tombstones.get(key)? {
+            Some(bytes) => Ok(Version::Absent(serde_json::from_slice(&bytes)?)),
+            None => Ok(Version::Absent(state.floor)),
+        }
+    }
+
+    /// Private helper function to clear the tombstones once there are too
+    /// many, moving every removed key to the version of the last write. The
+    /// floor is raised first, so a crash in between only makes transactions
+    /// that read a removed key conflict.
+    fn prune_tombstones(&self, state: &mut State) -> Result<()> {
+        if state.tombstones <= MAX_TOMBSTONES {
+            return Ok(());
+        }
+
+        self.meta
+            .insert(FLOOR_KEY, serde_json::to_vec(&state.seq)?)?;
+        self.tombstones.clear()?;
+        state.floor = state.seq;
+        state.tombstones = 0;
+
+        Ok(())
+    }
+
+    /// Private helper function to return the record of a key that hasn't
+    /// expired.
+    fn live_record(&self, key: &[u8]) -> Result<Option<Record>> {
+        match self.db.get(key)? {
+            Some(bytes) => {
+                let record = Record::decode(&bytes)?;
+                Ok(Some(record).filter(|record| !record.is_expired(now_millis())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Private helper function to apply writes, already given their sequence
+    /// numbers, in one transaction with the sequence number of the last, and
+    /// send them to the watchers. Returns the sequence number of the last
+    /// write applied.
+    fn apply(&self, state: &mut State, changes: Vec<Change>) -> Result<u64> {
+        let seq = match changes.last() {
+            Some(change) => change.seq,
+            None => return Ok(state.seq),
+        };
+
+        let mut writes = Vec::with_capacity(changes.len());
+        for change in &changes {
+            let record = match &change.value {
+                Some(value) => Some(
+                    Record {
+                        value: value.clone(),
+                        version: change.seq,
+                        timestamp: change.timestamp,
+                        expires_at: change.expires_at,
+                    }
+                    .encode()?,
+                ),
+                None => None,
+            };
+            writes.push((change.key.as_slice(), record));
+        }
+        let seq_bytes = serde_json::to_vec(&seq)?;
+        let removed_at: Vec<Vec<u8>> = changes
+            .iter()
+            .map(|change| serde_json::to_vec(&change.seq))
+            .collect::<serde_json::Result<_>>()?;
+
+        // A transaction can be retried, so the tombstones it adds are counted
+        // afresh each time.
+        let added = Cell::new(0isize);
+        (&*self.db, &self.meta, &self.tombstones)
+            .transaction(
+                |(keys, meta, tombstones)| -> ConflictableTransactionResult<(), ()> {
+                    added.set(0);
+                    for ((key, record), seq) in writes.iter().zip(&removed_at) {
+                        let count = match record {
+                            Some(record) => {
+                                keys.insert(*key, record.as_slice())?;
+                                -(tombstones.remove(*key)?.is_some() as isize)
+                            }
+                            None => {
+                                keys.remove(*key)?;
+                                tombstones.insert(*key, seq.as_slice())?.is_none() as isize
+                            }
+                        };
+                        added.set(added.get() + count);
+                    }
+                    meta.insert(SEQ_KEY, seq_bytes.as_slice())?;
+                    Ok(())
+                },
+            )
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => KvStoreError::from(e),
+                TransactionError::Abort(()) => {
+                    KvStoreError::StringError("The write was aborted".to_string())
+                }
+            })?;
+        state.seq = seq;
+        state.tombstones = (state.tombstones as isize + added.get()).max(0) as usize;
+        self.prune_tombstones(state)?;
+
+        state
+            .replicas
+            .retain(|sender| sender.try_send(changes.clone()).is_ok());
+        for change in changes {
+            state.watchers.retain(|watcher| {
+                !change.key.starts_with(&watcher.prefix)
+                    || watcher.sender.try_send(change.clone()).is_ok()
+            });
+        }
+
+        Ok(seq)
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    type Snapshot = SledSnapshot;
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.live_record(key)? {
+            Some(record) => Ok(Some(record.value)),
+            None => Err(KvStoreError::KeyNotFoundError),
+        }
+    }
+
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<u64> {
+        let mut state = self.state.lock()?;
+        let change = write(state.seq + 1, key.to_vec(), Some(value.to_vec()), None);
+        self.apply(&mut state, vec![change])
+    }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<u64> {
+        let mut state = self.state.lock()?;
+        let change = write(
+            state.seq + 1,
+            key.to_vec(),
+            Some(value.to_vec()),
+            Some(expires_at(ttl)),
+        );
+        self.apply(&mut state, vec![change])
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        match self.live_record(key)? {
+            Some(record) => Ok(record
+                .expires_at
+                .map(|t| Duration::from_millis(t.saturating_sub(now_millis())))),
+            None => Err(KvStoreError::KeyNotFoundError),
+        }
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<u64> {
+        let mut state = self.state.lock()?;
+        if self.live_record(key)?.is_none() {
+            return Err(KvStoreError::KeyNotFoundError);
+        }
+
+        let change = write(state.seq + 1, key.to_vec(), None, None);
+        self.apply(&mut state, vec![change])
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<u64> {
+        let mut state = self.state.lock()?;
+        let changes = batch_changes(state.seq, batch);
+        self.apply(&mut state, changes)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        let mut state = self.state.lock()?;
+
+        let current = self.live_record(key)?.map(|record| record.value);
+        if current.as_deref() != expected {
+            return Ok(CasOutcome::Mismatch(current));
+        }
+
+        let mut seq = state.seq;
+        if new.is_some() || current.is_some() {
+            let change = write(state.seq + 1, key.to_vec(), new.map(<[u8]>::to_vec), None);
+            seq = self.apply(&mut state, vec![change])?;
+        }
+
+        Ok(CasOutcome::Swapped(seq))
+    }
+
+    /// Reads the value and version under the lock, so they're of the same
+    /// write.
+    fn get_versioned_bytes(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Version)> {
+        let state = self.state.lock()?;
+        let value = self.live_record(key)?.map(|record| record.value);
+
+        Ok((value, self.version(&state, key)?))
+    }
+
+    fn scan_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+
+        for item in self.db.scan_prefix(prefix) {
+            let (key, bytes) = item?;
+            let record = Record::decode(&bytes)?;
+            if !record.is_expired(now) {
+                pairs.push((key.to_vec(), record.value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn scan_page_bytes(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<Vec<Change>> {
+        let now = now_millis();
+        let mut page = Vec::new();
+
+        for item in self.db.range(start.max(prefix)..) {
+            let (key, bytes) = item?;
+            if !key.starts_with(prefix) || page.len() == limit {
+                break;
+            }
+
+            let record = Record::decode(&bytes)?;
+            if !record.is_expired(now) {
+                page.push(Change {
+                    seq: record.version,
+                    timestamp: record.timestamp,
+                    key: key.to_vec(),
+                    value: Some(record.value),
+                    expires_at: record.expires_at,
+                });
+            }
+        }
+
+        Ok(page)
+    }
+
+    /// Checks no key read by the transaction has been written since, then
+    /// applies the transaction's writes, all under the lock.
+    fn commit(&self, reads: Vec<(Vec<u8>, Version)>, batch: WriteBatch) -> Result<u64> {
+        let mut state = self.state.lock()?;
+
+        for (key, version) in reads {
+            if self.version(&state, &key)? != version {
+                return Err(KvStoreError::TransactionConflictError(
+                    String::from_utf8_lossy(&key).into_owned(),
+                ));
+            }
+        }
+
+        let changes = batch_changes(state.seq, batch);
+        self.apply(&mut state, changes)
+    }
+
+    /// Registers a watcher for every later write.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::CompactedError` is returned if `from` is at or
+    /// before the last write, since sled keeps no history to replay.
+    fn watch_bytes(&self, prefix: &[u8], from: Option<u64>) -> Result<Receiver<Change>> {
+        let mut state = self.state.lock()?;
+        if let Some(from) = from {
+            if from <= state.seq {
+                return Err(KvStoreError::CompactedError(from));
+            }
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(WATCH_BUFFER);
+        state.watchers.push(Watcher {
+            prefix: prefix.to_vec(),
+            sender,
+        });
+
+        Ok(receiver)
+    }
+
+    /// Copies every live key under the lock, so the copy is of a single
+    /// write.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let state = self.state.lock()?;
+        let pairs = self.scan_bytes(b"")?.into_iter().collect();
+
+        Ok(SledSnapshot {
+            seq: state.seq,
+            pairs,
+        })
+    }
+
+    fn last_seq(&self) -> Result<u64> {
+        Ok(self.state.lock()?.seq)
+    }
+
+    /// Collects the live keys and registers a watcher for every later write,
+    /// all under the lock so no write is missed or sent twice.
+    fn keys(&self) -> Result<(u64, Vec<Change>)> {
+        let state = self.state.lock()?;
+        Ok((state.seq, self.live_keys()?))
+    }
+
+    fn replicate(&self) -> Result<Replication> {
+        let mut state = self.state.lock()?;
+        let keys = self.live_keys()?;
+
+        let (sender, changes) = mpsc::sync_channel(WATCH_BUFFER);
+        state.replicas.push(sender);
+
+        Ok(Replication {
+            seq: state.seq,
+            keys,
+            changes,
+        })
+    }
+
+    /// Clears the database and writes the keys, then flushes it to disk.
+    fn restore(&self, seq: u64, keys: Vec<Change>) -> Result<()> {
+        let mut state = self.state.lock()?;
+
+        self.db.clear()?;
+        self.tombstones.clear()?;
+        let mut batch = sled::Batch::default();
+        for change in keys {
+            if let Some(value) = change.value {
+                let record = Record {
+                    value,
+                    version: change.seq,
+                    timestamp: change.timestamp,
+                    expires_at: change.expires_at,
+                };
+                batch.insert(change.key, record.encode()?);
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.meta.insert(SEQ_KEY, serde_json::to_vec(&seq)?)?;
+        self.meta.insert(FLOOR_KEY, serde_json::to_vec(&seq)?)?;
+        self.db.flush()?;
+        state.seq = seq;
+        state.floor = seq;
+        state.tombstones = 0;
+
+        Ok(())
+    }
+
+    /// Writes the changes in one transaction with their own sequence numbers
+    /// and timestamps, skipping the changes at or before the last write
+    /// applied.
+    fn apply_changes(&self, changes: Vec<Change>) -> Result<()> {
+        let mut state = self.state.lock()?;
+        let seq = state.seq;
+
+        let changes = changes
+            .into_iter()
+            .filter(|change| change.seq > seq)
+            .collect();
+        self.apply(&mut state, changes)?;
+        Ok(())
+    }
+
+    fn backup(&self, _dest: &Path) -> Result<BackupManifest> {
+        Err(KvStoreError::StringError(
+            "The sled engine can't be backed up, export its keys instead".to_string(),
+        ))
+    }
+}
+
+/// Private helper function to open the database, waiting up to
+/// `LOCK_TIMEOUT` while its lock is still held by a handle being closed, such
+/// as when an engine is dropped and the directory reopened straight away.
+fn open_db(path: &Path) -> Result<Db> {
+    let start = Instant::now();
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(ref e))
+                if e.to_string().starts_with("could not acquire lock")
+                    && start.elapsed() < LOCK_TIMEOUT =>
+            {
+                thread::sleep(Duration::from_millis(10));
+            }
+            res => return Ok(res?),
+        }
+    }
+}
+
+/// Private helper function to read a sequence number from the meta tree, 0 if
+/// it was never written.
+fn read_seq(meta: &Tree, key: &[u8]) -> Result<u64> {
+    match meta.get(key)? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(0),
+    }
+}
+
+/// Private helper function to return a write made now with the sequence
+/// number.
+fn write(seq: u64, key: Vec<u8>, value: Option<Vec<u8>>, expires_at: Option<u64>) -> Change {
+    Change {
+        seq,
+        timestamp: now_millis(),
+        key,
+        value,
+        expires_at,
+    }
+}
+
+/// Private helper function to return the writes of a batch, numbered after
+/// the write `last`.
+fn batch_changes(last: u64, batch: WriteBatch) -> Vec<Change> {
+    batch
+        .into_ops()
+        .into_iter()
+        .zip(last + 1..)
+        .map(|(op, seq)| match op {
+            BatchOp::Set { key, value, ttl } => write(seq, key, Some(value), ttl.map(expires_at)),
+            BatchOp::Remove { key } => write(seq, key, None, None),
+        })
+        .collect()
+}
@@ -0,0 +1,155 @@
+use super::{into_string, KvsEngine, Version};
+use crate::{Result, WriteBatch};
+use std::collections::HashMap;
+
+/// The reads and buffered writes of an optimistic transaction, shared by the
+/// engine and client transaction types.
+#[derive(Default)]
+pub(crate) struct TransactionState {
+    /// The value and version of each key the first time it was read, the
+    /// value `None` if the key didn't exist.
+    reads: HashMap<Vec<u8>, (Option<Vec<u8>>, Version)>,
+
+    /// The latest value written to each key, `None` if it was removed.
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+
+    batch: WriteBatch,
+}
+
+impl TransactionState {
+    /// Returns the value of a key the transaction already wrote or read, so
+    /// reads are repeatable and see the transaction's own writes.
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(value) => Some(value.clone()),
+            None => self.reads.get(key).map(|(value, _)| value.clone()),
+        }
+    }
+
+    /// Records the version a key was read at and returns its value.
+    pub(crate) fn record_read(
+        &mut self,
+        key: Vec<u8>,
+        read: (Option<Vec<u8>>, Version),
+    ) -> Option<Vec<u8>> {
+        let value = read.0.clone();
+        self.reads.insert(key, read);
+        value
+    }
+
+    pub(crate) fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.set_bytes(key, value);
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        self.batch.remove_bytes(key);
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Splits the transaction into the version of every key read and the
+    /// writes to commit.
+    pub(crate) fn into_commit(self) -> (Vec<(Vec<u8>, Version)>, WriteBatch) {
+        let reads = self
+            .reads
+            .into_iter()
+            .map(|(key, (_, version))| (key, version))
+            .collect();
+
+        (reads, self.batch)
+    }
+}
+
+/// An optimistic transaction against a `KvsEngine`, started with
+/// `KvsEngine::begin`.
+///
+/// Reads go to the engine and record the version of each key read, while
+/// writes are buffered. On commit the writes are applied atomically, unless any
+/// key read was written by someone else in the meantime, in which case nothing
+/// is written and a `TransactionConflictError` is returned so the caller can
+/// retry from the start.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("from".to_owned(), "100".to_owned())?;
+///
+/// let mut txn = store.begin();
+/// let from: u64 = txn.get("from".to_owned())?.unwrap().parse().unwrap();
+/// txn.set("from".to_owned(), (from - 10).to_string());
+/// txn.set("to".to_owned(), "10".to_owned());
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    state: TransactionState,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Self {
+        Transaction {
+            engine,
+            state: TransactionState::default(),
+        }
+    }
+
+    /// Gets the value of a key as seen by the transaction.
+    ///
+    /// Returns `None` if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if the value isn't
+    /// UTF-8. Use `get_bytes` for binary values.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        into_string(self.get_bytes(key.as_bytes())?)
+    }
+
+    /// Gets the value of a binary key as seen by the transaction.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.state.lookup(key) {
+            return Ok(value);
+        }
+
+        let read = self.engine.get_versioned_bytes(key)?;
+        Ok(self.state.record_read(key.to_vec(), read))
+    }
+
+    /// Buffers a set of a key until commit.
+    pub fn set(&mut self, key: String, value: String) {
+        self.state.set(key.as_bytes(), value.as_bytes());
+    }
+
+    /// Buffers a set of a binary key until commit.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.state.set(key, value);
+    }
+
+    /// Buffers a remove of a key until commit.
+    pub fn remove(&mut self, key: String) {
+        self.state.remove(key.as_bytes());
+    }
+
+    /// Buffers a remove of a binary key until commit.
+    pub fn remove_bytes(&mut self, key: &[u8]) {
+        self.state.remove(key);
+    }
+
+    /// Commits the transaction's writes atomically, returning the sequence
+    /// number of the last write.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::TransactionConflictError` is returned if a key
+    /// read by the transaction was written since.
+    pub fn commit(self) -> Result<u64> {
+        let (reads, batch) = self.state.into_commit();
+        self.engine.commit(reads, batch)
+    }
+}
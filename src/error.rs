@@ -1,3 +1,5 @@
+#![allow(non_local_definitions)]
+
 // TODO: Remove failure crate and do own implementation like:
 // https://github.com/ccdle12/rust-vaults/blob/master/src/error.rs
 /// The custom error type for this project. Each error type will be added as an
@@ -23,6 +25,72 @@ pub enum KvStoreError {
     /// FromStringUtf8 Error when converting a Vec<u8> to String.
     #[fail(display = "{}", _0)]
     StringUtf8Error(#[cause] std::string::FromUtf8Error),
+
+    /// Error for a connection that failed to authenticate.
+    #[fail(display = "Authentication failed: {}", _0)]
+    AuthenticationError(String),
+
+    /// Error for a request the authenticated user isn't permitted to make.
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDeniedError(String),
+
+    /// Error for a transaction that read a key written by someone else before
+    /// it committed. Holds the key.
+    #[fail(display = "Transaction conflict on key {}", _0)]
+    TransactionConflictError(String),
+
+    /// Error for a watch starting from a sequence number whose writes have
+    /// been compacted out of the log. Holds the sequence number.
+    #[fail(
+        display = "Writes since sequence number {} have been compacted out of the log",
+        _0
+    )]
+    CompactedError(u64),
+
+    /// Error for a write sent to a replica. Holds the address of the leader,
+    /// where writes must be sent instead.
+    #[fail(display = "Not the leader, send writes to {}", _0)]
+    RedirectError(String),
+
+    /// Error for a write sent to a cluster node that doesn't know of a
+    /// leader, such as during an election.
+    #[fail(display = "No leader has been elected, try again later")]
+    NoLeaderError,
+
+    /// Error for a record of the log that doesn't match its checksum. Holds
+    /// the offset of the record in the log.
+    #[fail(
+        display = "The log record at offset {} is corrupt, run kvs-admin repair",
+        _0
+    )]
+    CorruptRecordError(u64),
+
+    /// CSV Errors from exporting or importing key/value pairs.
+    #[fail(display = "{}", _0)]
+    CsvError(#[cause] csv::Error),
+
+    /// Errors from the sled database of a `SledKvsEngine`.
+    #[fail(display = "{}", _0)]
+    SledError(#[cause] sled::Error),
+
+    /// TLS Errors from establishing or configuring a rustls session.
+    #[fail(display = "{}", _0)]
+    TlsError(#[cause] rustls::Error),
+
+    /// MessagePack Serialization Errors, from binary log records and
+    /// connections.
+    #[fail(display = "{}", _0)]
+    MessagePackEncodeError(#[cause] rmp_serde::encode::Error),
+
+    /// MessagePack Deserialization Errors, from binary log records and
+    /// connections.
+    #[fail(display = "{}", _0)]
+    MessagePackDecodeError(#[cause] rmp_serde::decode::Error),
+
+    /// Error for a stored value that its codec can't decode into the type
+    /// asked for, such as one written with another codec or as another type.
+    #[fail(display = "Failed to decode value: {}", _0)]
+    DecodeError(String),
 }
 
 impl From<std::io::Error> for KvStoreError {
@@ -43,5 +111,60 @@ impl From<std::string::FromUtf8Error> for KvStoreError {
     }
 }
 
+impl From<csv::Error> for KvStoreError {
+    fn from(err: csv::Error) -> KvStoreError {
+        KvStoreError::CsvError(err)
+    }
+}
+
+impl From<sled::Error> for KvStoreError {
+    fn from(err: sled::Error) -> KvStoreError {
+        KvStoreError::SledError(err)
+    }
+}
+
+impl From<rustls::Error> for KvStoreError {
+    fn from(err: rustls::Error) -> KvStoreError {
+        KvStoreError::TlsError(err)
+    }
+}
+
+/// Failures to write to the stream underneath are kept as `IOError`s, so a
+/// dropped connection reads the same in either wire format.
+impl From<rmp_serde::encode::Error> for KvStoreError {
+    fn from(err: rmp_serde::encode::Error) -> KvStoreError {
+        use rmp::encode::ValueWriteError;
+        use rmp_serde::encode::Error;
+
+        match err {
+            Error::InvalidValueWrite(ValueWriteError::InvalidMarkerWrite(e))
+            | Error::InvalidValueWrite(ValueWriteError::InvalidDataWrite(e)) => {
+                KvStoreError::IOError(e)
+            }
+            err => KvStoreError::MessagePackEncodeError(err),
+        }
+    }
+}
+
+/// Failures to read from the stream underneath, including its end, are kept
+/// as `IOError`s, so a dropped connection reads the same in either wire
+/// format.
+impl From<rmp_serde::decode::Error> for KvStoreError {
+    fn from(err: rmp_serde::decode::Error) -> KvStoreError {
+        use rmp_serde::decode::Error;
+
+        match err {
+            Error::InvalidMarkerRead(e) | Error::InvalidDataRead(e) => KvStoreError::IOError(e),
+            err => KvStoreError::MessagePackDecodeError(err),
+        }
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for KvStoreError {
+    fn from(err: std::sync::PoisonError<T>) -> KvStoreError {
+        KvStoreError::StringError(err.to_string())
+    }
+}
+
 /// Alias for Result in this project.
 pub type Result<T> = std::result::Result<T, KvStoreError>;
@@ -0,0 +1,226 @@
+//! Export and import of key/value pairs as JSON Lines or CSV.
+//!
+//! Both engines and clients export through `write_pages` and import through
+//! `import`, so a file exported from one can be imported into any other.
+//!
+//! Each pair keeps when its key expires, in milliseconds since the unix
+//! epoch, so an import made later expires the key at the same time. A key
+//! that expired before the import is skipped.
+
+use crate::common::now_millis;
+use crate::{Change, KvStoreError, Result, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The number of pairs imported per batch, so an import is written in a few
+/// large batches without holding the whole file in memory.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// The number of pairs scanned per page of an export, so an export is written
+/// as it's scanned without holding every key in memory.
+const EXPORT_PAGE_SIZE: usize = 1000;
+
+/// The format of an export.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// A JSON object `{"key": ..., "value": ...}` per line, with
+    /// `"expires_at"` for a key with a TTL.
+    Jsonl,
+
+    /// A `key,value,expires_at` header, then a row per pair, with an empty
+    /// `expires_at` for a key without a TTL.
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(KvStoreError::StringError(format!(
+                "Unknown export format {}, expected jsonl or csv",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportFormat::Jsonl => f.write_str("jsonl"),
+            ExportFormat::Csv => f.write_str("csv"),
+        }
+    }
+}
+
+/// A key/value pair as exported. In JSON Lines binary keys and values are
+/// written as base64 objects; CSV holds them as they are. Exports made before
+/// expiry was kept have no `expires_at`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Pair {
+    #[serde(with = "crate::bytes")]
+    key: Vec<u8>,
+    #[serde(with = "crate::bytes")]
+    value: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// Writes every key starting with `prefix` to `writer` in the format, a page
+/// at a time as `scan_page` returns them, returning the number written.
+///
+/// `scan_page` is given the key to start from and the most keys to return,
+/// and returns the live keys starting with `prefix` from there in key order.
+pub(crate) fn write_pages<W, F>(
+    prefix: &[u8],
+    format: ExportFormat,
+    writer: W,
+    mut scan_page: F,
+) -> Result<u64>
+where
+    W: Write,
+    F: FnMut(&[u8], usize) -> Result<Vec<Change>>,
+{
+    let mut writer = match format {
+        ExportFormat::Jsonl => PairWriter::Jsonl(BufWriter::new(writer)),
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(["key", "value", "expires_at"])?;
+            PairWriter::Csv(Box::new(writer))
+        }
+    };
+
+    let mut count = 0;
+    let mut start = prefix.to_vec();
+    loop {
+        let page = scan_page(&start, EXPORT_PAGE_SIZE)?;
+        let full = page.len() == EXPORT_PAGE_SIZE;
+
+        for change in page {
+            // The next page starts right after the last key, at the key with
+            // a zero byte appended.
+            start.clone_from(&change.key);
+            start.push(0);
+
+            if let Some(value) = change.value {
+                writer.write(Pair {
+                    key: change.key,
+                    value,
+                    expires_at: change.expires_at,
+                })?;
+                count += 1;
+            }
+        }
+
+        if !full {
+            break;
+        }
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Private helper type to write pairs in either format.
+enum PairWriter<W: Write> {
+    Jsonl(BufWriter<W>),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> PairWriter<W> {
+    fn write(&mut self, pair: Pair) -> Result<()> {
+        match self {
+            PairWriter::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, &pair)?;
+                writer.write_all(b"\n")?;
+            }
+            PairWriter::Csv(writer) => {
+                let expires_at = pair.expires_at.map(|t| t.to_string()).unwrap_or_default();
+                writer.write_record(&[pair.key, pair.value, expires_at.into_bytes()])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            PairWriter::Jsonl(writer) => writer.flush()?,
+            PairWriter::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Reads pairs from `reader` in the format and writes those whose key starts
+/// with `prefix` in batches through `write`, each with a TTL up to when it
+/// expires. Returns the number imported, not counting pairs already expired.
+///
+/// Pairs are imported as they're read, so on error the pairs before the
+/// failing batch are already written.
+pub(crate) fn import<R, F>(
+    reader: R,
+    format: ExportFormat,
+    prefix: &str,
+    mut write: F,
+) -> Result<u64>
+where
+    R: Read,
+    F: FnMut(WriteBatch) -> Result<u64>,
+{
+    let pairs: Box<dyn Iterator<Item = Result<Pair>>> = match format {
+        ExportFormat::Jsonl => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        ExportFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(reader);
+            let headers = reader.byte_headers()?.clone();
+            Box::new(
+                reader
+                    .into_byte_records()
+                    .map(move |record| Ok(record?.deserialize(Some(&headers))?)),
+            )
+        }
+    };
+
+    let mut count = 0;
+    let mut batch = WriteBatch::new();
+    for pair in pairs {
+        let Pair {
+            key,
+            value,
+            expires_at,
+        } = pair?;
+        if !key.starts_with(prefix.as_bytes()) {
+            continue;
+        }
+
+        let now = now_millis();
+        match expires_at {
+            Some(t) if t <= now => continue,
+            Some(t) => batch.set_bytes_with_ttl(&key, &value, Duration::from_millis(t - now)),
+            None => batch.set_bytes(&key, &value),
+        };
+        if batch.len() == IMPORT_BATCH_SIZE {
+            count += batch.len() as u64;
+            write(batch)?;
+            batch = WriteBatch::new();
+        }
+    }
+
+    if !batch.is_empty() {
+        count += batch.len() as u64;
+        write(batch)?;
+    }
+
+    Ok(count)
+}
@@ -1,17 +1,41 @@
 //! A library for a TCP client and server to run a write-ahead-log kv store.
 
 extern crate failure;
-// #[macro_use]
-// extern crate failure_derive;
+#[macro_use]
+extern crate failure_derive;
 extern crate serde;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine};
+pub use auth::{Acl, Credential};
+pub use client::{ClientTransaction, KvsClient, KvsClientBuilder, Pipeline, Reply, Watch};
+pub use codec::{BincodeCodec, Codec, JsonCodec, MessagePackCodec};
+pub use engines::{
+    read_engine_marker, write_engine_marker, BackupFile, BackupManifest, CasOutcome, Change,
+    KvStore, KvsEngine, KvsSnapshot, LogReader, LogRecord, LogStats, RecordFormat, RecordStatus,
+    RecoveryTarget, Repair, Replication, SledKvsEngine, SledSnapshot, Snapshot, Transaction,
+    Version, WriteBatch,
+};
 pub use error::{KvStoreError, Result};
+pub use export::ExportFormat;
+pub use pool::KvsPool;
+pub use proxy::KvsProxy;
+pub use raft::{ClusterStatus, Role};
+pub use replication::{ReplicaStatus, ServerStatus};
 pub use server::KvsServer;
+pub use sharding::ShardedKvsClient;
 
+mod auth;
+mod bytes;
 mod client;
+mod codec;
 mod common;
 mod engines;
 mod error;
+mod export;
+mod pool;
+mod proxy;
+mod raft;
+mod replication;
 mod server;
+mod sharding;
+pub mod tls;
+mod wire;
@@ -0,0 +1,301 @@
+//! Pools of connections to a server: `KvsPool` of client connections, built
+//! with `KvsClientBuilder`, and the proxy's pools of upstream connections,
+//! both kept by a `Pool`.
+//!
+//! Requests are sent over an idle connection, or a new one while fewer than
+//! the pool size are open. A connection that fails is closed rather than
+//! returned to the pool, so the next request reconnects, which lets clients
+//! ride out a server restart.
+
+use crate::{KvStoreError, KvsClient, KvsClientBuilder, Result, ServerStatus, WriteBatch};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Opens the connections of a `Pool`, and checks idle ones are still open.
+pub(crate) trait Connector {
+    type Connection;
+
+    fn connect(&self) -> Result<Self::Connection>;
+
+    /// Returns whether an idle connection due a health check still works.
+    fn check(&self, conn: &mut Self::Connection) -> bool;
+}
+
+/// A pool of up to `size` connections, shared by many threads. Connections
+/// are opened as needed and kept open while idle; once `size` are busy,
+/// callers wait for one to be returned.
+pub(crate) struct Pool<C: Connector> {
+    connector: C,
+    size: usize,
+
+    /// How long a connection may sit idle before it's checked when taken, or
+    /// `None` to never check.
+    health_check_interval: Option<Duration>,
+
+    state: Mutex<PoolState<C::Connection>>,
+    returned: Condvar,
+}
+
+struct PoolState<T> {
+    /// The idle connections, and when each was returned.
+    idle: Vec<(T, Instant)>,
+
+    /// The number of connections open, idle or busy.
+    open: usize,
+}
+
+impl<C: Connector> Pool<C> {
+    pub(crate) fn new(connector: C, size: usize, health_check_interval: Option<Duration>) -> Self {
+        Pool {
+            connector,
+            size,
+            health_check_interval,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn connector(&self) -> &C {
+        &self.connector
+    }
+
+    /// Takes an idle connection, opens a new one if fewer than the pool size
+    /// are open, or else waits for one to be returned. An idle connection due
+    /// a health check that fails it is closed and another taken.
+    pub(crate) fn take(&self) -> Result<C::Connection> {
+        let mut state = self.state.lock()?;
+        loop {
+            if let Some((mut conn, since)) = state.idle.pop() {
+                match self.health_check_interval {
+                    Some(interval) if since.elapsed() >= interval => {}
+                    _ => return Ok(conn),
+                }
+
+                // Checks without holding the lock.
+                drop(state);
+                if self.connector.check(&mut conn) {
+                    return Ok(conn);
+                }
+                state = self.state.lock()?;
+                state.open -= 1;
+                continue;
+            }
+            if state.open < self.size {
+                break;
+            }
+            state = self.returned.wait(state)?;
+        }
+
+        // Connects without holding the lock, counting the connection as open
+        // until it fails.
+        state.open += 1;
+        drop(state);
+
+        self.connector.connect().or_else(|e| {
+            self.state.lock()?.open -= 1;
+            self.returned.notify_one();
+            Err(e)
+        })
+    }
+
+    /// Returns a connection to the pool, or closes it if it's no longer fit
+    /// for another request.
+    pub(crate) fn put(&self, conn: C::Connection, keep: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            if keep {
+                state.idle.push((conn, Instant::now()));
+            } else {
+                state.open -= 1;
+            }
+        }
+        self.returned.notify_one();
+    }
+}
+
+impl Connector for KvsClientBuilder {
+    type Connection = KvsClient;
+
+    fn connect(&self) -> Result<KvsClient> {
+        KvsClientBuilder::connect(self)
+    }
+
+    fn check(&self, client: &mut KvsClient) -> bool {
+        client.status().is_ok()
+    }
+}
+
+/// A pool of connections to a server, shared by many threads.
+///
+/// Idempotent requests, `get`, `ttl`, `scan` and `status`, are retried with
+/// backoff over a new connection if the connection fails or times out. Other
+/// writes are only retried if no connection could be opened, as a write
+/// whose connection failed may have been made.
+pub struct KvsPool {
+    pool: Pool<KvsClientBuilder>,
+}
+
+impl KvsPool {
+    pub(crate) fn new(builder: KvsClientBuilder) -> Result<Self> {
+        if builder.pool_size == 0 {
+            return Err(KvStoreError::StringError(
+                "A pool needs at least one connection".to_string(),
+            ));
+        }
+
+        let size = builder.pool_size;
+        let interval = builder.health_check_interval;
+        Ok(KvsPool {
+            pool: Pool::new(builder, size, Some(interval)),
+        })
+    }
+
+    /// Gets a value according to a key from the server, retrying on
+    /// connection failures.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.retry(true, |client| client.get(key.clone()))
+    }
+
+    /// Gets the time left until a key expires from the server, retrying on
+    /// connection failures.
+    pub fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.retry(true, |client| client.ttl(key.clone()))
+    }
+
+    /// Gets every key starting with `prefix` at the server, and its value,
+    /// retrying on connection failures.
+    pub fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.retry(true, |client| client.scan(prefix.clone()))
+    }
+
+    /// Gets a value according to a binary key from the server, retrying on
+    /// connection failures.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.retry(true, |client| client.get_bytes(key))
+    }
+
+    /// Gets the time left until a binary key expires from the server,
+    /// retrying on connection failures.
+    pub fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.retry(true, |client| client.ttl_bytes(key))
+    }
+
+    /// Gets every key starting with the binary `prefix` at the server, and
+    /// its value, retrying on connection failures.
+    pub fn scan_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.retry(true, |client| client.scan_bytes(prefix))
+    }
+
+    /// Gets the status of the server, retrying on connection failures.
+    pub fn status(&self) -> Result<ServerStatus> {
+        self.retry(true, KvsClient::status)
+    }
+
+    /// Sets a key value pair at the server, returning the sequence number of
+    /// the write.
+    pub fn set(&self, key: String, value: String) -> Result<u64> {
+        self.retry(false, |client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Sets a key value pair at the server that expires after the `ttl`,
+    /// returning the sequence number of the write.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<u64> {
+        self.retry(false, |client| {
+            client.set_with_ttl(key.clone(), value.clone(), ttl)
+        })
+    }
+
+    /// Sets a binary key value pair at the server, returning the sequence
+    /// number of the write.
+    pub fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<u64> {
+        self.retry(false, |client| client.set_bytes(key, value))
+    }
+
+    /// Sets a binary key value pair at the server that expires after the
+    /// `ttl`, returning the sequence number of the write.
+    pub fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<u64> {
+        self.retry(false, |client| client.set_bytes_with_ttl(key, value, ttl))
+    }
+
+    /// Removes a kv pair, returning the sequence number of the write.
+    pub fn remove(&self, key: String) -> Result<u64> {
+        self.retry(false, |client| client.remove(key.clone()))
+    }
+
+    /// Removes a binary key, returning the sequence number of the write.
+    pub fn remove_bytes(&self, key: &[u8]) -> Result<u64> {
+        self.retry(false, |client| client.remove_bytes(key))
+    }
+
+    /// Applies every write in the batch atomically at the server, returning
+    /// the sequence number of the last write.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<u64> {
+        self.retry(false, |client| client.write_batch(batch.clone()))
+    }
+
+    /// Runs `f` with a pooled connection, for requests the pool has no method
+    /// for, such as transactions and pipelines. The connection is closed
+    /// rather than returned if `f` fails with a connection error. `f` isn't
+    /// retried.
+    pub fn with<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvsClient) -> Result<T>,
+    {
+        let mut f = Some(f);
+        self.retry(false, |client| match f.take() {
+            Some(f) => f(client),
+            None => unreachable!("a request that was sent is never retried"),
+        })
+    }
+
+    /// Private helper function to run a request over a pooled connection,
+    /// retrying with backoff if no connection could be opened or, for an
+    /// idempotent request, if its connection failed.
+    fn retry<T, F>(&self, idempotent: bool, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        let builder = self.pool.connector();
+        let mut backoff = builder.backoff;
+        let mut attempt = 0;
+        loop {
+            let (res, sent) = match self.pool.take() {
+                Ok(mut client) => {
+                    let res = f(&mut client);
+                    // A connection that failed may still have a response on
+                    // the way.
+                    let failed = res.as_ref().err().is_some_and(is_connection_error);
+                    self.pool.put(client, !failed);
+                    (res, true)
+                }
+                Err(e) => (Err(e), false),
+            };
+
+            match res {
+                Err(e)
+                    if is_connection_error(&e)
+                        && (idempotent || !sent)
+                        && attempt < builder.retries =>
+                {
+                    attempt += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Private helper function to tell whether an error is a failure of the
+/// connection, including a timeout, rather than an error from the server.
+fn is_connection_error(e: &KvStoreError) -> bool {
+    match e {
+        KvStoreError::IOError(_) | KvStoreError::TlsError(_) => true,
+        KvStoreError::SerdeError(e) => e.is_io() || e.is_eof(),
+        _ => false,
+    }
+}
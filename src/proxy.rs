@@ -0,0 +1,571 @@
+//! A proxy speaking the server protocol in front of one or more servers.
+//!
+//! Clients connect to the proxy as they would to a server. Each request is
+//! forwarded over one of a small pool of connections to the server, and the
+//! connection returned to the pool once the response is read, so many client
+//! connections share a few upstream ones. With several servers, keys are
+//! sharded across them by the same hash ring as `ShardedKvsClient`.
+//!
+//! Clients may switch their connection to MessagePack as they would with a
+//! server, and the proxy speaks MessagePack to the servers, so binary values
+//! pass through as raw bytes.
+//!
+//! The proxy may serve TLS to clients, and connect to the servers over TLS.
+//! Pooled connections are shared by every client, so they're authenticated
+//! as the proxy's own user, if it has one. A client that authenticates gets
+//! connections of its own instead, authenticated as its user, so each server
+//! checks the client's requests against its ACL as if it were connected
+//! directly.
+
+use crate::bytes::AnyValue;
+use crate::common::{
+    AuthResponse, ErrorResponse, FormatResponse, Request, ScanPageResponse, ScanResponse,
+};
+use crate::pool::{Connector, Pool};
+use crate::server::WATCH_POLL_INTERVAL;
+use crate::sharding::HashRing;
+use crate::tls::{self, Stream};
+use crate::wire::{WireFormat, WireReader, WireWriter};
+use crate::{Credential, KvStoreError, Result};
+use log::error;
+use rustls::{ClientConfig, ServerConfig};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+/// Proxy for one or more Key/Value store servers.
+#[derive(Clone)]
+pub struct KvsProxy {
+    /// The connection pool of each server, by address.
+    pools: Arc<HashMap<String, Pool<Backend>>>,
+
+    /// The servers in the order given, the first of which serves requests
+    /// without a key when there's only one.
+    backends: Arc<Vec<String>>,
+
+    /// The ring sharding keys across the servers, if there are several.
+    ring: Option<Arc<HashRing>>,
+
+    /// The most connections to keep open to each server.
+    pool_size: usize,
+
+    /// How connections to the servers are made.
+    options: Arc<BackendOptions>,
+
+    /// The TLS config clients connect with, if TLS is enabled.
+    tls: Option<Arc<ServerConfig>>,
+}
+
+/// How the proxy connects to the servers.
+#[derive(Clone, Default)]
+struct BackendOptions {
+    /// The config to connect over TLS with, verifying each server's
+    /// certificate against its host, if TLS is enabled.
+    tls: Option<Arc<ClientConfig>>,
+
+    /// The user pooled connections authenticate as, if any.
+    credential: Option<(String, Credential)>,
+}
+
+impl KvsProxy {
+    /// Creates a proxy for the servers at the addresses, keeping up to
+    /// `pool_size` connections open to each. Keys are sharded across the
+    /// servers if there are several.
+    pub fn new(backends: Vec<String>, pool_size: usize) -> Result<Self> {
+        if backends.is_empty() || pool_size == 0 {
+            return Err(KvStoreError::StringError(
+                "A proxy needs at least one server and a pool of at least one connection"
+                    .to_string(),
+            ));
+        }
+
+        let options = Arc::new(BackendOptions::default());
+        let pools = Arc::new(pools(&backends, pool_size, &options));
+
+        let ring = match backends.len() {
+            1 => None,
+            _ => {
+                let mut ring = HashRing::default();
+                for addr in &backends {
+                    ring.insert(addr.clone());
+                }
+                Some(Arc::new(ring))
+            }
+        };
+
+        Ok(KvsProxy {
+            pools,
+            backends: Arc::new(backends),
+            ring,
+            pool_size,
+            options,
+            tls: None,
+        })
+    }
+
+    /// Enables TLS on every client connection accepted by the proxy.
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Connects to the servers over TLS, verifying each server's certificate
+    /// against the host in its address.
+    pub fn with_backend_tls(mut self, config: Arc<ClientConfig>) -> Self {
+        Arc::make_mut(&mut self.options).tls = Some(config);
+        self.pools = Arc::new(pools(&self.backends, self.pool_size, &self.options));
+        self
+    }
+
+    /// Authenticates the pooled connections to the servers as the user, whose
+    /// access every client that doesn't authenticate itself gets.
+    pub fn with_backend_credential(mut self, user: String, credential: Credential) -> Self {
+        Arc::make_mut(&mut self.options).credential = Some((user, credential));
+        self.pools = Arc::new(pools(&self.backends, self.pool_size, &self.options));
+        self
+    }
+
+    /// Listens on the address and serves each client connection on its own
+    /// thread.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves each client connection accepted by an already bound listener,
+    /// like `run`.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let proxy = self.clone();
+
+            thread::spawn(move || {
+                if let Err(e) = proxy.handle_stream(stream) {
+                    error!("Error on connection: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_stream(&self, stream: TcpStream) -> Result<()> {
+        let stream = match &self.tls {
+            Some(config) => Stream::server(stream, config.clone())?,
+            None => Stream::Plain(stream),
+        };
+
+        let mut r = WireReader::new(stream.try_clone()?);
+        let mut w = WireWriter::new(stream);
+        let mut upstreams = Upstreams {
+            proxy: self,
+            credential: None,
+            own: HashMap::new(),
+        };
+
+        // Macro for sending reponses back over the tcp stream.
+        macro_rules! send_response {
+            ($response:expr) => {{
+                let resp = $response;
+                w.write(&resp)?;
+                w.flush()?;
+            }};
+        }
+
+        while let Some(req) = r.next::<Request>() {
+            let req = req?;
+
+            match req {
+                Request::Auth { user, credential } => {
+                    match upstreams.authenticate(user, credential) {
+                        Ok(response) => send_response!(response),
+                        Err(e) => send_response!(error_response(e)),
+                    }
+                }
+                Request::Format { format } => {
+                    send_response!(FormatResponse::Ok(format));
+                    r.set_format(format);
+                    w.set_format(format);
+                }
+                Request::Scan { prefix } => send_response!(upstreams.scan(prefix)),
+                Request::ScanPage { .. } => send_response!(upstreams.scan_page(&req)),
+                Request::Status | Request::Backup { .. } => match self.single() {
+                    Some(addr) => send_response!(upstreams.forward(addr, &req)),
+                    None => send_response!(ErrorResponse::Err(
+                        "Status and backups aren't supported across shards".to_string()
+                    )),
+                },
+                Request::Watch { .. } | Request::Replicate => {
+                    let addr = match self.single() {
+                        Some(addr) => addr,
+                        None => {
+                            send_response!(ErrorResponse::Err(
+                                "Streams aren't supported across shards".to_string()
+                            ));
+                            continue;
+                        }
+                    };
+
+                    // The connection only streams from the server from now
+                    // on, over a connection of its own in the client's format.
+                    w.flush()?;
+                    let client = w.get_ref().try_clone()?;
+                    let credential = upstreams.credential();
+                    let upstream = connect(addr, &self.options, credential, r.format())?;
+                    return stream_from(upstream, &req, client);
+                }
+                Request::AppendEntries(_)
+                | Request::RequestVote(_)
+                | Request::InstallSnapshot(_) => {
+                    return Err(KvStoreError::StringError(
+                        "Raft requests aren't forwarded by the proxy".to_string(),
+                    ))
+                }
+                _ => match self.route(&req) {
+                    Ok(addr) => send_response!(upstreams.forward(addr, &req)),
+                    Err(e) => send_response!(error_response(e)),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Private helper function to return the server, if there's only one.
+    fn single(&self) -> Option<&str> {
+        match self.ring {
+            Some(_) => None,
+            None => Some(&self.backends[0]),
+        }
+    }
+
+    /// Private helper function to return the server a request with keys
+    /// belongs to.
+    fn route(&self, req: &Request) -> Result<&str> {
+        let ring = match &self.ring {
+            Some(ring) => ring,
+            None => return Ok(&self.backends[0]),
+        };
+
+        let keys: Vec<&[u8]> = match req {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::Ttl { key } => vec![key],
+            Request::CompareAndSwap { key, .. } | Request::GetVersioned { key } => vec![key],
+            Request::Batch { batch } => batch.keys().collect(),
+            Request::Commit { reads, batch } => reads
+                .iter()
+                .map(|(key, _)| key.as_slice())
+                .chain(batch.keys())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        // Writes to several keys are only atomic on a single server.
+        let mut addrs = keys.into_iter().map(|key| ring.node_for(key));
+        let addr = addrs.next().unwrap_or(&self.backends[0]);
+        if addrs.any(|other| other != addr) {
+            return Err(KvStoreError::StringError(
+                "The keys of a batch or transaction must be on the same shard".to_string(),
+            ));
+        }
+
+        Ok(addr)
+    }
+}
+
+/// The connections to the servers that one client's requests are forwarded
+/// over: the pools shared by every client until it authenticates, and then
+/// connections of its own, authenticated as its user, since a pooled
+/// connection can't be shared between users.
+struct Upstreams<'a> {
+    proxy: &'a KvsProxy,
+    credential: Option<(String, Credential)>,
+
+    /// The client's own connections, by address.
+    own: HashMap<String, Upstream>,
+}
+
+impl Upstreams<'_> {
+    /// Authenticates the client as the user at the first server, replacing
+    /// the connections of any earlier user, and returns the server's answer.
+    /// Until it succeeds, requests go over the pools again.
+    fn authenticate(&mut self, user: String, credential: Credential) -> Result<AuthResponse> {
+        self.credential = None;
+        self.own.clear();
+
+        let addr = &self.proxy.backends[0];
+        let mut upstream = connect(addr, &self.proxy.options, None, WireFormat::MessagePack)?;
+        let response = upstream.call(&Request::Auth {
+            user: user.clone(),
+            credential: credential.clone(),
+        })?;
+        if let AuthResponse::Ok(_) = response {
+            self.credential = Some((user, credential));
+            self.own.insert(addr.clone(), upstream);
+        }
+
+        Ok(response)
+    }
+
+    /// Returns the user the client's own connections authenticate as: the
+    /// client's user once it has authenticated, or else the proxy's, if any.
+    fn credential(&self) -> Option<&(String, Credential)> {
+        self.credential
+            .as_ref()
+            .or_else(|| self.proxy.options.credential.as_ref())
+    }
+
+    /// Private helper function to send a request to a server and return the
+    /// response, over the client's own connection once it has authenticated.
+    /// A connection that fails is closed, and the next request reconnects.
+    fn call<T: DeserializeOwned>(&mut self, addr: &str, req: &Request) -> Result<T> {
+        let credential = match &self.credential {
+            Some(credential) => credential,
+            None => return self.proxy.pools[addr].call(req),
+        };
+
+        let mut upstream = match self.own.remove(addr) {
+            Some(upstream) => upstream,
+            None => connect(
+                addr,
+                &self.proxy.options,
+                Some(credential),
+                WireFormat::MessagePack,
+            )?,
+        };
+        let res = upstream.call(req);
+        if res.is_ok() {
+            self.own.insert(addr.to_string(), upstream);
+        }
+
+        res
+    }
+
+    /// Private helper function to forward a request to a server, returning
+    /// its response or the error forwarding it.
+    fn forward(&mut self, addr: &str, req: &Request) -> AnyValue {
+        self.call(addr, req).unwrap_or_else(error_response)
+    }
+
+    /// Private helper function to scan every server, merging their keys in
+    /// key order.
+    fn scan(&mut self, prefix: Vec<u8>) -> ScanResponse {
+        let req = Request::Scan { prefix };
+        let mut keys = Vec::new();
+
+        for addr in self.proxy.backends.iter() {
+            match self.call(addr, &req) {
+                Ok(ScanResponse::Ok(r)) => keys.extend(r),
+                Ok(response) => return response,
+                Err(e) => return ScanResponse::Err(e.to_string()),
+            }
+        }
+
+        keys.sort();
+        ScanResponse::Ok(keys)
+    }
+
+    /// Private helper function to scan a page of every server, keeping the
+    /// first page of their keys merged in key order. Each server's page holds
+    /// its first keys, so the merged page is the first of every key.
+    fn scan_page(&mut self, req: &Request) -> ScanPageResponse {
+        let limit = match req {
+            Request::ScanPage { limit, .. } => *limit,
+            _ => unreachable!("only ScanPage requests are paged"),
+        };
+        let mut keys = Vec::new();
+
+        for addr in self.proxy.backends.iter() {
+            match self.call(addr, req) {
+                Ok(ScanPageResponse::Ok(r)) => keys.extend(r),
+                Ok(response) => return response,
+                Err(e) => return ScanPageResponse::Err(e.to_string()),
+            }
+        }
+
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys.truncate(limit);
+        ScanPageResponse::Ok(keys)
+    }
+}
+
+/// Private helper function to return the response of any type sent for an
+/// error.
+fn error_response(e: KvStoreError) -> AnyValue {
+    AnyValue::Map(vec![(
+        AnyValue::Str("Err".to_string()),
+        AnyValue::Str(e.to_string()),
+    )])
+}
+
+/// A connection to a server, sending requests and reading responses of any
+/// type.
+struct Upstream {
+    reader: WireReader<Stream>,
+    writer: WireWriter<Stream>,
+
+    /// The TCP connection under the stream, to set timeouts on.
+    tcp: TcpStream,
+}
+
+impl Upstream {
+    /// Asks the server to switch the connection to the format.
+    fn switch_format(&mut self, format: WireFormat) -> Result<()> {
+        match self.call(&Request::Format { format })? {
+            FormatResponse::Ok(format) => {
+                self.reader.set_format(format);
+                self.writer.set_format(format);
+                Ok(())
+            }
+            FormatResponse::Err(e) => Err(KvStoreError::StringError(e)),
+        }
+    }
+
+    /// Sends the request and reads the response.
+    fn call<T: DeserializeOwned>(&mut self, req: &Request) -> Result<T> {
+        self.writer.write(req)?;
+        self.writer.flush()?;
+
+        self.reader.read()
+    }
+}
+
+/// Opens the pooled connections to a server, in MessagePack and
+/// authenticated as the proxy's user, if it has one.
+struct Backend {
+    addr: String,
+    options: Arc<BackendOptions>,
+}
+
+impl Connector for Backend {
+    type Connection = Upstream;
+
+    fn connect(&self) -> Result<Upstream> {
+        let credential = self.options.credential.as_ref();
+        connect(
+            &self.addr,
+            &self.options,
+            credential,
+            WireFormat::MessagePack,
+        )
+    }
+
+    // Idle connections are never checked, since a failed request is closed
+    // and the error returned to the client.
+    fn check(&self, _: &mut Upstream) -> bool {
+        true
+    }
+}
+
+impl Pool<Backend> {
+    /// Sends the request over a pooled connection and returns the response.
+    /// A connection that fails is closed rather than returned to the pool.
+    fn call<T: DeserializeOwned>(&self, req: &Request) -> Result<T> {
+        let mut upstream = self.take()?;
+        let res = upstream.call(req);
+        self.put(upstream, res.is_ok());
+
+        res
+    }
+}
+
+/// Private helper function to create a pool of connections to each server.
+fn pools(
+    backends: &[String],
+    pool_size: usize,
+    options: &Arc<BackendOptions>,
+) -> HashMap<String, Pool<Backend>> {
+    backends
+        .iter()
+        .map(|addr| {
+            let backend = Backend {
+                addr: addr.clone(),
+                options: options.clone(),
+            };
+            (addr.clone(), Pool::new(backend, pool_size, None))
+        })
+        .collect()
+}
+
+/// Private helper function to connect to a server, over TLS if enabled,
+/// authenticating as the user if given, then switching to the format.
+fn connect(
+    addr: &str,
+    options: &BackendOptions,
+    credential: Option<&(String, Credential)>,
+    format: WireFormat,
+) -> Result<Upstream> {
+    let tcp = TcpStream::connect(addr)?;
+    tcp.set_nodelay(true)?;
+    let stream = match &options.tls {
+        Some(config) => {
+            let name = tls::server_name(host(addr))?;
+            Stream::client(tcp.try_clone()?, name, config.clone())?
+        }
+        None => Stream::Plain(tcp.try_clone()?),
+    };
+
+    let mut upstream = Upstream {
+        reader: WireReader::new(stream.try_clone()?),
+        writer: WireWriter::new(stream),
+        tcp,
+    };
+    if let Some((user, credential)) = credential {
+        let request = Request::Auth {
+            user: user.clone(),
+            credential: credential.clone(),
+        };
+        if let AuthResponse::Err(e) = upstream.call(&request)? {
+            return Err(KvStoreError::AuthenticationError(e));
+        }
+    }
+    if format != WireFormat::Json {
+        upstream.switch_format(format)?;
+    }
+
+    Ok(upstream)
+}
+
+/// Private helper function to return the host of an address, without its
+/// port or the brackets around an IPv6 address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplitn(2, ':').last().unwrap_or(addr);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Private helper function to send a streaming request to the server over a
+/// connection of its own, already in the client's format, and copy the stream
+/// to the client until either hangs up.
+fn stream_from(mut upstream: Upstream, req: &Request, mut client: Stream) -> Result<()> {
+    upstream.writer.write(req)?;
+    upstream.writer.flush()?;
+
+    // A quiet stream is checked for a client hang-up, which the server can
+    // only see once the proxy hangs up too.
+    upstream.tcp.set_read_timeout(Some(WATCH_POLL_INTERVAL))?;
+    let mut stream = upstream.writer.get_ref().try_clone()?;
+    drop(upstream);
+    let mut buf = [0; 8192];
+    loop {
+        let len = match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                if client.has_input().unwrap_or(true) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if client.write_all(&buf[..len]).is_err() || client.flush().is_err() {
+            return Ok(());
+        }
+    }
+}
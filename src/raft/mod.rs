@@ -0,0 +1,839 @@
+//! Raft consensus between the servers of a cluster.
+//!
+//! Every write to a cluster goes to the leader, which appends it to its Raft
+//! log and replicates the log to the other nodes. A write commits once a
+//! majority of nodes have it in their logs, and every node applies committed
+//! writes to its engine, the state machine, in log order. If the leader fails,
+//! the other nodes elect a new one once they stop hearing from it.
+//!
+//! A node's engine holds only what its log applies. Once `SNAPSHOT_INTERVAL`
+//! entries have been applied since the last snapshot, the node snapshots its
+//! engine and drops those entries from its log. On start the engine is
+//! restored from the snapshot and the entries after it applied again as the
+//! node learns which have committed. A node too far behind for the leader's
+//! log is sent the leader's snapshot instead.
+//!
+//! With an ACL, nodes authenticate to each other as a user with write access
+//! to every key, and Raft requests from any other connection are dropped.
+
+mod peer;
+mod storage;
+
+use self::peer::Peer;
+use self::storage::Storage;
+use crate::common::{Applied, Request, WriteCommand};
+use crate::engines::{Change, KvsEngine};
+use crate::{Credential, KvStoreError, Result};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the leader sends each node its new entries, or an empty heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a node waits without hearing from a leader before starting an
+/// election. Each wait is randomly up to twice as long, so nodes rarely start
+/// elections at the same time.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How often nodes check whether their election timeout has passed.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a write waits to commit before giving up.
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most entries sent to a node in one request.
+const MAX_ENTRIES: usize = 64;
+
+/// How many entries are applied between snapshots.
+const SNAPSHOT_INTERVAL: u64 = 1000;
+
+/// An entry in the Raft log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    /// The term of the leader that appended the entry.
+    term: u64,
+
+    /// The write, or `None` for the entry a new leader appends to commit the
+    /// entries of earlier terms.
+    command: Option<WriteCommand>,
+}
+
+/// Sent by the leader to replicate its log, and as a heartbeat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AppendEntries {
+    term: u64,
+    leader_id: usize,
+
+    /// The index and term of the entry before `entries`, which the node must
+    /// have for the entries to be appended.
+    prev_log_index: u64,
+    prev_log_term: u64,
+
+    entries: Vec<LogEntry>,
+    leader_commit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AppendEntriesResponse {
+    term: u64,
+    success: bool,
+
+    /// On failure, the index the leader should send entries from next.
+    conflict_index: u64,
+}
+
+/// Sent by a candidate to ask for a node's vote.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RequestVote {
+    term: u64,
+    candidate_id: usize,
+    last_log_index: u64,
+    last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct VoteResponse {
+    term: u64,
+    vote_granted: bool,
+}
+
+/// The engine's keys as of an applied entry, which replace the log up to that
+/// entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct RaftSnapshot {
+    /// The index and term of the last entry applied.
+    index: u64,
+    term: u64,
+
+    /// The engine's sequence number and every live key, as `replicate`
+    /// returns them.
+    seq: u64,
+    keys: Vec<Change>,
+}
+
+/// Sent by the leader in place of entries it no longer has, those up to its
+/// snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct InstallSnapshot {
+    term: u64,
+    leader_id: usize,
+    snapshot: RaftSnapshot,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct InstallSnapshotResponse {
+    term: u64,
+}
+
+/// The role of a node in the cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The Raft status of a node in a cluster.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    /// The index of the node in the cluster's addresses.
+    pub id: usize,
+
+    pub role: Role,
+    pub term: u64,
+
+    /// The address of the leader, if known.
+    pub leader: Option<String>,
+
+    /// The index of the last entry known to be committed.
+    pub commit_index: u64,
+
+    /// The index of the last entry applied to the engine.
+    pub last_applied: u64,
+}
+
+/// A node in a Raft cluster.
+pub(crate) struct Raft {
+    /// The index of this node in `peers`.
+    id: usize,
+
+    /// The address of every node in the cluster, including this one.
+    peers: Vec<String>,
+
+    /// The user and credential to authenticate to the other nodes as, if
+    /// they require it.
+    credential: Option<(String, Credential)>,
+
+    state: Mutex<State>,
+
+    /// Notified whenever the state changes in a way a background thread may
+    /// be waiting for: new entries, a new commit index or a new role.
+    changed: Condvar,
+}
+
+/// The state of a node, behind its lock.
+struct State {
+    storage: Storage,
+    role: Role,
+    leader: Option<usize>,
+
+    /// The votes received as a candidate in the current term.
+    votes: usize,
+
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+
+    /// As leader, the index of the next entry to send to each node and of the
+    /// last entry each node is known to have.
+    next_index: Vec<u64>,
+    match_index: Vec<u64>,
+
+    /// The writes proposed to this node as leader, by log index, with the
+    /// term they were appended in, waiting to be applied.
+    waiters: HashMap<u64, (u64, Sender<Result<Applied>>)>,
+}
+
+impl Raft {
+    /// Opens the node's Raft state in the directory. `id` is the index of this
+    /// node's address in `peers`.
+    pub(crate) fn open(
+        dir: &Path,
+        peers: Vec<String>,
+        id: usize,
+        credential: Option<(String, Credential)>,
+    ) -> Result<Raft> {
+        if id >= peers.len() {
+            return Err(KvStoreError::StringError(format!(
+                "Node {} isn't one of the {} cluster addresses",
+                id,
+                peers.len()
+            )));
+        }
+
+        // Every entry the snapshot replaced was committed and is applied by
+        // restoring it on start.
+        let storage = Storage::open(dir)?;
+        let snapshot_index = storage.snapshot_index();
+        let state = State {
+            storage,
+            role: Role::Follower,
+            leader: None,
+            votes: 0,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            election_deadline: election_deadline(),
+            next_index: vec![1; peers.len()],
+            match_index: vec![0; peers.len()],
+            waiters: HashMap::new(),
+        };
+
+        Ok(Raft {
+            id,
+            peers,
+            credential,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+        })
+    }
+
+    /// Restores the engine from the snapshot, emptying it without one, and
+    /// starts the background threads that elect a leader, replicate the log
+    /// and apply committed entries to the engine.
+    pub(crate) fn start<E: KvsEngine>(self: &Arc<Self>, engine: E) -> Result<()> {
+        let snapshot = self.state.lock()?.storage.snapshot()?;
+        engine.restore(snapshot.seq, snapshot.keys)?;
+
+        let raft = self.clone();
+        thread::spawn(move || log_error("election timer", raft.run_timer()));
+
+        for peer in self.others() {
+            let raft = self.clone();
+            thread::spawn(move || log_error("replication", raft.run_replication(peer)));
+        }
+
+        let raft = self.clone();
+        thread::spawn(move || log_error("applier", raft.run_applier(engine)));
+
+        Ok(())
+    }
+
+    /// Appends the write to the log as leader and waits for it to be
+    /// committed and applied, returning the result of applying it.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::RedirectError` is returned if this node isn't
+    /// the leader, and `KvStoreError::NoLeaderError` if no leader is known.
+    pub(crate) fn propose(&self, command: WriteCommand) -> Result<Applied> {
+        let applied = {
+            let mut state = self.state.lock()?;
+            if state.role != Role::Leader {
+                return Err(match state.leader {
+                    Some(leader) => KvStoreError::RedirectError(self.peers[leader].clone()),
+                    None => KvStoreError::NoLeaderError,
+                });
+            }
+
+            let term = state.storage.term();
+            state.storage.append(vec![LogEntry {
+                term,
+                command: Some(command),
+            }])?;
+
+            let (sender, applied) = mpsc::channel();
+            let index = state.storage.last_index();
+            state.waiters.insert(index, (term, sender));
+
+            self.advance_commit(&mut state);
+            self.changed.notify_all();
+            applied
+        };
+
+        applied.recv_timeout(PROPOSAL_TIMEOUT).unwrap_or_else(|_| {
+            Err(KvStoreError::StringError(
+                "Timed out waiting for the write to commit".to_string(),
+            ))
+        })
+    }
+
+    /// Handles a leader's request to append entries to the log.
+    pub(crate) fn handle_append_entries(
+        &self,
+        request: AppendEntries,
+    ) -> Result<AppendEntriesResponse> {
+        let mut state = self.state.lock()?;
+
+        let term = state.storage.term();
+        if request.term < term {
+            return Ok(AppendEntriesResponse {
+                term,
+                success: false,
+                conflict_index: 0,
+            });
+        }
+
+        self.step_down(&mut state, request.term)?;
+        state.leader = Some(request.leader_id);
+        state.election_deadline = election_deadline();
+
+        // Entries up to the snapshot are committed, so match the leader's and
+        // are skipped.
+        let mut request = request;
+        let snapshot_index = state.storage.snapshot_index();
+        if request.prev_log_index < snapshot_index {
+            let skipped = (snapshot_index - request.prev_log_index) as usize;
+            let skipped = skipped.min(request.entries.len());
+            request.entries = request.entries.split_off(skipped);
+            request.prev_log_index = snapshot_index;
+            request.prev_log_term = state.storage.term_at(snapshot_index).unwrap_or(0);
+        }
+
+        // The log must have the entry before the new ones. If not, the leader
+        // is told where to retry from, skipping a whole conflicting term.
+        let prev = request.prev_log_index;
+        match state.storage.term_at(prev) {
+            Some(prev_term) if prev_term == request.prev_log_term => {}
+            Some(prev_term) => {
+                let mut conflict_index = prev;
+                while conflict_index > 1
+                    && state.storage.term_at(conflict_index - 1) == Some(prev_term)
+                {
+                    conflict_index -= 1;
+                }
+                return Ok(AppendEntriesResponse {
+                    term: request.term,
+                    success: false,
+                    conflict_index,
+                });
+            }
+            None => {
+                return Ok(AppendEntriesResponse {
+                    term: request.term,
+                    success: false,
+                    conflict_index: state.storage.last_index() + 1,
+                })
+            }
+        }
+
+        // Entries already in the log are skipped, and the log is truncated at
+        // the first entry that conflicts with the leader's.
+        let last_new = prev + request.entries.len() as u64;
+        let mut new_entries = Vec::new();
+        for (index, entry) in (prev + 1..).zip(request.entries) {
+            if new_entries.is_empty() {
+                match state.storage.term_at(index) {
+                    Some(term) if term == entry.term => continue,
+                    Some(_) => self.truncate(&mut state, index)?,
+                    None => {}
+                }
+            }
+            new_entries.push(entry);
+        }
+        state.storage.append(new_entries)?;
+
+        if request.leader_commit > state.commit_index {
+            state.commit_index = request.leader_commit.min(last_new);
+            self.changed.notify_all();
+        }
+
+        Ok(AppendEntriesResponse {
+            term: request.term,
+            success: true,
+            conflict_index: 0,
+        })
+    }
+
+    /// Handles a leader's request to replace the log with its snapshot. The
+    /// applier restores the engine from it.
+    pub(crate) fn handle_install_snapshot(
+        &self,
+        request: InstallSnapshot,
+    ) -> Result<InstallSnapshotResponse> {
+        let mut state = self.state.lock()?;
+
+        let term = state.storage.term();
+        if request.term < term {
+            return Ok(InstallSnapshotResponse { term });
+        }
+
+        self.step_down(&mut state, request.term)?;
+        state.leader = Some(request.leader_id);
+        state.election_deadline = election_deadline();
+
+        // A node that has committed the snapshot's entries already has them.
+        let index = request.snapshot.index;
+        if index > state.commit_index {
+            state.storage.install_snapshot(&request.snapshot)?;
+            state.commit_index = index;
+            self.changed.notify_all();
+        }
+
+        Ok(InstallSnapshotResponse { term: request.term })
+    }
+
+    /// Handles a candidate's request for this node's vote.
+    pub(crate) fn handle_request_vote(&self, request: RequestVote) -> Result<VoteResponse> {
+        let mut state = self.state.lock()?;
+        if request.term > state.storage.term() {
+            self.step_down(&mut state, request.term)?;
+        }
+
+        // A vote only goes to a candidate whose log has every entry this node
+        // has, so the new leader has every committed entry.
+        let term = state.storage.term();
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (state.storage.last_term(), state.storage.last_index());
+        let vote_granted = request.term == term
+            && up_to_date
+            && state
+                .storage
+                .voted_for()
+                .is_none_or(|id| id == request.candidate_id);
+
+        if vote_granted {
+            state
+                .storage
+                .set_term_and_vote(term, Some(request.candidate_id))?;
+            state.election_deadline = election_deadline();
+        }
+
+        Ok(VoteResponse { term, vote_granted })
+    }
+
+    pub(crate) fn status(&self) -> Result<ClusterStatus> {
+        let state = self.state.lock()?;
+
+        Ok(ClusterStatus {
+            id: self.id,
+            role: state.role,
+            term: state.storage.term(),
+            leader: state.leader.map(|leader| self.peers[leader].clone()),
+            commit_index: state.commit_index,
+            last_applied: state.last_applied,
+        })
+    }
+
+    /// Private helper function to return the ids of the other nodes.
+    fn others(&self) -> Vec<usize> {
+        (0..self.peers.len()).filter(|&id| id != self.id).collect()
+    }
+
+    /// Private helper function to return whether `count` nodes are a majority.
+    fn is_majority(&self, count: usize) -> bool {
+        count > self.peers.len() / 2
+    }
+
+    /// Private helper function to start an election once the election timeout
+    /// passes without hearing from a leader.
+    fn run_timer(self: Arc<Self>) -> Result<()> {
+        loop {
+            thread::sleep(TICK_INTERVAL);
+
+            let mut state = self.state.lock()?;
+            if state.role != Role::Leader && Instant::now() >= state.election_deadline {
+                self.start_election(&mut state)?;
+            }
+        }
+    }
+
+    /// Private helper function to become a candidate in the next term and ask
+    /// every other node for its vote.
+    fn start_election(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        let term = state.storage.term() + 1;
+        state.storage.set_term_and_vote(term, Some(self.id))?;
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.votes = 1;
+        state.election_deadline = election_deadline();
+        info!("Starting an election for term {}", term);
+
+        if self.is_majority(state.votes) {
+            return self.become_leader(state);
+        }
+
+        let request = RequestVote {
+            term,
+            candidate_id: self.id,
+            last_log_index: state.storage.last_index(),
+            last_log_term: state.storage.last_term(),
+        };
+        for peer in self.others() {
+            let raft = self.clone();
+            let request = Request::RequestVote(request.clone());
+            thread::spawn(move || {
+                let mut peer = Peer::new(raft.peers[peer].clone(), raft.credential.clone());
+                if let Ok(response) = peer.call(&request) {
+                    log_error("election", raft.handle_vote(term, response));
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Private helper function to count a vote for this node's candidacy in
+    /// `term`, becoming leader on a majority.
+    fn handle_vote(&self, term: u64, response: VoteResponse) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if response.term > state.storage.term() {
+            return self.step_down(&mut state, response.term);
+        }
+
+        if state.role == Role::Candidate && state.storage.term() == term && response.vote_granted {
+            state.votes += 1;
+            if self.is_majority(state.votes) {
+                self.become_leader(&mut state)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Private helper function to become leader, appending an empty entry so
+    /// the entries of earlier terms are committed along with it.
+    fn become_leader(&self, state: &mut State) -> Result<()> {
+        let term = state.storage.term();
+        info!("Became the leader for term {}", term);
+
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        state.next_index = vec![state.storage.last_index() + 1; self.peers.len()];
+        state.match_index = vec![0; self.peers.len()];
+        state.storage.append(vec![LogEntry {
+            term,
+            command: None,
+        }])?;
+
+        self.advance_commit(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Private helper function to become a follower, moving to `term` if it's
+    /// newer than the current one.
+    fn step_down(&self, state: &mut State, term: u64) -> Result<()> {
+        if term > state.storage.term() {
+            state.storage.set_term_and_vote(term, None)?;
+            state.leader = None;
+        }
+
+        if state.role != Role::Follower {
+            info!("Became a follower in term {}", state.storage.term());
+            state.role = Role::Follower;
+            self.changed.notify_all();
+        }
+
+        Ok(())
+    }
+
+    /// Private helper function to drop the log from the index on, failing the
+    /// writes waiting on the dropped entries.
+    fn truncate(&self, state: &mut State, index: u64) -> Result<()> {
+        state.storage.truncate(index)?;
+
+        let dropped: Vec<u64> = state
+            .waiters
+            .keys()
+            .filter(|&&i| i >= index)
+            .cloned()
+            .collect();
+        for i in dropped {
+            if let Some((_, sender)) = state.waiters.remove(&i) {
+                let _ = sender.send(Err(lost_leadership()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Private helper function to commit, as leader, the last entry of the
+    /// current term that a majority of nodes have.
+    fn advance_commit(&self, state: &mut State) {
+        let term = state.storage.term();
+        let last_index = state.storage.last_index();
+
+        for index in (state.commit_index + 1..=last_index).rev() {
+            if state.storage.term_at(index) != Some(term) {
+                break;
+            }
+
+            let count = 1 + self
+                .others()
+                .into_iter()
+                .filter(|&peer| state.match_index[peer] >= index)
+                .count();
+            if self.is_majority(count) {
+                state.commit_index = index;
+                self.changed.notify_all();
+                break;
+            }
+        }
+    }
+
+    /// Private helper function to send a node new entries, or a heartbeat,
+    /// whenever this node is leader. A node that needs entries replaced by
+    /// the snapshot is sent the snapshot.
+    fn run_replication(self: Arc<Self>, peer: usize) -> Result<()> {
+        let mut client = Peer::new(self.peers[peer].clone(), self.credential.clone());
+
+        loop {
+            let request = {
+                let mut state = self.state.lock()?;
+                while state.role != Role::Leader {
+                    state = self.changed.wait(state)?;
+                }
+
+                let next_index = state.next_index[peer];
+                if next_index <= state.storage.snapshot_index() {
+                    Request::InstallSnapshot(InstallSnapshot {
+                        term: state.storage.term(),
+                        leader_id: self.id,
+                        snapshot: state.storage.snapshot()?,
+                    })
+                } else {
+                    Request::AppendEntries(AppendEntries {
+                        term: state.storage.term(),
+                        leader_id: self.id,
+                        prev_log_index: next_index - 1,
+                        prev_log_term: state.storage.term_at(next_index - 1).unwrap_or(0),
+                        entries: state.storage.entries_from(next_index, MAX_ENTRIES),
+                        leader_commit: state.commit_index,
+                    })
+                }
+            };
+
+            let answered = match &request {
+                Request::AppendEntries(sent) => match client.call(&request) {
+                    Ok(response) => {
+                        let count = sent.entries.len() as u64;
+                        let prev = sent.prev_log_index;
+                        self.handle_append_response(peer, sent.term, prev, count, response)?;
+                        true
+                    }
+                    Err(_) => false,
+                },
+                Request::InstallSnapshot(sent) => match client.call(&request) {
+                    Ok(response) => {
+                        let index = sent.snapshot.index;
+                        self.handle_snapshot_response(peer, sent.term, index, response)?;
+                        true
+                    }
+                    Err(_) => false,
+                },
+                _ => unreachable!("only AppendEntries and InstallSnapshot are sent"),
+            };
+
+            // Sends the rest of the log right away to a node that answered,
+            // and otherwise waits for new entries or the next heartbeat.
+            let state = self.state.lock()?;
+            let behind =
+                state.role == Role::Leader && state.next_index[peer] <= state.storage.last_index();
+            if !(answered && behind) {
+                let _ = self.changed.wait_timeout(state, HEARTBEAT_INTERVAL)?;
+            }
+        }
+    }
+
+    /// Private helper function to handle a node's response to entries sent in
+    /// `term` after `prev`.
+    fn handle_append_response(
+        &self,
+        peer: usize,
+        term: u64,
+        prev: u64,
+        sent: u64,
+        response: AppendEntriesResponse,
+    ) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if response.term > state.storage.term() {
+            return self.step_down(&mut state, response.term);
+        }
+        if state.role != Role::Leader || state.storage.term() != term {
+            return Ok(());
+        }
+
+        let last_index = state.storage.last_index();
+        if response.success {
+            state.match_index[peer] = state.match_index[peer].max(prev + sent);
+            state.next_index[peer] = prev + sent + 1;
+            self.advance_commit(&mut state);
+        } else {
+            state.next_index[peer] = response.conflict_index.max(1).min(last_index + 1);
+        }
+
+        Ok(())
+    }
+
+    /// Private helper function to handle a node's response to the snapshot
+    /// sent in `term`, up to `index`.
+    fn handle_snapshot_response(
+        &self,
+        peer: usize,
+        term: u64,
+        index: u64,
+        response: InstallSnapshotResponse,
+    ) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if response.term > state.storage.term() {
+            return self.step_down(&mut state, response.term);
+        }
+        if state.role != Role::Leader || state.storage.term() != term {
+            return Ok(());
+        }
+
+        state.match_index[peer] = state.match_index[peer].max(index);
+        state.next_index[peer] = index + 1;
+        self.advance_commit(&mut state);
+
+        Ok(())
+    }
+
+    /// Private helper function to apply committed entries to the engine in
+    /// log order, answering the writes waiting on them. The engine is
+    /// restored from a snapshot the leader installed, and snapshotted every
+    /// `SNAPSHOT_INTERVAL` entries.
+    fn run_applier<E: KvsEngine>(self: Arc<Self>, engine: E) -> Result<()> {
+        loop {
+            let (index, entry) = {
+                let mut state = self.state.lock()?;
+                while state.last_applied >= state.commit_index
+                    && state.last_applied >= state.storage.snapshot_index()
+                {
+                    state = self.changed.wait(state)?;
+                }
+
+                // The snapshot is read under the lock, so it can't be
+                // replaced meanwhile, and restored outside it.
+                if state.last_applied < state.storage.snapshot_index() {
+                    let snapshot = state.storage.snapshot()?;
+                    drop(state);
+
+                    engine.restore(snapshot.seq, snapshot.keys)?;
+                    let mut state = self.state.lock()?;
+                    state.last_applied = state.last_applied.max(snapshot.index);
+                    continue;
+                }
+
+                let index = state.last_applied + 1;
+                match state.storage.entry(index) {
+                    Some(entry) => (index, entry.clone()),
+                    None => {
+                        return Err(KvStoreError::StringError(format!(
+                            "Committed entry {} is missing from the log",
+                            index
+                        )))
+                    }
+                }
+            };
+
+            let res = match entry.command {
+                Some(command) => command.apply(&engine),
+                None => Ok(Applied::Seq(0)),
+            };
+
+            // The engine's keys are read outside the lock, and only replace
+            // the log if no snapshot was installed meanwhile.
+            let snapshot_index = self.state.lock()?.storage.snapshot_index();
+            let snapshot = if index - snapshot_index >= SNAPSHOT_INTERVAL {
+                let (seq, keys) = engine.keys()?;
+                Some(RaftSnapshot {
+                    index,
+                    term: entry.term,
+                    seq,
+                    keys,
+                })
+            } else {
+                None
+            };
+
+            let mut state = self.state.lock()?;
+            state.last_applied = index;
+            if let Some(snapshot) = snapshot {
+                if state.storage.snapshot_index() < index {
+                    state.storage.install_snapshot(&snapshot)?;
+                }
+            }
+            if let Some((term, sender)) = state.waiters.remove(&index) {
+                // A different entry at the index means the write was lost
+                // along with this node's leadership.
+                let res = if term == entry.term {
+                    res
+                } else {
+                    Err(lost_leadership())
+                };
+                let _ = sender.send(res);
+            }
+        }
+    }
+}
+
+/// Private helper function to return when to start an election if no leader
+/// is heard from, a random time between one and two election timeouts away.
+fn election_deadline() -> Instant {
+    let timeout = ELECTION_TIMEOUT.as_millis() as u64;
+    let jitter = RandomState::new().build_hasher().finish() % timeout;
+
+    Instant::now() + Duration::from_millis(timeout + jitter)
+}
+
+/// Private helper function to return the error for a write dropped from the
+/// log when its leader lost leadership.
+fn lost_leadership() -> KvStoreError {
+    KvStoreError::StringError("Leadership was lost before the write committed".to_string())
+}
+
+/// Private helper function to log the error ending a background thread.
+fn log_error(name: &str, res: Result<()>) {
+    if let Err(e) = res {
+        error!("Raft {} failed: {}", name, e);
+    }
+}
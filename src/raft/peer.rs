@@ -0,0 +1,85 @@
+use crate::common::{AuthResponse, Request};
+use crate::{Credential, KvStoreError, Result};
+use serde::de::DeserializeOwned;
+use serde_json::de::{Deserializer, IoRead};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// How long to wait to connect to a peer.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long to wait for a peer to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The reader and writer of an open connection.
+type Connection = (
+    Deserializer<IoRead<BufReader<TcpStream>>>,
+    BufWriter<TcpStream>,
+);
+
+/// A connection to another node of the cluster for Raft requests, opened on
+/// first use and reopened after any error. Each connection is authenticated
+/// first if the node has a credential.
+pub(crate) struct Peer {
+    addr: String,
+    credential: Option<(String, Credential)>,
+    conn: Option<Connection>,
+}
+
+impl Peer {
+    pub(crate) fn new(addr: String, credential: Option<(String, Credential)>) -> Self {
+        Peer {
+            addr,
+            credential,
+            conn: None,
+        }
+    }
+
+    /// Sends the request and waits for the peer's response.
+    pub(crate) fn call<T: DeserializeOwned>(&mut self, request: &Request) -> Result<T> {
+        let res = self.try_call(request);
+        if res.is_err() {
+            self.conn = None;
+        }
+        res
+    }
+
+    /// Private helper function to send a request, connecting first if needed.
+    fn try_call<T: DeserializeOwned>(&mut self, request: &Request) -> Result<T> {
+        if self.conn.is_none() {
+            let addr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                KvStoreError::StringError(format!("Unable to resolve {}", self.addr))
+            })?;
+
+            let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+            stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+            stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+            stream.set_nodelay(true)?;
+
+            let reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+            let mut conn = (reader, BufWriter::new(stream));
+
+            if let Some((user, credential)) = self.credential.clone() {
+                let request = Request::Auth { user, credential };
+                match send(&mut conn, &request)? {
+                    AuthResponse::Ok(_) => {}
+                    AuthResponse::Err(e) => return Err(KvStoreError::AuthenticationError(e)),
+                }
+            }
+            self.conn = Some(conn);
+        }
+
+        send(self.conn.as_mut().unwrap(), request)
+    }
+}
+
+/// Private helper function to send a request over the connection and read
+/// the response.
+fn send<T: DeserializeOwned>(conn: &mut Connection, request: &Request) -> Result<T> {
+    let (reader, writer) = conn;
+    serde_json::to_writer(&mut *writer, request)?;
+    writer.flush()?;
+
+    Ok(T::deserialize(reader)?)
+}
@@ -0,0 +1,284 @@
+use super::{LogEntry, RaftSnapshot};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// The term and vote of a node, which must survive a restart so a node never
+/// votes twice in a term.
+#[derive(Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<usize>,
+}
+
+/// The persisted state of a Raft node.
+///
+/// The term and vote are kept in `raft-state.json`, rewritten on every change.
+/// The entries up to the last snapshot are replaced by the snapshot, kept in
+/// `raft-snapshot.json`, and the entries after it are kept in a log named for
+/// the index of its first entry, `raft-log-<index>.txt`, one JSON entry per
+/// line, appended to as entries are added. Log indexes start at 1.
+///
+/// Every change is synced to disk before it returns, since a node's answers
+/// to the leader and to candidates promise what it has stored.
+pub(crate) struct Storage {
+    dir: PathBuf,
+    state_path: PathBuf,
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    state: HardState,
+
+    /// The index and term of the last entry the snapshot replaced, or 0
+    /// without a snapshot.
+    snapshot_index: u64,
+    snapshot_term: u64,
+
+    /// The entries after the snapshot.
+    entries: Vec<LogEntry>,
+}
+
+impl Storage {
+    /// Opens the state, snapshot and log in the directory, creating them if
+    /// they don't exist.
+    pub(crate) fn open(dir: &Path) -> Result<Storage> {
+        fs::create_dir_all(dir)?;
+        let state_path = dir.join("raft-state.json");
+        let snapshot_path = dir.join("raft-snapshot.json");
+
+        let state = match fs::read(&state_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let (snapshot_index, snapshot_term) = match fs::read(&snapshot_path) {
+            Ok(bytes) => {
+                let snapshot: RaftSnapshot = serde_json::from_slice(&bytes)?;
+                (snapshot.index, snapshot.term)
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e.into()),
+        };
+
+        // The log of a node from before snapshots has no index in its name.
+        let log_path = log_path(dir, snapshot_index + 1);
+        let old_log_path = dir.join("raft-log.txt");
+        if snapshot_index == 0 && old_log_path.exists() && !log_path.exists() {
+            replace(&old_log_path, &log_path)?;
+        }
+
+        // A crash while compacting may leave the log of another snapshot
+        // behind, which the snapshot on disk doesn't go with.
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            if name.is_some_and(|name| name.starts_with("raft-log-")) && path != log_path {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let log_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&log_path)?;
+
+        // An entry torn by a crash mid-write was never acknowledged, so it's
+        // dropped and the log truncated to the last whole entry.
+        let mut entries = Vec::new();
+        let mut reader = BufReader::new(&log_file);
+        let mut record = Vec::new();
+        let mut offset = 0;
+        loop {
+            record.clear();
+            let len = reader.read_until(b'\n', &mut record)? as u64;
+            if len == 0 {
+                break;
+            }
+            if record.last() != Some(&b'\n') {
+                log_file.set_len(offset)?;
+                break;
+            }
+
+            entries.push(serde_json::from_slice(&record)?);
+            offset += len;
+        }
+
+        Ok(Storage {
+            dir: dir.to_path_buf(),
+            state_path,
+            snapshot_path,
+            log_path,
+            state,
+            snapshot_index,
+            snapshot_term,
+            entries,
+        })
+    }
+
+    pub(crate) fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub(crate) fn voted_for(&self) -> Option<usize> {
+        self.state.voted_for
+    }
+
+    /// Persists the term and vote, replacing the state file whole so a crash
+    /// leaves either the old or the new state.
+    pub(crate) fn set_term_and_vote(&mut self, term: u64, voted_for: Option<usize>) -> Result<()> {
+        self.state = HardState { term, voted_for };
+
+        let tmp_path = self.state_path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(&self.state)?)?;
+        file.sync_all()?;
+        replace(&tmp_path, &self.state_path)
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// Returns the index of the last entry the snapshot replaced, 0 without
+    /// a snapshot.
+    pub(crate) fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    /// Returns the term of the entry at the index, 0 for the index 0 before
+    /// the first entry, or `None` if there's no entry at the index or it was
+    /// replaced by the snapshot.
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else {
+            self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    /// Returns the entry at the index, or `None` if there's no entry at the
+    /// index or it was replaced by the snapshot.
+    pub(crate) fn entry(&self, index: u64) -> Option<&LogEntry> {
+        index
+            .checked_sub(self.snapshot_index + 1)
+            .and_then(|i| self.entries.get(i as usize))
+    }
+
+    /// Returns up to `max` entries starting at the index, which must be after
+    /// the snapshot.
+    pub(crate) fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        let start = index.saturating_sub(self.snapshot_index + 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// Reads the snapshot back, or returns an empty one at index 0 without a
+    /// snapshot.
+    pub(crate) fn snapshot(&self) -> Result<RaftSnapshot> {
+        if self.snapshot_index == 0 {
+            return Ok(RaftSnapshot::default());
+        }
+
+        Ok(serde_json::from_slice(&fs::read(&self.snapshot_path)?)?)
+    }
+
+    /// Replaces the entries up to the snapshot's last entry with the
+    /// snapshot. The entries after it are kept if the log has its last entry,
+    /// and otherwise the whole log is dropped for the snapshot's.
+    ///
+    /// The log after the snapshot is written before the snapshot, and the old
+    /// log removed after it, so a crash leaves a snapshot and its log.
+    pub(crate) fn install_snapshot(&mut self, snapshot: &RaftSnapshot) -> Result<()> {
+        let entries = if self.term_at(snapshot.index) == Some(snapshot.term) {
+            self.entries_from(snapshot.index + 1, usize::MAX)
+        } else {
+            Vec::new()
+        };
+
+        let log_path = log_path(&self.dir, snapshot.index + 1);
+        write_log(&log_path, &entries)?;
+
+        let tmp_path = self.snapshot_path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, snapshot)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        replace(&tmp_path, &self.snapshot_path)?;
+
+        if log_path != self.log_path {
+            fs::remove_file(&self.log_path)?;
+        }
+        self.log_path = log_path;
+        self.snapshot_index = snapshot.index;
+        self.snapshot_term = snapshot.term;
+        self.entries = entries;
+
+        Ok(())
+    }
+
+    /// Appends the entries to the log.
+    pub(crate) fn append(&mut self, entries: Vec<LogEntry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut writer = BufWriter::new(OpenOptions::new().append(true).open(&self.log_path)?);
+        for entry in &entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Drops the entry at the index, which must be after the snapshot, and
+    /// every entry after it, rewriting the log.
+    pub(crate) fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries
+            .truncate(index.saturating_sub(self.snapshot_index + 1) as usize);
+        write_log(&self.log_path, &self.entries)
+    }
+}
+
+/// Private helper function to return the path of the log whose first entry
+/// is at the index. Zero-padded so the logs sort in order.
+fn log_path(dir: &Path, first_index: u64) -> PathBuf {
+    dir.join(format!("raft-log-{:020}.txt", first_index))
+}
+
+/// Private helper function to write a whole log, replacing the one at the
+/// path.
+fn write_log(path: &Path, entries: &[LogEntry]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    replace(&tmp_path, path)
+}
+
+/// Private helper function to move a synced file over another, then sync the
+/// directory so the rename itself survives a crash.
+fn replace(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)?;
+    if let Some(dir) = to.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Asynchronous leader-follower replication.
+//!
+//! A replica connects to its leader and sends a `Replicate` request. The
+//! leader answers with a snapshot of every live key, which replaces the
+//! replica's data, then streams every later write, which the replica applies
+//! with the leader's sequence numbers. Replicas serve reads and redirect
+//! writes to the leader. On a lost connection the replica reconnects and
+//! bootstraps again, but gives up if the leader rejects its credentials.
+
+use crate::common::{now_millis, ReplicationMessage};
+use crate::engines::KvsEngine;
+use crate::raft::ClusterStatus;
+use crate::{KvStoreError, KvsClientBuilder, Result};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// How long a replica waits before reconnecting to its leader.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often an idle leader sends replicas a heartbeat.
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The status of a server, returned by `KvsClient::status`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    /// The sequence number of the last write applied by the server.
+    pub seq: u64,
+
+    /// The replication status, if the server is a replica.
+    pub replica: Option<ReplicaStatus>,
+
+    /// The Raft status, if the server is a node of a cluster.
+    #[serde(default)]
+    pub cluster: Option<ClusterStatus>,
+}
+
+/// The replication status of a replica.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    /// The address of the leader.
+    pub leader: String,
+
+    /// Whether the replica is connected to the leader and bootstrapped.
+    pub connected: bool,
+
+    /// The sequence number of the last write applied by the replica.
+    pub applied_seq: u64,
+
+    /// The sequence number of the leader's last write, as last heard from
+    /// the leader.
+    pub leader_seq: u64,
+
+    /// When the replica last heard from the leader, in milliseconds since the
+    /// unix epoch.
+    pub last_contact: Option<u64>,
+}
+
+impl ReplicaStatus {
+    /// Returns the number of writes the replica is behind the leader, as of
+    /// when it last heard from the leader.
+    pub fn lag(&self) -> u64 {
+        self.leader_seq.saturating_sub(self.applied_seq)
+    }
+}
+
+/// A server's replication from its leader.
+pub(crate) struct Replica {
+    /// Connects to the leader, over TLS or authenticated if the leader
+    /// requires it.
+    leader: KvsClientBuilder,
+    status: Mutex<ReplicaStatus>,
+}
+
+impl Replica {
+    pub(crate) fn new(leader: KvsClientBuilder) -> Self {
+        Replica {
+            status: Mutex::new(ReplicaStatus {
+                leader: leader.addr().to_string(),
+                connected: false,
+                applied_seq: 0,
+                leader_seq: 0,
+                last_contact: None,
+            }),
+            leader,
+        }
+    }
+
+    pub(crate) fn leader(&self) -> &str {
+        self.leader.addr()
+    }
+
+    pub(crate) fn status(&self) -> Result<ReplicaStatus> {
+        Ok(self.status.lock()?.clone())
+    }
+
+    /// Replicates from the leader into the engine, reconnecting whenever the
+    /// connection is lost, until the leader rejects the replica's
+    /// credentials, which retrying can't fix.
+    pub(crate) fn follow<E: KvsEngine>(&self, engine: E) {
+        loop {
+            match self.replicate(&engine) {
+                Err(e @ KvStoreError::AuthenticationError(_))
+                | Err(e @ KvStoreError::PermissionDeniedError(_)) => {
+                    error!("Replication from {} refused: {}", self.leader(), e);
+                    if let Ok(mut status) = self.status.lock() {
+                        status.connected = false;
+                    }
+                    return;
+                }
+                Err(e) => error!("Replication from {} failed: {}", self.leader(), e),
+                Ok(()) => {}
+            }
+
+            if let Ok(mut status) = self.status.lock() {
+                status.connected = false;
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    }
+
+    /// Private helper function to bootstrap from the leader and apply its
+    /// writes until the connection is lost.
+    fn replicate<E: KvsEngine>(&self, engine: &E) -> Result<()> {
+        let messages = self.leader.connect()?.replicate()?;
+
+        for message in messages {
+            let (applied_seq, leader_seq) = match message? {
+                ReplicationMessage::Snapshot { seq, keys } => {
+                    engine.restore(seq, keys)?;
+                    info!("Bootstrapped from {} at sequence {}", self.leader(), seq);
+                    (Some(seq), seq)
+                }
+                ReplicationMessage::Write {
+                    changes,
+                    leader_seq,
+                } => {
+                    let seq = changes.last().map(|change| change.seq);
+                    engine.apply_changes(changes)?;
+                    (seq, leader_seq)
+                }
+                ReplicationMessage::Heartbeat { leader_seq } => (None, leader_seq),
+            };
+
+            let mut status = self.status.lock()?;
+            status.connected = true;
+            status.leader_seq = leader_seq;
+            status.last_contact = Some(now_millis());
+            if let Some(seq) = applied_seq {
+                status.applied_seq = seq;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,61 +1,529 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::auth::{Access, Acl, Credential, Session};
+use crate::common::{
+    expires_at, Applied, AuthResponse, BackupResponse, BatchResponse, CasResponse, CommitResponse,
+    FormatResponse, GetResponse, GetVersionedResponse, RedirectResponse, RemoveResponse,
+    ReplicateResponse, ReplicationMessage, Request, ScanPageResponse, ScanResponse, SetResponse,
+    StatusResponse, TtlResponse, WatchResponse, WriteCommand,
+};
 use crate::engines::KvsEngine;
 use crate::error::KvStoreError;
-use crate::Result;
-use serde_json::Deserializer;
-use std::io::prelude::*;
-use std::io::{BufReader, BufWriter};
+use crate::raft::Raft;
+use crate::replication::{Replica, ServerStatus, HEARTBEAT_INTERVAL};
+use crate::tls::Stream;
+use crate::wire::{WireReader, WireWriter};
+use crate::{KvsClient, KvsClientBuilder, Result};
+use log::error;
+use rustls::ServerConfig;
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often a watch connection with no changes to send checks whether the
+/// client has hung up.
+pub(crate) const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Server for the Key/Value store.
+#[derive(Clone)]
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+
+    /// The TLS config used to wrap every accepted connection, if TLS is
+    /// enabled.
+    tls: Option<Arc<ServerConfig>>,
+
+    /// The users allowed to connect and the keys they may access. Without an
+    /// ACL every connection may access every key.
+    acl: Option<Arc<Acl>>,
+
+    /// The replication from the leader, if the server is a replica.
+    replica: Option<Arc<Replica>>,
+
+    /// The Raft node, if the server is a node of a cluster.
+    raft: Option<Arc<Raft>>,
+
+    /// The directory clients may back the data up into. Without one, backups
+    /// are refused.
+    backup_dir: Option<Arc<PathBuf>>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            tls: None,
+            acl: None,
+            replica: None,
+            raft: None,
+            backup_dir: None,
+        }
     }
 
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        TcpListener::bind(addr)?
-            .incoming()
-            .try_for_each(|s| self.handle_stream(s?))
+    /// Enables TLS on every connection accepted by the server.
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
     }
 
-    pub fn handle_stream(&mut self, stream: TcpStream) -> Result<()> {
-        let r = BufReader::new(&stream);
-        let mut w = BufWriter::new(&stream);
+    /// Requires every connection to authenticate as a user in the ACL, and
+    /// checks each request against that user's grants.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Makes the server a replica of the leader at the address. Once serving,
+    /// the replica replaces its data with the leader's and applies the
+    /// leader's writes as they're made, serving reads and redirecting writes
+    /// to the leader.
+    pub fn with_replica_of(self, leader: String) -> Self {
+        self.with_replica_of_client(KvsClient::builder(leader))
+    }
 
-        let request = Deserializer::from_reader(r).into_iter::<Request>();
+    /// Makes the server a replica of the leader the builder connects to, like
+    /// `with_replica_of`, over TLS or authenticated as a user if the builder
+    /// is. The user needs read access to every key.
+    pub fn with_replica_of_client(mut self, leader: KvsClientBuilder) -> Self {
+        self.replica = Some(Arc::new(Replica::new(leader)));
+        self
+    }
+
+    /// Makes the server the node at index `id` of a Raft cluster of the
+    /// servers at `peers`, keeping its Raft state in the directory. Once
+    /// serving, every write goes through the cluster's leader and is applied
+    /// once a majority of nodes have it. Writes sent to another node are
+    /// redirected to the leader.
+    pub fn with_cluster(mut self, peers: Vec<String>, id: usize, path: &Path) -> Result<Self> {
+        self.raft = Some(Arc::new(Raft::open(path, peers, id, None)?));
+        Ok(self)
+    }
+
+    /// Makes the server a node of a Raft cluster, like `with_cluster`, that
+    /// authenticates to the other nodes as a user. Nodes with an ACL only
+    /// accept Raft requests from a user with write access to every key.
+    pub fn with_cluster_as(
+        mut self,
+        peers: Vec<String>,
+        id: usize,
+        path: &Path,
+        user: String,
+        credential: Credential,
+    ) -> Result<Self> {
+        let credential = Some((user, credential));
+        self.raft = Some(Arc::new(Raft::open(path, peers, id, credential)?));
+        Ok(self)
+    }
+
+    /// Allows clients to back the data up into new directories under `dir`,
+    /// named by a relative path that stays within it.
+    pub fn with_backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backup_dir = Some(Arc::new(dir));
+        self
+    }
+
+    /// Listens on the address and serves each connection on its own thread. An
+    /// error on a single connection, such as a failed TLS handshake, is logged
+    /// and doesn't stop the server.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves each connection accepted by an already bound listener, like
+    /// `run`.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        if let Some(replica) = &self.replica {
+            let replica = replica.clone();
+            let engine = self.engine.clone();
+            thread::spawn(move || replica.follow(engine));
+        }
+
+        if let Some(raft) = &self.raft {
+            raft.start(self.engine.clone())?;
+        }
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+
+            thread::spawn(move || {
+                if let Err(e) = server.handle_stream(stream) {
+                    error!("Error on connection: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_stream(&self, stream: TcpStream) -> Result<()> {
+        let stream = match &self.tls {
+            Some(config) => Stream::server(stream, config.clone())?,
+            None => Stream::Plain(stream),
+        };
+
+        let mut r = WireReader::new(stream.try_clone()?);
+        let mut w = WireWriter::new(stream);
 
         // Macro for sending reponses back over the tcp stream.
         macro_rules! send_response {
             ($response:expr) => {{
                 let resp = $response;
-                serde_json::to_writer(&mut w, &resp)?;
+                w.write(&resp)?;
                 w.flush()?;
-            };};
+            }};
         }
 
-        for req in request {
+        let mut session = Session::new(self.acl.as_deref());
+
+        while let Some(req) = r.next::<Request>() {
             let req = req?;
+
+            if let (Some(replica), true) = (&self.replica, req.is_write()) {
+                send_response!(RedirectResponse::Redirect(replica.leader().to_string()));
+                continue;
+            }
+
             match req {
-                Request::Set { key, value } => send_response!(match self.engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(e.to_string()),
-                }),
-                Request::Get { key } => send_response!(match self.engine.get(key) {
-                    Ok(r) => GetResponse::Ok(r),
-                    Err(_) => GetResponse::Err(KvStoreError::KeyNotFoundError.to_string()),
+                Request::Auth { user, credential } => {
+                    send_response!(match session.authenticate(&user, &credential) {
+                        Ok(_) => AuthResponse::Ok(()),
+                        Err(e) => AuthResponse::Err(e),
+                    })
+                }
+                Request::Format { format } => {
+                    send_response!(FormatResponse::Ok(format));
+                    r.set_format(format);
+                    w.set_format(format);
+                }
+                Request::Set { key, value, ttl } => {
+                    send_response!(match session.check(&key, Access::Write) {
+                        Err(e) => SetResponse::Denied(e),
+                        Ok(_) => {
+                            let expires_at = ttl.map(expires_at);
+                            let command = WriteCommand::Set {
+                                key,
+                                value,
+                                expires_at,
+                            };
+                            match self.write(command).and_then(Applied::into_seq) {
+                                Ok(seq) => SetResponse::Ok(seq),
+                                Err(KvStoreError::RedirectError(leader)) => {
+                                    SetResponse::Redirect(leader)
+                                }
+                                Err(e) => SetResponse::Err(e.to_string()),
+                            }
+                        }
+                    })
+                }
+                Request::Get { key } => send_response!(match session.check(&key, Access::Read) {
+                    Err(e) => GetResponse::Denied(e),
+                    Ok(_) => match self.engine.get_bytes(&key) {
+                        Ok(r) => GetResponse::Ok(r),
+                        Err(e) => GetResponse::Err(e.to_string()),
+                    },
                 }),
-                Request::Remove { key } => send_response!(match self.engine.remove(key) {
-                    Ok(_) => RemoveResponse::Ok(()),
-                    Err(e) => RemoveResponse::Err(e.to_string()),
+                Request::Ttl { key } => send_response!(match session.check(&key, Access::Read) {
+                    Err(e) => TtlResponse::Denied(e),
+                    Ok(_) => match self.engine.ttl_bytes(&key) {
+                        Ok(r) => TtlResponse::Ok(r),
+                        Err(e) => TtlResponse::Err(e.to_string()),
+                    },
                 }),
+                Request::Batch { batch } => {
+                    let allowed = batch
+                        .keys()
+                        .try_for_each(|key| session.check(key, Access::Write));
+                    send_response!(match allowed {
+                        Err(e) => BatchResponse::Denied(e),
+                        Ok(_) => match self
+                            .write(WriteCommand::Batch { batch })
+                            .and_then(Applied::into_seq)
+                        {
+                            Ok(seq) => BatchResponse::Ok(seq),
+                            Err(KvStoreError::RedirectError(leader)) => {
+                                BatchResponse::Redirect(leader)
+                            }
+                            Err(e) => BatchResponse::Err(e.to_string()),
+                        },
+                    })
+                }
+                Request::CompareAndSwap { key, expected, new } => {
+                    // The current value is returned on a mismatch, so reading
+                    // the key must be allowed too.
+                    let allowed = session
+                        .check(&key, Access::Read)
+                        .and_then(|_| session.check(&key, Access::Write));
+                    send_response!(match allowed {
+                        Err(e) => CasResponse::Denied(e),
+                        Ok(_) => match self
+                            .write(WriteCommand::CompareAndSwap { key, expected, new })
+                            .and_then(Applied::into_cas)
+                        {
+                            Ok(r) => CasResponse::Ok(r),
+                            Err(KvStoreError::RedirectError(leader)) => {
+                                CasResponse::Redirect(leader)
+                            }
+                            Err(e) => CasResponse::Err(e.to_string()),
+                        },
+                    })
+                }
+                Request::GetVersioned { key } => {
+                    send_response!(match session.check(&key, Access::Read) {
+                        Err(e) => GetVersionedResponse::Denied(e),
+                        Ok(_) => match self.engine.get_versioned_bytes(&key) {
+                            Ok(r) => GetVersionedResponse::Ok(r),
+                            Err(e) => GetVersionedResponse::Err(e.to_string()),
+                        },
+                    })
+                }
+                Request::Commit { reads, batch } => {
+                    // A conflict reveals whether a read key has changed, so
+                    // every read key must be readable as well.
+                    let allowed = reads
+                        .iter()
+                        .try_for_each(|(key, _)| session.check(key, Access::Read))
+                        .and_then(|_| {
+                            batch
+                                .keys()
+                                .try_for_each(|key| session.check(key, Access::Write))
+                        });
+                    send_response!(match allowed {
+                        Err(e) => CommitResponse::Denied(e),
+                        Ok(_) => match self
+                            .write(WriteCommand::Commit { reads, batch })
+                            .and_then(Applied::into_seq)
+                        {
+                            Ok(seq) => CommitResponse::Ok(seq),
+                            Err(KvStoreError::RedirectError(leader)) => {
+                                CommitResponse::Redirect(leader)
+                            }
+                            Err(KvStoreError::TransactionConflictError(key)) => {
+                                CommitResponse::Conflict(key)
+                            }
+                            Err(e) => CommitResponse::Err(e.to_string()),
+                        },
+                    })
+                }
+                Request::Scan { prefix } => {
+                    send_response!(match session.check(&prefix, Access::Read) {
+                        Err(e) => ScanResponse::Denied(e),
+                        Ok(_) => match self.engine.scan_bytes(&prefix) {
+                            Ok(r) => ScanResponse::Ok(r),
+                            Err(e) => ScanResponse::Err(e.to_string()),
+                        },
+                    })
+                }
+                Request::ScanPage {
+                    prefix,
+                    start,
+                    limit,
+                } => {
+                    send_response!(match session.check(&prefix, Access::Read) {
+                        Err(e) => ScanPageResponse::Denied(e),
+                        Ok(_) => match self.engine.scan_page_bytes(&prefix, &start, limit) {
+                            Ok(r) => ScanPageResponse::Ok(r),
+                            Err(e) => ScanPageResponse::Err(e.to_string()),
+                        },
+                    })
+                }
+                Request::Watch { prefix, from } => {
+                    let watch = session
+                        .check(&prefix, Access::Read)
+                        .map(|_| self.engine.watch_bytes(&prefix, from));
+                    let changes = match watch {
+                        Err(e) => {
+                            send_response!(WatchResponse::Denied(e));
+                            continue;
+                        }
+                        Ok(Err(e)) => {
+                            send_response!(WatchResponse::Err(e.to_string()));
+                            continue;
+                        }
+                        Ok(Ok(changes)) => changes,
+                    };
+                    send_response!(WatchResponse::Ok(()));
+
+                    // The connection only streams changes from now on, until
+                    // the client hangs up or falls too far behind. The client
+                    // sends nothing more, so anything it sends, or the end of
+                    // the connection, means it has hung up.
+                    loop {
+                        match changes.recv_timeout(WATCH_POLL_INTERVAL) {
+                            Ok(change) => {
+                                if w.write(&change).is_err() || w.flush().is_err() {
+                                    break;
+                                }
+                            }
+                            Err(RecvTimeoutError::Timeout) => {
+                                if w.get_ref().has_input().unwrap_or(true) {
+                                    break;
+                                }
+                            }
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    return Ok(());
+                }
+                Request::Replicate => {
+                    // A replica copies every key.
+                    let replication = session
+                        .check(b"", Access::Read)
+                        .map(|_| self.engine.replicate());
+                    let replication = match replication {
+                        Err(e) => {
+                            send_response!(ReplicateResponse::Denied(e));
+                            continue;
+                        }
+                        Ok(Err(e)) => {
+                            send_response!(ReplicateResponse::Err(e.to_string()));
+                            continue;
+                        }
+                        Ok(Ok(replication)) => replication,
+                    };
+                    send_response!(ReplicateResponse::Ok(()));
+                    send_response!(ReplicationMessage::Snapshot {
+                        seq: replication.seq,
+                        keys: replication.keys,
+                    });
+
+                    // The connection only streams writes from now on, with a
+                    // heartbeat when idle, until the replica hangs up.
+                    loop {
+                        let message = match replication.changes.recv_timeout(HEARTBEAT_INTERVAL) {
+                            Ok(changes) => ReplicationMessage::Write {
+                                changes,
+                                leader_seq: self.engine.last_seq()?,
+                            },
+                            Err(RecvTimeoutError::Timeout) => ReplicationMessage::Heartbeat {
+                                leader_seq: self.engine.last_seq()?,
+                            },
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        };
+                        if w.write(&message).is_err() || w.flush().is_err() {
+                            break;
+                        }
+                    }
+                    return Ok(());
+                }
+                Request::Status => {
+                    if let Err(e) = session.check_authenticated() {
+                        send_response!(StatusResponse::Denied(e));
+                        continue;
+                    }
+
+                    let status = self.engine.last_seq().and_then(|seq| {
+                        let replica = match &self.replica {
+                            Some(replica) => Some(replica.status()?),
+                            None => None,
+                        };
+                        let cluster = match &self.raft {
+                            Some(raft) => Some(raft.status()?),
+                            None => None,
+                        };
+                        Ok(ServerStatus {
+                            seq,
+                            replica,
+                            cluster,
+                        })
+                    });
+                    send_response!(match status {
+                        Ok(status) => StatusResponse::Ok(status),
+                        Err(e) => StatusResponse::Err(e.to_string()),
+                    })
+                }
+                Request::Backup { dest } => {
+                    // A backup copies every key.
+                    let allowed = session
+                        .check(b"", Access::Read)
+                        .and_then(|_| session.check(b"", Access::Write));
+                    send_response!(match allowed {
+                        Err(e) => BackupResponse::Denied(e),
+                        Ok(_) => match self
+                            .backup_path(&dest)
+                            .and_then(|dest| self.engine.backup(&dest))
+                        {
+                            Ok(r) => BackupResponse::Ok(r),
+                            Err(e) => BackupResponse::Err(e.to_string()),
+                        },
+                    })
+                }
+                Request::AppendEntries(request) => {
+                    // Raft requests rewrite the log, so with an ACL only a
+                    // user with write access to every key, as the other nodes
+                    // authenticate as, may send them. Any other connection
+                    // sending one is dropped.
+                    session.check(b"", Access::Write).map_err(cluster_only)?;
+                    let raft = self.raft.as_ref().ok_or_else(|| cluster_only(()))?;
+                    send_response!(raft.handle_append_entries(request)?)
+                }
+                Request::RequestVote(request) => {
+                    session.check(b"", Access::Write).map_err(cluster_only)?;
+                    let raft = self.raft.as_ref().ok_or_else(|| cluster_only(()))?;
+                    send_response!(raft.handle_request_vote(request)?)
+                }
+                Request::InstallSnapshot(request) => {
+                    session.check(b"", Access::Write).map_err(cluster_only)?;
+                    let raft = self.raft.as_ref().ok_or_else(|| cluster_only(()))?;
+                    send_response!(raft.handle_install_snapshot(request)?)
+                }
+                Request::Remove { key } => {
+                    send_response!(match session.check(&key, Access::Write) {
+                        Err(e) => RemoveResponse::Denied(e),
+                        Ok(_) => match self
+                            .write(WriteCommand::Remove { key })
+                            .and_then(Applied::into_seq)
+                        {
+                            Ok(seq) => RemoveResponse::Ok(seq),
+                            Err(KvStoreError::RedirectError(leader)) => {
+                                RemoveResponse::Redirect(leader)
+                            }
+                            Err(e) => RemoveResponse::Err(e.to_string()),
+                        },
+                    })
+                }
             }
         }
 
         Ok(())
     }
+
+    /// Private helper function to resolve where to write a backup, refusing
+    /// any destination that isn't a relative path within the backup directory.
+    fn backup_path(&self, dest: &str) -> Result<PathBuf> {
+        let dir = self.backup_dir.as_ref().ok_or_else(|| {
+            KvStoreError::StringError(
+                "Backups are disabled, start the server with --backup-dir".to_string(),
+            )
+        })?;
+
+        let dest = Path::new(dest);
+        let within = dest
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !within || dest.as_os_str().is_empty() {
+            return Err(KvStoreError::StringError(format!(
+                "The backup destination {} must be a relative path within the backup directory",
+                dest.display()
+            )));
+        }
+
+        Ok(dir.join(dest))
+    }
+
+    /// Private helper function to make a write, through the cluster's leader
+    /// if the server is a node of a cluster.
+    fn write(&self, command: WriteCommand) -> Result<Applied> {
+        match &self.raft {
+            Some(raft) => raft.propose(command),
+            None => command.apply(&self.engine),
+        }
+    }
+}
+
+/// Private helper function to return the error for a Raft request from a
+/// connection that isn't a node of the cluster.
+fn cluster_only<T>(_: T) -> KvStoreError {
+    KvStoreError::StringError("Raft requests are only accepted between cluster nodes".to_string())
 }
@@ -0,0 +1,237 @@
+//! Client-side sharding of keys across servers.
+//!
+//! Each server is placed on a hash ring at many points, its virtual nodes, and
+//! a key belongs to the server of the first point at or after the key's hash.
+//! Spreading each server over the ring keeps the share of keys per server
+//! even, and adding a server only moves the keys that now hash to it.
+
+use crate::engines::into_string;
+use crate::{KvStoreError, KvsClient, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// The number of points each server takes on the hash ring.
+const VIRTUAL_NODES: usize = 128;
+
+/// A client that spreads keys across servers by consistent hashing, with the
+/// same `get`, `set` and `remove` API as `KvsClient`, for `String` and binary
+/// keys alike.
+///
+/// # Adding a server
+///
+/// `add_node` puts a new server on the ring, then scans every other server
+/// for the keys that now belong to the new one. Each such key is set on the
+/// new server, keeping its TTL, and only then removed from the old one, so a
+/// key is never missing from both. Other clients must stop writing while keys
+/// migrate, and be given the new list of servers afterwards, since a write
+/// made through an old ring during the migration may be left on the wrong
+/// server.
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    clients: HashMap<String, KvsClient>,
+}
+
+impl ShardedKvsClient {
+    /// Connects to every server given their addresses.
+    pub fn connect(addrs: Vec<String>) -> Result<Self> {
+        if addrs.is_empty() {
+            return Err(KvStoreError::StringError(
+                "At least one server address is required".to_string(),
+            ));
+        }
+
+        let mut client = ShardedKvsClient {
+            ring: HashRing::default(),
+            clients: HashMap::new(),
+        };
+        for addr in addrs {
+            client.insert(addr)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Returns the address of the server a key belongs to.
+    pub fn node_for(&self, key: &str) -> &str {
+        self.node_for_bytes(key.as_bytes())
+    }
+
+    /// Returns the address of the server a binary key belongs to.
+    pub fn node_for_bytes(&self, key: &[u8]) -> &str {
+        self.ring.node_for(key)
+    }
+
+    /// Sets a key value pair at the server it belongs to, returning the
+    /// sequence number of the write at that server.
+    pub fn set(&mut self, key: String, value: String) -> Result<u64> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Sets a key value pair that expires after the `ttl` at the server it
+    /// belongs to, returning the sequence number of the write at that server.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<u64> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    /// Get a value according to a key from the server it belongs to.
+    ///
+    /// # Errors
+    ///
+    /// An error `KvStoreError::StringUtf8Error` is returned if the value isn't
+    /// UTF-8. Use `get_bytes` for binary values.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        into_string(self.get_bytes(key.as_bytes())?)
+    }
+
+    /// Removes a kv pair from the server it belongs to, returning the sequence
+    /// number of the write at that server.
+    pub fn remove(&mut self, key: String) -> Result<u64> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Sets a binary key value pair at the server it belongs to, returning
+    /// the sequence number of the write at that server.
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<u64> {
+        self.client_for(key).set_bytes(key, value)
+    }
+
+    /// Sets a binary key value pair that expires after the `ttl` at the
+    /// server it belongs to, returning the sequence number of the write at
+    /// that server.
+    pub fn set_bytes_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<u64> {
+        self.client_for(key).set_bytes_with_ttl(key, value, ttl)
+    }
+
+    /// Get a value according to a binary key from the server it belongs to.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.client_for(key).get_bytes(key)
+    }
+
+    /// Removes a binary key from the server it belongs to, returning the
+    /// sequence number of the write at that server.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<u64> {
+        self.client_for(key).remove_bytes(key)
+    }
+
+    /// Adds a server to the ring and moves the keys that now belong to it from
+    /// the other servers, returning the number of keys moved. See the type's
+    /// docs for how keys migrate.
+    pub fn add_node(&mut self, addr: String) -> Result<usize> {
+        self.insert(addr.clone())?;
+
+        let others: Vec<String> = self
+            .clients
+            .keys()
+            .filter(|other| **other != addr)
+            .cloned()
+            .collect();
+
+        let mut moved = 0;
+        for other in others {
+            let keys = self.client(&other).scan_bytes(b"")?;
+
+            for (key, value) in keys {
+                if self.ring.node_for(&key) != addr {
+                    continue;
+                }
+
+                // A key that expired or was removed since the scan is skipped.
+                let ttl = match self.client(&other).ttl_bytes(&key) {
+                    Ok(ttl) => ttl,
+                    Err(KvStoreError::KeyNotFoundError) => continue,
+                    Err(e) => return Err(e),
+                };
+                match ttl {
+                    Some(ttl) => self.client(&addr).set_bytes_with_ttl(&key, &value, ttl)?,
+                    None => self.client(&addr).set_bytes(&key, &value)?,
+                };
+                self.client(&other).remove_bytes(&key)?;
+                moved += 1;
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Private helper function to connect to a server and place it on the
+    /// ring.
+    fn insert(&mut self, addr: String) -> Result<()> {
+        if self.clients.contains_key(&addr) {
+            return Err(KvStoreError::StringError(format!(
+                "{} is already a server of the ring",
+                addr
+            )));
+        }
+
+        self.clients
+            .insert(addr.clone(), KvsClient::connect(addr.as_str())?);
+        self.ring.insert(addr);
+
+        Ok(())
+    }
+
+    /// Private helper function to return the client of the server a key
+    /// belongs to.
+    fn client_for(&mut self, key: &[u8]) -> &mut KvsClient {
+        let addr = self.node_for_bytes(key).to_string();
+        self.client(&addr)
+    }
+
+    /// Private helper function to return the client of a server on the ring.
+    fn client(&mut self, addr: &str) -> &mut KvsClient {
+        self.clients
+            .get_mut(addr)
+            .expect("every server on the ring has a client")
+    }
+}
+
+/// A consistent hash ring of server addresses.
+#[derive(Default)]
+pub(crate) struct HashRing {
+    /// The hash of every virtual node, and the address of its server.
+    points: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    /// Places a server on the ring at each of its virtual nodes.
+    pub(crate) fn insert(&mut self, addr: String) {
+        for i in 0..VIRTUAL_NODES {
+            let point = hash(format!("{}#{}", addr, i).as_bytes());
+            self.points.insert(point, addr.clone());
+        }
+    }
+
+    /// Returns the address of the server a key belongs to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ring is empty.
+    pub(crate) fn node_for(&self, key: &[u8]) -> &str {
+        let hash = hash(key);
+
+        // The ring wraps around past the last point to the first.
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, addr)| addr.as_str())
+            .expect("the ring has no servers")
+    }
+}
+
+/// Private helper function to hash bytes onto the ring, the same way on
+/// every client. FNV-1a, with a final mix so similar inputs such as the names
+/// of one server's virtual nodes land far apart.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,244 @@
+//! TLS support for the client and server using rustls. Certificates and keys
+//! are loaded from PEM files on disk, so no external CA is required.
+
+use crate::{KvStoreError, Result};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Builds the TLS config for a `KvsServer` from a PEM certificate chain and
+/// private key.
+///
+/// If `client_ca` is given, clients must present a certificate signed by one
+/// of the CA certificates in that file (mutual TLS).
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(ca)?), provider)
+                    .build()
+                    .map_err(|e| KvStoreError::StringError(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(
+        builder.with_single_cert(certs(cert)?, private_key(key)?)?,
+    ))
+}
+
+/// Builds the TLS config for a `KvsClient`, trusting the CA certificates in the
+/// PEM file `ca`.
+///
+/// `identity` is an optional certificate chain and private key pair, presented
+/// to servers that require mutual TLS.
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store(ca)?);
+
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(certs(cert)?, private_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Converts a host name or IP address into the name the server certificate is
+/// verified against.
+pub(crate) fn server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_owned()).map_err(|e| KvStoreError::StringError(e.to_string()))
+}
+
+/// Private helper function to read every certificate in a PEM file.
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+
+    if certs.is_empty() {
+        return Err(KvStoreError::StringError(format!(
+            "no certificates found in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certs)
+}
+
+/// Private helper function to read the first private key in a PEM file.
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+/// Private helper function to build a root store from the certificates in a
+/// PEM file.
+fn root_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> KvStoreError {
+    KvStoreError::StringError(format!("unable to read {}: {}", path.display(), err))
+}
+
+/// A TCP connection between the client and server, either plaintext or wrapped
+/// in TLS.
+///
+/// The TLS session can't be split into independent read and write halves like
+/// a `TcpStream`, so both halves share it behind a lock. The client and server
+/// only ever read and write in turn, so the lock is never contended.
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Tls(Arc<Mutex<TlsStream>>),
+}
+
+pub(crate) enum TlsStream {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl Stream {
+    /// Wraps an accepted connection in a server side TLS session.
+    pub(crate) fn server(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Stream> {
+        let conn = ServerConnection::new(config)?;
+        let tls = TlsStream::Server(StreamOwned::new(conn, stream));
+
+        Ok(Stream::Tls(Arc::new(Mutex::new(tls))))
+    }
+
+    /// Wraps an outgoing connection in a client side TLS session.
+    pub(crate) fn client(
+        stream: TcpStream,
+        name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> Result<Stream> {
+        let conn = ClientConnection::new(config, name)?;
+        let tls = TlsStream::Client(StreamOwned::new(conn, stream));
+
+        Ok(Stream::Tls(Arc::new(Mutex::new(tls))))
+    }
+
+    /// Creates a reference to the same connection that can be handled
+    /// independently.
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Plain(s) => Stream::Plain(s.try_clone()?),
+            Stream::Tls(s) => Stream::Tls(s.clone()),
+        })
+    }
+
+    /// Notifies the peer that no more data will be sent. For TLS this sends a
+    /// `close_notify` alert so the peer can tell a clean close from a
+    /// truncated one.
+    pub(crate) fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => Ok(()),
+            Stream::Tls(s) => {
+                let mut s = s.lock().expect("tls stream lock poisoned");
+                match &mut *s {
+                    TlsStream::Client(s) => s.conn.send_close_notify(),
+                    TlsStream::Server(s) => s.conn.send_close_notify(),
+                }
+                s.flush()
+            }
+        }
+    }
+
+    /// Returns whether the peer has sent anything or closed the connection,
+    /// without blocking or consuming it. For TLS this sees the encrypted
+    /// bytes, such as a `close_notify` alert, before they're read.
+    pub(crate) fn has_input(&self) -> io::Result<bool> {
+        let tcp = match self {
+            Stream::Plain(s) => s.try_clone()?,
+            Stream::Tls(s) => match &*s.lock().expect("tls stream lock poisoned") {
+                TlsStream::Client(s) => s.sock.try_clone()?,
+                TlsStream::Server(s) => s.sock.try_clone()?,
+            },
+        };
+
+        // The clone shares the socket, so it's only non-blocking for the peek.
+        tcp.set_nonblocking(true)?;
+        let peeked = tcp.peek(&mut [0; 1]);
+        tcp.set_nonblocking(false)?;
+        match peeked {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.lock().expect("tls stream lock poisoned").read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.lock().expect("tls stream lock poisoned").write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.lock().expect("tls stream lock poisoned").flush(),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Client(s) => s.read(buf),
+            TlsStream::Server(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Client(s) => s.write(buf),
+            TlsStream::Server(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Client(s) => s.flush(),
+            TlsStream::Server(s) => s.flush(),
+        }
+    }
+}
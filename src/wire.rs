@@ -0,0 +1,119 @@
+//! The formats messages are sent in over a connection.
+//!
+//! Every connection starts out in JSON. A client may then send a `Format`
+//! request, and once the server answers it in JSON both sides switch to the
+//! format agreed. MessagePack carries keys and values as raw bytes, where
+//! JSON has to spell out a binary value in base64.
+
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+/// A format messages are sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WireFormat {
+    Json,
+    MessagePack,
+}
+
+/// Reads messages in the connection's format.
+pub(crate) struct WireReader<R> {
+    reader: BufReader<R>,
+    format: WireFormat,
+}
+
+impl<R: Read> WireReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        WireReader {
+            reader: BufReader::new(reader),
+            format: WireFormat::Json,
+        }
+    }
+
+    pub(crate) fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    pub(crate) fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Reads the next message.
+    ///
+    /// Reads stop at the end of the message, so the format may change between
+    /// messages. A JSON message is an object or a string, which ends without
+    /// reading past it.
+    pub(crate) fn read<T: DeserializeOwned>(&mut self) -> Result<T> {
+        match self.format {
+            WireFormat::Json => {
+                let mut de = serde_json::Deserializer::from_reader(&mut self.reader);
+                Ok(T::deserialize(&mut de)?)
+            }
+            WireFormat::MessagePack => Ok(rmp_serde::from_read(&mut self.reader)?),
+        }
+    }
+
+    /// Reads the next message, or `None` if the connection was closed between
+    /// messages.
+    pub(crate) fn next<T: DeserializeOwned>(&mut self) -> Option<Result<T>> {
+        if self.format == WireFormat::Json {
+            // JSON messages may be separated by whitespace.
+            loop {
+                let buf = match self.reader.fill_buf() {
+                    Ok(buf) => buf,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let space = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+                let more = space > 0 && space == buf.len();
+                self.reader.consume(space);
+                if !more {
+                    break;
+                }
+            }
+        }
+
+        match self.reader.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => Some(self.read()),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// Writes messages in the connection's format. Messages are buffered until
+/// flushed.
+pub(crate) struct WireWriter<W: Write> {
+    writer: BufWriter<W>,
+    format: WireFormat,
+}
+
+impl<W: Write> WireWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        WireWriter {
+            writer: BufWriter::new(writer),
+            format: WireFormat::Json,
+        }
+    }
+
+    pub(crate) fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    pub(crate) fn write<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        match self.format {
+            WireFormat::Json => serde_json::to_writer(&mut self.writer, message)?,
+            WireFormat::MessagePack => rmp_serde::encode::write_named(&mut self.writer, message)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub(crate) fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
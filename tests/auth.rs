@@ -0,0 +1,152 @@
+mod common;
+
+use kvs::{Credential, KvStoreError, KvsClient, Result, WriteBatch};
+use std::net::SocketAddr;
+use tempfile::TempDir;
+
+const ACL: &str = r#"{
+  "users": [
+    {
+      "name": "admin",
+      "password": "hunter2",
+      "grants": [{ "prefix": "", "read": true, "write": true }]
+    },
+    {
+      "name": "cache",
+      "tokens": ["8f14e45fceea167a"],
+      "grants": [
+        { "prefix": "cache/", "read": true, "write": true },
+        { "prefix": "config/", "read": true }
+      ]
+    }
+  ]
+}"#;
+
+// Starts a server with the ACL on a free port in the background.
+fn start_server() -> (SocketAddr, TempDir) {
+    common::start_server_with_acl(ACL)
+}
+
+fn is_denied<T>(res: Result<T>) -> bool {
+    matches!(res, Err(KvStoreError::PermissionDeniedError(_)))
+}
+
+// Should deny every request until the connection authenticates.
+#[test]
+fn unauthenticated_requests_denied() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+
+    assert!(is_denied(
+        client.set("key1".to_owned(), "value1".to_owned())
+    ));
+    assert!(is_denied(client.get("key1".to_owned())));
+    assert!(is_denied(client.remove("key1".to_owned())));
+
+    Ok(())
+}
+
+// Should only report the server's status to an authenticated user, whatever
+// their grants.
+#[test]
+fn status_requires_authentication() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+    assert!(is_denied(client.status()));
+
+    client.authenticate(
+        "cache".to_owned(),
+        Credential::Token("8f14e45fceea167a".to_owned()),
+    )?;
+    assert_eq!(client.status()?.seq, 0);
+
+    Ok(())
+}
+
+// Should reject an unknown user or a wrong secret.
+#[test]
+fn invalid_credentials() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+
+    let attempts = vec![
+        ("admin", Credential::Password("wrong".to_owned())),
+        ("admin", Credential::Token("hunter2".to_owned())),
+        ("cache", Credential::Password("8f14e45fceea167a".to_owned())),
+        ("nobody", Credential::Password("hunter2".to_owned())),
+    ];
+    for (user, credential) in attempts {
+        match client.authenticate(user.to_owned(), credential) {
+            Err(KvStoreError::AuthenticationError(_)) => {}
+            res => panic!("expected an authentication error, got {:?}", res),
+        }
+    }
+
+    // A failed attempt leaves the connection unauthenticated.
+    assert!(is_denied(client.get("key1".to_owned())));
+
+    Ok(())
+}
+
+// Should allow a user to access only the key prefixes they were granted.
+#[test]
+fn grants_by_prefix() -> Result<()> {
+    let (addr, _data) = start_server();
+
+    let mut admin = KvsClient::connect(addr)?;
+    admin.authenticate(
+        "admin".to_owned(),
+        Credential::Password("hunter2".to_owned()),
+    )?;
+    admin.set("config/ttl".to_owned(), "30".to_owned())?;
+    admin.set("users/1".to_owned(), "alice".to_owned())?;
+    drop(admin);
+
+    let mut cache = KvsClient::connect(addr)?;
+    cache.authenticate(
+        "cache".to_owned(),
+        Credential::Token("8f14e45fceea167a".to_owned()),
+    )?;
+
+    cache.set("cache/a".to_owned(), "1".to_owned())?;
+    assert_eq!(cache.get("cache/a".to_owned())?, Some("1".to_owned()));
+    cache.remove("cache/a".to_owned())?;
+
+    assert_eq!(cache.get("config/ttl".to_owned())?, Some("30".to_owned()));
+    assert!(is_denied(
+        cache.set("config/ttl".to_owned(), "0".to_owned())
+    ));
+    assert!(is_denied(cache.remove("config/ttl".to_owned())));
+
+    assert!(is_denied(cache.get("users/1".to_owned())));
+    assert!(is_denied(cache.set("users/2".to_owned(), "bob".to_owned())));
+
+    Ok(())
+}
+
+// Should deny a whole batch if the user may not write any one of its keys.
+#[test]
+fn batch_denied_by_any_key() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut cache = KvsClient::connect(addr)?;
+    cache.authenticate(
+        "cache".to_owned(),
+        Credential::Token("8f14e45fceea167a".to_owned()),
+    )?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("cache/a".to_owned(), "1".to_owned())
+        .set("config/ttl".to_owned(), "0".to_owned());
+    assert!(is_denied(cache.write_batch(batch)));
+    assert!(cache.get("cache/a".to_owned()).is_err());
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("cache/a".to_owned(), "1".to_owned())
+        .set("cache/b".to_owned(), "2".to_owned());
+    cache.write_batch(batch)?;
+    assert_eq!(cache.get("cache/b".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
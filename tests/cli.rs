@@ -0,0 +1,277 @@
+mod common;
+
+use common::start_server;
+use serde_json::Value;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+const ACL: &str = r#"{
+  "users": [
+    {
+      "name": "admin",
+      "password": "hunter2",
+      "grants": [{ "prefix": "", "read": true, "write": true }]
+    }
+  ]
+}"#;
+
+// Starts a server only letting the user admin, with the password hunter2, in.
+fn start_server_with_acl() -> (SocketAddr, TempDir) {
+    common::start_server_with_acl(ACL)
+}
+
+// Runs kvs-client with the arguments, writing `stdin` to it.
+fn kvs_client(args: &[&str], home: &TempDir, stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kvs-client"))
+        .args(args)
+        .env("HOME", home.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("unable to run kvs-client");
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Should run every command of a shell session over one connection, keeping
+// the session alive after an error and saving the history.
+#[test]
+fn shell() {
+    let (addr, _data) = start_server();
+    let home = TempDir::new().expect("unable to create temporary working directory");
+
+    let output = kvs_client(
+        &["shell", "--addr", &addr.to_string()],
+        &home,
+        "set key1 \"a value\"\nget key1\nbogus\nrm key1\nget key1\nexit\n",
+    );
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let results: Vec<&str> = stdout
+        .lines()
+        .filter(|line| !line.ends_with("ms)"))
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            "OK, seq 1",
+            "a value",
+            "error: Unknown command bogus, see help",
+            "OK, seq 2",
+            "Key not found",
+        ]
+    );
+
+    let history = std::fs::read_to_string(home.path().join(".kvs_history")).unwrap();
+    assert!(history.contains("get key1"));
+}
+
+// Should print a result line per command of a batch, in order, and exit
+// non-zero if any failed.
+#[test]
+fn batch() {
+    let (addr, _data) = start_server();
+    let home = TempDir::new().expect("unable to create temporary working directory");
+    let addr = addr.to_string();
+
+    let mut commands: String = (0..300)
+        .map(|i| format!("set key{} value{}\n", i, i))
+        .collect();
+    commands.push_str("get key299\n{\"Get\":{\"key\":\"key0\"}}\n");
+    commands.push_str("{\"Set\":{\"key\":\"bin\",\"value\":{\"base64\":\"AP4=\"}}}\nget bin\n");
+    let output = kvs_client(&["batch", "--addr", &addr], &home, &commands);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let results: Vec<&str> = stdout.lines().collect();
+    assert_eq!(results.len(), 304);
+    assert_eq!(results[0], "OK, seq 1");
+    assert_eq!(results[299], "OK, seq 300");
+    assert_eq!(results[300], "value299");
+    assert_eq!(results[301], "{\"Ok\":\"value0\"}");
+    assert_eq!(results[303], "{\"base64\":\"AP4=\"}");
+
+    let output = kvs_client(
+        &["batch", "--addr", &addr],
+        &home,
+        "rm missing\nbogus\nget key1\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        vec![
+            "error: Key not found",
+            "error: Unknown command or bad arguments: bogus",
+            "value1",
+        ]
+    );
+}
+
+// Should print a server's failure to read a key in a batch as an error, not
+// as a missing key.
+#[test]
+fn batch_server_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        let mut stream = listener.incoming().next().unwrap().unwrap();
+        let requests = serde_json::Deserializer::from_reader(stream.try_clone().unwrap());
+        for request in requests.into_iter::<Value>() {
+            let reply = match request.unwrap().get("Format") {
+                Some(_) => &br#"{"Err":"JSON only"}"#[..],
+                None => br#"{"Err":"Log record at offset 0 is corrupt"}"#,
+            };
+            stream.write_all(reply).unwrap();
+        }
+    });
+    let home = TempDir::new().expect("unable to create temporary working directory");
+
+    let output = kvs_client(&["batch", "--addr", &addr], &home, "get key1\nttl key1\n");
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        vec![
+            "error: Log record at offset 0 is corrupt",
+            "error: Log record at offset 0 is corrupt",
+        ]
+    );
+}
+
+// Should exit with the documented code for each kind of failure.
+#[test]
+fn exit_codes() {
+    let (addr, data) = start_server();
+    let home = TempDir::new().expect("unable to create temporary working directory");
+    let addr = addr.to_string();
+
+    let output = kvs_client(&["set", "key1", "value1", "--addr", &addr], &home, "");
+    assert!(output.status.success());
+    let output = kvs_client(&["get", "key1", "--addr", &addr], &home, "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "value1\n");
+
+    for args in [
+        vec!["get", "missing"],
+        vec!["rm", "missing"],
+        vec!["ttl", "missing"],
+    ] {
+        let output = kvs_client(&[&args[..], &["--addr", &addr]].concat(), &home, "");
+        assert_eq!(output.status.code(), Some(2));
+        assert!(output.stdout.is_empty());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "error: Key not found\n"
+        );
+    }
+
+    // Nothing listens on the address once the listener is dropped.
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let output = kvs_client(&["get", "key1", "--addr", &closed.to_string()], &home, "");
+    assert_eq!(output.status.code(), Some(3));
+
+    // The server refuses backups without a backup directory.
+    let dest = data.path().to_str().unwrap();
+    let output = kvs_client(&["backup", dest, "--addr", &addr], &home, "");
+    assert_eq!(output.status.code(), Some(4));
+
+    let (acl_addr, _acl_data) = start_server_with_acl();
+    let acl_addr = acl_addr.to_string();
+    let output = kvs_client(&["get", "key1", "--addr", &acl_addr], &home, "");
+    assert_eq!(output.status.code(), Some(5));
+    let output = kvs_client(
+        &[
+            "get",
+            "key1",
+            "--addr",
+            &acl_addr,
+            "--user",
+            "admin",
+            "--password",
+            "wrong",
+        ],
+        &home,
+        "",
+    );
+    assert_eq!(output.status.code(), Some(5));
+
+    let output = kvs_client(
+        &["get", "key1", "--addr", &addr, "--user", "admin"],
+        &home,
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+}
+
+// Should print results as JSON on stdout, and errors as JSON on stderr, with
+// --output json.
+#[test]
+fn json_output() {
+    let (addr, _data) = start_server();
+    let home = TempDir::new().expect("unable to create temporary working directory");
+    let addr = addr.to_string();
+    let json = |args: &[&str]| {
+        let output = kvs_client(
+            &[args, &["--addr", &addr, "--output", "json"]].concat(),
+            &home,
+            "",
+        );
+        let stdout: Option<Value> = serde_json::from_slice(&output.stdout).ok();
+        let stderr: Option<Value> = serde_json::from_slice(&output.stderr).ok();
+        (output.status.code(), stdout, stderr)
+    };
+
+    let (code, stdout, _) = json(&["set", "key1", "value1", "--ttl", "1h"]);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout.unwrap()["seq"], 1);
+
+    let (code, stdout, _) = json(&["get", "key1"]);
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        stdout.unwrap(),
+        serde_json::json!({ "key": "key1", "value": "value1" })
+    );
+
+    let (_, stdout, _) = json(&["ttl", "key1"]);
+    assert!(stdout.unwrap()["ttl_ms"].as_u64().unwrap() > 3_500_000);
+
+    let (_, stdout, _) = json(&["status"]);
+    assert_eq!(stdout.unwrap()["seq"], 1);
+
+    let (code, stdout, stderr) = json(&["get", "missing"]);
+    assert_eq!(code, Some(2));
+    assert_eq!(stdout, None);
+    assert_eq!(
+        stderr.unwrap(),
+        serde_json::json!({ "error": "not_found", "message": "Key not found" })
+    );
+
+    let output = kvs_client(
+        &["batch", "--addr", &addr, "--output", "json"],
+        &home,
+        "get key1\nbogus\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        vec![
+            "{\"Ok\":\"value1\"}",
+            "{\"Err\":\"Unknown command or bad arguments: bogus\"}",
+        ]
+    );
+}
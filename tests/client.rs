@@ -0,0 +1,419 @@
+mod common;
+
+use common::start_server;
+use kvs::{
+    BincodeCodec, CasOutcome, ExportFormat, JsonCodec, KvStore, KvStoreError, KvsClient, KvsEngine,
+    Reply, Result,
+};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should report whether a conditional write was made, with the current value
+// on a mismatch.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+
+    assert_eq!(
+        client.set_if_absent("key1".to_owned(), "value1".to_owned())?,
+        CasOutcome::Swapped(1)
+    );
+    assert_eq!(
+        client.set_if_absent("key1".to_owned(), "value2".to_owned())?,
+        CasOutcome::Mismatch(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        client.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned())
+        )?,
+        CasOutcome::Swapped(2)
+    );
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should serve clients concurrently without losing any of their
+// read-modify-writes.
+#[test]
+fn concurrent_clients_compare_and_swap() -> Result<()> {
+    let (addr, _data) = start_server();
+    KvsClient::connect(addr)?.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                for _ in 0..25 {
+                    let mut current = client.get("counter".to_owned())?;
+                    loop {
+                        let n: u64 = current.as_ref().unwrap().parse().unwrap();
+                        match client.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some((n + 1).to_string()),
+                        )? {
+                            CasOutcome::Swapped(_) => break,
+                            CasOutcome::Mismatch(actual) => current = actual,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("counter".to_owned())?, Some("100".to_owned()));
+
+    Ok(())
+}
+
+// Should commit a transaction at the server, and abort it if a key it read
+// was written by another client first.
+#[test]
+fn transaction() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    client.set("from".to_owned(), "100".to_owned())?;
+
+    let mut txn = client.begin();
+    assert_eq!(txn.get("from".to_owned())?, Some("100".to_owned()));
+    txn.set("from".to_owned(), "90".to_owned());
+    txn.set("to".to_owned(), "10".to_owned());
+    txn.commit()?;
+    assert_eq!(other.get("to".to_owned())?, Some("10".to_owned()));
+
+    let mut txn = client.begin();
+    txn.get("from".to_owned())?;
+    txn.set("to".to_owned(), "20".to_owned());
+    other.set("from".to_owned(), "0".to_owned())?;
+    match txn.commit() {
+        Err(KvStoreError::TransactionConflictError(key)) => assert_eq!(key, "from"),
+        res => panic!("expected a transaction conflict, got {:?}", res),
+    }
+    assert_eq!(client.get("to".to_owned())?, Some("10".to_owned()));
+
+    Ok(())
+}
+
+// Should acknowledge every write with its sequence number.
+#[test]
+fn write_acks_sequence_numbers() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+
+    assert_eq!(client.set("key1".to_owned(), "value1".to_owned())?, 1);
+    assert_eq!(client.set("key2".to_owned(), "value2".to_owned())?, 2);
+    assert_eq!(client.remove("key1".to_owned())?, 3);
+
+    let mut txn = client.begin();
+    txn.set("key3".to_owned(), "value3".to_owned());
+    txn.set("key4".to_owned(), "value4".to_owned());
+    assert_eq!(txn.commit()?, 5);
+
+    Ok(())
+}
+
+// Should stream writes made by other clients to a watching client.
+#[test]
+fn watch() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+    client.set("cache/a".to_owned(), "1".to_owned())?;
+
+    let mut watch = KvsClient::connect(addr)?.watch("cache/".to_owned(), Some(1))?;
+    client.set("config/ttl".to_owned(), "30".to_owned())?;
+    client.remove("cache/a".to_owned())?;
+
+    let change = watch.next().unwrap()?;
+    assert_eq!(
+        (change.seq, change.key, change.value),
+        (1, b"cache/a".to_vec(), Some(b"1".to_vec()))
+    );
+    let change = watch.next().unwrap()?;
+    assert_eq!(
+        (change.seq, change.key, change.value),
+        (3, b"cache/a".to_vec(), None)
+    );
+
+    Ok(())
+}
+
+// Should back the server's data up while it serves, into a backup a new data
+// directory can be restored from.
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let (addr, data) =
+        common::start_server_with(|server, _| server.with_backup_dir(backup_dir.path().into()));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let seq = client.set("key2".to_owned(), "value2".to_owned())?;
+
+    let manifest = client.backup("backup".to_owned())?;
+    assert_eq!(manifest.seq, seq);
+    assert_eq!(manifest.files[0].name, "log.txt");
+    client.set("key3".to_owned(), "value3".to_owned())?;
+
+    // A backup is never written over another, nor outside the backup
+    // directory.
+    assert!(client.backup("backup".to_owned()).is_err());
+    for dest in ["../escaped", "nested/../../escaped", ""] {
+        assert!(client.backup(dest.to_owned()).is_err());
+    }
+    let outside = data.path().join("backup");
+    assert!(client
+        .backup(outside.to_string_lossy().into_owned())
+        .is_err());
+    assert!(!outside.exists());
+    assert!(!backup_dir.path().parent().unwrap().join("escaped").exists());
+
+    let dest = backup_dir.path().join("backup");
+
+    let restored = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::restore_from(&dest, restored.path())?;
+    assert!(KvStore::restore_from(&dest, restored.path()).is_err());
+
+    let store = KvStore::open(restored.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.get("key3".to_owned()).is_err());
+    assert_eq!(store.last_seq()?, seq);
+
+    Ok(())
+}
+
+// Should import an export from one server into another.
+#[test]
+fn export_and_import() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..1500 {
+        client.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let mut export = Vec::new();
+    assert_eq!(
+        client.export("".to_owned(), ExportFormat::Csv, &mut export)?,
+        1500
+    );
+
+    let (other_addr, _other_data) = start_server();
+    let mut other = KvsClient::connect(other_addr)?;
+    assert_eq!(
+        other.import("".to_owned(), ExportFormat::Csv, &export[..])?,
+        1500
+    );
+    assert_eq!(other.scan("".to_owned())?, client.scan("".to_owned())?);
+
+    Ok(())
+}
+
+// Should read the responses to raw requests sent back to back in order, and
+// refuse an invalid request without sending it.
+#[test]
+fn raw_requests() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+
+    client.send_raw(r#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    client.send_raw(r#"{"Get":{"key":"key1"}}"#)?;
+    assert!(client.send_raw(r#"{"Get":{}}"#).is_err());
+    assert!(client
+        .send_raw(r#"{"Watch":{"prefix":"","from":null}}"#)
+        .is_err());
+    client.send_raw(r#"{"Get":{"key":"missing"}}"#)?;
+
+    assert_eq!(client.receive_raw()?, serde_json::json!({ "Ok": 1 }));
+    assert_eq!(client.receive_raw()?, serde_json::json!({ "Ok": "value1" }));
+    assert_eq!(
+        client.receive_raw()?,
+        serde_json::json!({ "Err": "Key not found" })
+    );
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should send binary keys and values to the server as they are, showing them
+// in base64 in raw responses.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+    let value = [0x89, b'P', b'N', b'G', 0x00, 0xfe];
+
+    assert_eq!(client.set_bytes(&[0xff, 0x00], &value)?, 1);
+    assert_eq!(client.get_bytes(&[0xff, 0x00])?, Some(value.to_vec()));
+    assert_eq!(
+        client.scan_bytes(&[0xff])?,
+        vec![(vec![0xff, 0x00], value.to_vec())]
+    );
+    assert!(client.get_bytes(b"missing").is_err());
+
+    client.send_raw(r#"{"Get":{"key":{"base64":"/wA="}}}"#)?;
+    assert_eq!(
+        client.receive_raw()?,
+        serde_json::json!({ "Ok": { "base64": "iVBORwD+" } })
+    );
+    // Keys written as arrays of numbers are still read.
+    client.send_raw(r#"{"Get":{"key":[255,0]}}"#)?;
+    assert_eq!(
+        client.receive_raw()?,
+        serde_json::json!({ "Ok": { "base64": "iVBORwD+" } })
+    );
+
+    assert_eq!(client.remove_bytes(&[0xff, 0x00])?, 2);
+    assert_eq!(client.scan("".to_owned())?, vec![]);
+
+    // Conditional writes and transactions compare binary values too.
+    client.set_bytes(b"image", &value)?;
+    assert_eq!(
+        client.set_if_absent_bytes(b"image", b"other")?,
+        CasOutcome::Mismatch(Some(value.to_vec()))
+    );
+    assert_eq!(
+        client.compare_and_swap_bytes(b"image", Some(&value), Some(&[0xfe]))?,
+        CasOutcome::Swapped(4)
+    );
+    let mut txn = client.begin();
+    assert_eq!(txn.get_bytes(b"image")?, Some(vec![0xfe]));
+    txn.set_bytes(b"image", &[0xfd]);
+    assert_eq!(txn.commit()?, 5);
+    assert_eq!(client.get_bytes(b"image")?, Some(vec![0xfd]));
+
+    Ok(())
+}
+
+// Should tell a missing key from a server failing to read one.
+#[test]
+fn get_errors() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut stream = listener.incoming().next().unwrap().unwrap();
+        let requests = serde_json::Deserializer::from_reader(stream.try_clone().unwrap());
+        let mut requests = requests.into_iter::<serde_json::Value>();
+        if requests.next().is_some() {
+            stream.write_all(br#"{"Err":"JSON only"}"#).unwrap();
+        }
+        for reply in [
+            &br#"{"Err":"Key not found"}"#[..],
+            br#"{"Err":"Log record at offset 0 is corrupt"}"#,
+            br#"{"Err":"Log record at offset 0 is corrupt"}"#,
+        ] {
+            requests.next();
+            stream.write_all(reply).unwrap();
+        }
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvStoreError::KeyNotFoundError)
+    ));
+    match client.get("key1".to_owned()) {
+        Err(KvStoreError::StringError(e)) => assert!(e.contains("corrupt")),
+        res => panic!("expected a server error, got {:?}", res),
+    }
+    assert!(matches!(
+        client.ttl("key1".to_owned()),
+        Err(KvStoreError::StringError(_))
+    ));
+
+    Ok(())
+}
+
+// Should keep a key set through the server with a ttl too long to represent,
+// rather than overflow its expiry.
+#[test]
+fn huge_ttl() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(u64::MAX),
+    )?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.ttl("key1".to_owned())?.is_some());
+
+    Ok(())
+}
+
+// Should read back typed values set through the server with a codec.
+#[test]
+fn typed_values() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+    let mut scores = BTreeMap::new();
+    scores.insert("ada".to_owned(), 36u64);
+    scores.insert("grace".to_owned(), 85);
+
+    client.set_typed("scores".to_owned(), &scores, BincodeCodec)?;
+    assert_eq!(
+        client.get_typed("scores".to_owned(), BincodeCodec)?,
+        Some(scores)
+    );
+    match client.get_typed::<BTreeMap<String, u64>, _>("scores".to_owned(), JsonCodec) {
+        Err(KvStoreError::DecodeError(_)) => {}
+        res => panic!("expected a decode error, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// Should gather the results of more pipelined requests than fit in the window
+// in order, failing a rejected request on its own.
+#[test]
+fn pipeline() -> Result<()> {
+    let (addr, _data) = start_server();
+    let mut client = KvsClient::connect(addr)?;
+
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    pipeline.get("key999".to_owned())?;
+    pipeline.get("missing".to_owned())?;
+    pipeline.remove("key0".to_owned())?;
+    pipeline.ttl("key1".to_owned())?;
+    assert_eq!(pipeline.len(), 1004);
+
+    let replies = pipeline.finish()?;
+    assert_eq!(replies.len(), 1004);
+    for (i, reply) in replies[..1000].iter().enumerate() {
+        assert_eq!(reply.as_ref().unwrap(), &Reply::Written(i as u64 + 1));
+    }
+    assert_eq!(
+        replies[1000].as_ref().unwrap(),
+        &Reply::Value(Some("value999".to_owned()))
+    );
+    assert!(matches!(replies[1001], Err(KvStoreError::KeyNotFoundError)));
+    assert_eq!(replies[1002].as_ref().unwrap(), &Reply::Written(1001));
+    assert_eq!(replies[1003].as_ref().unwrap(), &Reply::Ttl(None));
+
+    // Dropping a pipeline reads its responses, so the connection stays in
+    // step.
+    let mut pipeline = client.pipeline();
+    pipeline.set("key1".to_owned(), "changed".to_owned())?;
+    pipeline.get("key2".to_owned())?;
+    drop(pipeline);
+    assert_eq!(client.get("key1".to_owned())?, Some("changed".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,359 @@
+use kvs::{Credential, KvStoreError, KvsClient, Result, Role};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// How long to wait for a cluster to elect a leader or apply a write.
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+// The ACL of a cluster whose nodes authenticate to each other as "node".
+const ACL: &str = r#"{
+  "users": [
+    {
+      "name": "node",
+      "password": "hunter2",
+      "grants": [{ "prefix": "", "read": true, "write": true }]
+    },
+    {
+      "name": "app",
+      "password": "letmein",
+      "grants": [{ "prefix": "app/", "read": true, "write": true }]
+    }
+  ]
+}"#;
+
+// A kvs-server process running as a node of a cluster, killed when dropped.
+struct Node {
+    addr: SocketAddr,
+    cluster: String,
+    args: Vec<String>,
+    credential: Option<(String, Credential)>,
+    dir: TempDir,
+    process: Option<Child>,
+}
+
+impl Node {
+    fn start(&mut self) {
+        let process = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+            .args(["--addr", &self.addr.to_string(), "--cluster", &self.cluster])
+            .args(&self.args)
+            .current_dir(self.dir.path())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("unable to start kvs-server");
+        self.process = Some(process);
+    }
+
+    fn kill(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            process.kill().expect("unable to kill kvs-server");
+            process.wait().unwrap();
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.process.is_some()
+    }
+
+    // Connects to the node, authenticating if the cluster has an ACL.
+    fn connect(&self) -> Result<KvsClient> {
+        let mut client = KvsClient::connect(self.addr)?;
+        if let Some((user, credential)) = &self.credential {
+            client.authenticate(user.clone(), credential.clone())?;
+        }
+        Ok(client)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// Starts a cluster of `size` nodes on free ports, each in its own directory.
+fn start_cluster(size: usize) -> Vec<Node> {
+    start_cluster_with(size, &[], None)
+}
+
+// Starts a cluster of nodes requiring the ACL, authenticating to each other
+// and to the tests as "node".
+fn start_cluster_with_acl(size: usize) -> Vec<Node> {
+    let args = [
+        "--acl",
+        "acl.json",
+        "--peer-user",
+        "node",
+        "--peer-password",
+        "hunter2",
+    ];
+    let credential = (
+        "node".to_owned(),
+        Credential::Password("hunter2".to_owned()),
+    );
+    start_cluster_with(size, &args, Some(credential))
+}
+
+// Starts a cluster of `size` nodes with the extra arguments, each in its own
+// directory holding the ACL.
+fn start_cluster_with(
+    size: usize,
+    args: &[&str],
+    credential: Option<(String, Credential)>,
+) -> Vec<Node> {
+    let addrs: Vec<SocketAddr> = (0..size)
+        .map(|_| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        })
+        .collect();
+    let cluster = addrs
+        .iter()
+        .map(SocketAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    addrs
+        .into_iter()
+        .map(|addr| {
+            let mut node = Node {
+                addr,
+                cluster: cluster.clone(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                credential: credential.clone(),
+                dir: TempDir::new().expect("unable to create temporary working directory"),
+                process: None,
+            };
+            fs::write(node.dir.path().join("acl.json"), ACL).unwrap();
+            node.start();
+            node
+        })
+        .collect()
+}
+
+// Waits until a running node is the leader, and returns its index.
+fn wait_for_leader(nodes: &[Node]) -> usize {
+    let deadline = Instant::now() + TIMEOUT;
+
+    loop {
+        assert!(Instant::now() < deadline, "no leader was elected");
+
+        for (i, node) in nodes.iter().enumerate().filter(|(_, n)| n.is_running()) {
+            let status = node.connect().and_then(|mut c| c.status());
+            if let Ok(status) = status {
+                if status.cluster.unwrap().role == Role::Leader {
+                    return i;
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Sets the key through the cluster's leader, retrying while it's elected.
+fn set(nodes: &[Node], key: &str, value: &str) -> Result<u64> {
+    let deadline = Instant::now() + TIMEOUT;
+
+    loop {
+        let leader = wait_for_leader(nodes);
+        let mut client = nodes[leader].connect()?;
+        match client.set(key.to_owned(), value.to_owned()) {
+            Ok(seq) => return Ok(seq),
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Waits until the node has applied the key's value.
+fn wait_for_value(node: &Node, key: &str, value: &str) -> Result<()> {
+    let deadline = Instant::now() + TIMEOUT;
+
+    loop {
+        let got = node.connect().and_then(|mut c| c.get(key.to_owned()));
+        if let Ok(Some(got)) = got {
+            if got == value {
+                return Ok(());
+            }
+        }
+
+        assert!(
+            Instant::now() < deadline,
+            "{} didn't apply {}",
+            node.addr,
+            key
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Should commit writes made through the leader on every node, and redirect
+// writes sent to a follower to the leader.
+#[test]
+fn cluster_replicates_writes() -> Result<()> {
+    let nodes = start_cluster(3);
+    set(&nodes, "key1", "value1")?;
+    set(&nodes, "key2", "value2")?;
+    for node in &nodes {
+        wait_for_value(node, "key1", "value1")?;
+        wait_for_value(node, "key2", "value2")?;
+    }
+
+    let leader = wait_for_leader(&nodes);
+    let follower = &nodes[(leader + 1) % nodes.len()];
+    let mut client = follower.connect()?;
+    match client.set("key3".to_owned(), "value3".to_owned()) {
+        Err(KvStoreError::RedirectError(addr)) => {
+            assert_eq!(addr, nodes[leader].addr.to_string())
+        }
+        res => panic!("expected a redirect, got {:?}", res),
+    }
+
+    let status = client.status()?.cluster.unwrap();
+    assert_eq!(status.role, Role::Follower);
+    assert_eq!(status.leader, Some(nodes[leader].addr.to_string()));
+
+    Ok(())
+}
+
+// Should elect a new leader when the leader is killed, and catch a restarted
+// node up on the writes it missed.
+#[test]
+fn cluster_fails_over() -> Result<()> {
+    let mut nodes = start_cluster(3);
+    set(&nodes, "key1", "value1")?;
+
+    let old_leader = wait_for_leader(&nodes);
+    nodes[old_leader].kill();
+
+    let new_leader = wait_for_leader(&nodes);
+    assert_ne!(new_leader, old_leader);
+    set(&nodes, "key2", "value2")?;
+    for node in nodes.iter().filter(|n| n.is_running()) {
+        wait_for_value(node, "key1", "value1")?;
+        wait_for_value(node, "key2", "value2")?;
+    }
+
+    nodes[old_leader].start();
+    wait_for_value(&nodes[old_leader], "key1", "value1")?;
+    wait_for_value(&nodes[old_leader], "key2", "value2")?;
+
+    Ok(())
+}
+
+// Should keep committing writes in a cluster of five while two nodes are down.
+#[test]
+fn cluster_tolerates_minority_failures() -> Result<()> {
+    let mut nodes = start_cluster(5);
+    set(&nodes, "key1", "value1")?;
+
+    let leader = wait_for_leader(&nodes);
+    nodes[leader].kill();
+    nodes[(leader + 1) % 5].kill();
+
+    set(&nodes, "key2", "value2")?;
+    for node in nodes.iter().filter(|n| n.is_running()) {
+        wait_for_value(node, "key2", "value2")?;
+    }
+
+    for node in nodes.iter_mut().filter(|n| !n.is_running()) {
+        node.start();
+    }
+    for node in &nodes {
+        wait_for_value(node, "key1", "value1")?;
+        wait_for_value(node, "key2", "value2")?;
+    }
+
+    Ok(())
+}
+
+// Should snapshot the log every 1000 entries, catch a node that missed the
+// snapshotted entries up by sending it the snapshot, and restore a restarted
+// node from its snapshot.
+#[test]
+fn cluster_compacts_log_into_snapshots() -> Result<()> {
+    let mut nodes = start_cluster(3);
+    set(&nodes, "key0", "value0")?;
+
+    let leader = wait_for_leader(&nodes);
+    let follower = (leader + 1) % nodes.len();
+    nodes[follower].kill();
+
+    let mut client = nodes[leader].connect()?;
+    for i in 1..=1100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(nodes[leader].dir.path().join("raft-snapshot.json").exists());
+
+    nodes[follower].start();
+    wait_for_value(&nodes[follower], "key1100", "value1100")?;
+    wait_for_value(&nodes[follower], "key0", "value0")?;
+    wait_for_value(&nodes[follower], "key500", "value500")?;
+    assert!(nodes[follower]
+        .dir
+        .path()
+        .join("raft-snapshot.json")
+        .exists());
+
+    nodes[leader].kill();
+    nodes[leader].start();
+    wait_for_value(&nodes[leader], "key0", "value0")?;
+    wait_for_value(&nodes[leader], "key1100", "value1100")?;
+
+    Ok(())
+}
+
+// Should replicate writes between nodes that authenticate to each other, and
+// drop Raft requests from connections of any other user.
+#[test]
+fn cluster_authenticates_peers() -> Result<()> {
+    let nodes = start_cluster_with_acl(3);
+    set(&nodes, "key1", "value1")?;
+    for node in &nodes {
+        wait_for_value(node, "key1", "value1")?;
+    }
+
+    let leader = wait_for_leader(&nodes);
+    let term = nodes[leader].connect()?.status()?.cluster.unwrap().term;
+    let vote = r#"{"RequestVote":{"term":1000,"candidate_id":0,"last_log_index":1000,"last_log_term":1000}}"#;
+    let auth = r#"{"Auth":{"user":"app","credential":{"Password":"letmein"}}}"#;
+    for messages in &[vec![vote], vec![auth, vote]] {
+        let mut stream = TcpStream::connect(nodes[leader].addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        for message in messages {
+            stream.write_all(message.as_bytes())?;
+        }
+
+        // Only the authentication is answered before the connection is dropped.
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(!response.contains("vote_granted"), "{}", response);
+    }
+
+    let status = nodes[leader].connect()?.status()?.cluster.unwrap();
+    assert_eq!(status.role, Role::Leader);
+    assert!(status.term < 1000 && status.term >= term);
+
+    Ok(())
+}
+
+// Should refuse to start a node of a cluster with an ACL but no credential
+// for the other nodes.
+#[test]
+fn cluster_acl_requires_peer_user() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(dir.path().join("acl.json"), ACL)?;
+    let status = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", "127.0.0.1:4000", "--cluster", "127.0.0.1:4000"])
+        .args(["--acl", "acl.json"])
+        .current_dir(dir.path())
+        .stderr(Stdio::null())
+        .status()?;
+    assert!(!status.success());
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+// Fixtures shared by the integration tests. Each test file only uses some of
+// them.
+#![allow(dead_code)]
+
+use kvs::{Acl, KvStore, KvsServer};
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+// Starts a server on a free port in the background.
+pub fn start_server() -> (SocketAddr, TempDir) {
+    start_server_with(|server, _| server)
+}
+
+// Starts a server requiring the users of the ACL, given as JSON, on a free
+// port in the background.
+pub fn start_server_with_acl(acl: &str) -> (SocketAddr, TempDir) {
+    start_server_with(|server, dir| {
+        let acl_path = dir.join("acl.json");
+        fs::write(&acl_path, acl).unwrap();
+        server.with_acl(Acl::load(&acl_path).unwrap())
+    })
+}
+
+// Starts a server, set up by `configure` given its data directory, on a free
+// port in the background.
+pub fn start_server_with<F>(configure: F) -> (SocketAddr, TempDir)
+where
+    F: FnOnce(KvsServer<KvStore>, &Path) -> KvsServer<KvStore>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let server = configure(KvsServer::new(store), temp_dir.path());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || server.serve(listener));
+
+    (addr, temp_dir)
+}
@@ -1,12 +1,22 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BackupManifest, BincodeCodec, CasOutcome, Change, Codec, ExportFormat, JsonCodec, KvStore,
+    KvStoreError, KvsEngine, KvsSnapshot, MessagePackCodec, RecordStatus, RecoveryTarget, Result,
+    WriteBatch,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
+#[allow(unused_imports)]
 use walkdir::WalkDir;
 
 // Should be able to set a key/value pair and retrieve it.
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     // TODO: to_owned may not be necessary as it clones the value.
     store.set("key1".to_owned(), "value1".to_owned())?;
@@ -24,6 +34,630 @@ fn get_stored_value() -> Result<()> {
     Ok(())
 }
 
+// Should stop returning a key once its ttl has passed.
+#[test]
+fn expire_key_after_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(100),
+    )?;
+
+    assert_eq!(store.ttl("key1".to_owned())?, None);
+    assert!(store.ttl("key2".to_owned())? <= Some(Duration::from_millis(100)));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    thread::sleep(Duration::from_millis(150));
+    assert!(store.get("key2".to_owned()).is_err());
+    assert!(store.ttl("key2".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Overwriting without a ttl clears the expiry.
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should persist the expiry of a key across restarts.
+#[test]
+fn persist_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(100),
+    )?;
+
+    drop(store);
+    thread::sleep(Duration::from_millis(150));
+    let store = KvStore::open(temp_dir.path())?;
+
+    let ttl = store
+        .ttl("key1".to_owned())?
+        .expect("expiry should be persisted");
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert!(store.get("key2".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should reclaim the log space of overwritten, removed and expired keys when
+// compacting.
+#[test]
+fn compact_reclaims_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || fs::metadata(temp_dir.path().join("log.txt")).unwrap().len();
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        store.set("key2".to_owned(), format!("value{}", i))?;
+        store.set_with_ttl(
+            format!("temp{}", i),
+            "value".to_owned(),
+            Duration::from_millis(50),
+        )?;
+    }
+    store.remove("key2".to_owned())?;
+    thread::sleep(Duration::from_millis(100));
+
+    let size = log_size();
+    store.compact()?;
+    assert!(log_size() * 100 < size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert!(store.get("key2".to_owned()).is_err());
+    assert!(store.get("temp0".to_owned()).is_err());
+
+    // The compacted log is appended to as normal.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should apply every write in a batch and persist them.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key1".to_owned())
+        .remove("missing".to_owned())
+        .set("key3".to_owned(), "value4".to_owned());
+    store.write_batch(batch)?;
+
+    assert!(store.get("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should drop a batch torn by a crash mid-write as a whole when replaying the
+// log, and keep appending after the last whole record.
+#[test]
+fn torn_batch_not_applied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.txt");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    let intact = fs::read(&log_path)?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "changed".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Simulate a crash by cutting the last record short.
+    let full = fs::read(&log_path)?;
+    let torn = &full[..intact.len() + (full.len() - intact.len()) / 2];
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(&log_path)?
+        .write_all(torn)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.get("key3".to_owned()).is_err());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should only write when the current value matches the expected value.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_if_absent("key1".to_owned(), "value1".to_owned())?,
+        CasOutcome::Swapped(1)
+    );
+    assert_eq!(
+        store.set_if_absent("key1".to_owned(), "value2".to_owned())?,
+        CasOutcome::Mismatch(Some("value1".to_owned()))
+    );
+
+    assert_eq!(
+        store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value2".to_owned()),
+            Some("value3".to_owned())
+        )?,
+        CasOutcome::Mismatch(Some("value1".to_owned()))
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert_eq!(
+        store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value3".to_owned())
+        )?,
+        CasOutcome::Swapped(2)
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // Swapping in None removes the key.
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value3".to_owned()), None)?,
+        CasOutcome::Swapped(3)
+    );
+    assert!(store.get("key1".to_owned()).is_err());
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value3".to_owned()), None)?,
+        CasOutcome::Mismatch(None)
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should not lose any increments when threads race to read-modify-write the
+// same key.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    let mut current = store.get("counter".to_owned())?;
+                    loop {
+                        let n: u64 = current.as_ref().unwrap().parse().unwrap();
+                        match store.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some((n + 1).to_string()),
+                        )? {
+                            CasOutcome::Swapped(_) => break,
+                            CasOutcome::Mismatch(actual) => current = actual,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Should commit every write of a transaction, with reads seeing the
+// transaction's own writes.
+#[test]
+fn transaction_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "100".to_owned())?;
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("from".to_owned())?, Some("100".to_owned()));
+    assert_eq!(txn.get("to".to_owned())?, None);
+    txn.set("from".to_owned(), "90".to_owned());
+    txn.set("to".to_owned(), "10".to_owned());
+    assert_eq!(txn.get("to".to_owned())?, Some("10".to_owned()));
+
+    // Writes aren't visible outside the transaction until it commits.
+    assert!(store.get("to".to_owned()).is_err());
+    txn.commit()?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("90".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+
+    Ok(())
+}
+
+// Should abort a transaction without writing anything if a key it read,
+// including one that didn't exist, was written before it committed.
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.begin();
+    txn.get("key1".to_owned())?;
+    txn.set("key2".to_owned(), "value2".to_owned());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match txn.commit() {
+        Err(KvStoreError::TransactionConflictError(key)) => assert_eq!(key, "key1"),
+        res => panic!("expected a transaction conflict, got {:?}", res),
+    }
+    assert!(store.get("key2".to_owned()).is_err());
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key3".to_owned())?, None);
+    txn.set("key3".to_owned(), "mine".to_owned());
+    store.set("key3".to_owned(), "theirs".to_owned())?;
+    assert!(txn.commit().is_err());
+    assert_eq!(store.get("key3".to_owned())?, Some("theirs".to_owned()));
+
+    // A key set and removed again since it was read as absent has changed,
+    // even across a compaction.
+    for compact in [false, true] {
+        let mut txn = store.begin();
+        assert_eq!(txn.get("key6".to_owned())?, None);
+        txn.set("key6".to_owned(), "mine".to_owned());
+        store.set("key6".to_owned(), "theirs".to_owned())?;
+        store.remove("key6".to_owned())?;
+        if compact {
+            store.compact()?;
+        }
+        assert!(txn.commit().is_err());
+        assert!(store.get("key6".to_owned()).is_err());
+    }
+
+    // Writes to keys the transaction didn't read don't conflict.
+    let mut txn = store.begin();
+    txn.get("key1".to_owned())?;
+    txn.set("key4".to_owned(), "value4".to_owned());
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should see the store as of when the snapshot was taken, whatever is written
+// afterwards.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user/1".to_owned(), "alice".to_owned())?;
+    store.set("user/2".to_owned(), "bob".to_owned())?;
+    store.set("config/ttl".to_owned(), "30".to_owned())?;
+
+    let snapshot = store.snapshot()?;
+    store.set("user/1".to_owned(), "carol".to_owned())?;
+    store.remove("user/2".to_owned())?;
+    store.set("user/3".to_owned(), "dave".to_owned())?;
+
+    assert_eq!(snapshot.get("user/1")?, Some("alice".to_owned()));
+    assert_eq!(snapshot.get("user/2")?, Some("bob".to_owned()));
+    assert_eq!(snapshot.get("user/3")?, None);
+    assert_eq!(
+        snapshot.scan("user/")?,
+        vec![
+            ("user/1".to_owned(), "alice".to_owned()),
+            ("user/2".to_owned(), "bob".to_owned()),
+        ]
+    );
+
+    // Compaction doesn't drop the old values a snapshot still sees.
+    store.compact()?;
+    assert_eq!(snapshot.get("user/2")?, Some("bob".to_owned()));
+
+    drop(snapshot);
+    let snapshot = store.snapshot()?;
+    assert_eq!(
+        snapshot.scan("user/")?,
+        vec![
+            ("user/1".to_owned(), "carol".to_owned()),
+            ("user/3".to_owned(), "dave".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+// Should refuse to restore the keys of a replica while a snapshot is open.
+#[test]
+fn restore_refused_with_open_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let keys = store.replicate()?.keys;
+
+    let snapshot = store.snapshot()?;
+    store.remove("key1".to_owned())?;
+    assert!(store.restore(1, keys.clone()).is_err());
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+
+    drop(snapshot);
+    store.restore(1, keys)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should never see a write half applied while writers move value between keys.
+#[test]
+fn snapshot_consistent_with_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("account/a".to_owned(), "100".to_owned())?;
+    store.set("account/b".to_owned(), "0".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..=100u64 {
+                let mut batch = WriteBatch::new();
+                batch
+                    .set("account/a".to_owned(), (100 - i).to_string())
+                    .set("account/b".to_owned(), i.to_string());
+                store.write_batch(batch)?;
+                store.set("other".to_owned(), i.to_string())?;
+            }
+            Ok(())
+        })
+    };
+
+    for _ in 0..100 {
+        let snapshot = store.snapshot()?;
+        let total: u64 = snapshot
+            .scan("account/")?
+            .iter()
+            .map(|(_, value)| value.parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, 100);
+    }
+    writer.join().unwrap()?;
+
+    Ok(())
+}
+
+// Should give every write the next sequence number, persisted with a
+// timestamp and never reused after a restart or compaction.
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.set("key1".to_owned(), "value1".to_owned())?, 1);
+    assert_eq!(store.set("key2".to_owned(), "value2".to_owned())?, 2);
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key1".to_owned());
+    assert_eq!(store.write_batch(batch)?, 4);
+    assert_eq!(store.remove("key2".to_owned())?, 5);
+
+    let log = fs::read_to_string(temp_dir.path().join("log.txt"))?;
+    let (json, checksum) = log.lines().next().unwrap().split_once('\t').unwrap();
+    assert_eq!(
+        checksum,
+        format!("{:08x}", crc32fast::hash(json.as_bytes()))
+    );
+    let record: serde_json::Value = serde_json::from_str(json)?;
+    assert_eq!(record["Set"]["seq"], 1);
+    assert!(record["Set"]["timestamp"].as_u64().unwrap() > 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.set("key4".to_owned(), "value4".to_owned())?, 6);
+
+    // The last write is a remove, whose record compaction drops.
+    store.remove("key4".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.set("key5".to_owned(), "value5".to_owned())?, 8);
+
+    Ok(())
+}
+
+// Should assign sequence numbers in order to a log written without them.
+#[test]
+fn sequence_numbers_for_old_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        concat!(
+            "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+            "{\"Remove\":{\"key\":\"key1\"}}\n",
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.set("key2".to_owned(), "value2".to_owned())?, 3);
+    drop(store);
+
+    // A log compacted before compaction headers existed ends with the
+    // sequence number of the last write instead.
+    fs::write(
+        temp_dir.path().join("log.txt"),
+        concat!(
+            "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\",\"seq\":2,\"timestamp\":0}}\n",
+            "{\"LastSeq\":{\"seq\":5}}\n",
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.set("key2".to_owned(), "value2".to_owned())?, 6);
+
+    Ok(())
+}
+
+// Should stream every later write to keys under the prefix, in order.
+#[test]
+fn watch_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("cache/a".to_owned(), "0".to_owned())?;
+
+    let changes = store.watch("cache/".to_owned(), None)?;
+    store.set("cache/a".to_owned(), "1".to_owned())?;
+    store.set("config/ttl".to_owned(), "30".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("cache/b".to_owned(), "2".to_owned())
+        .remove("cache/a".to_owned());
+    store.write_batch(batch)?;
+
+    let seqs_and_keys: Vec<(u64, Vec<u8>, Option<Vec<u8>>)> = changes
+        .try_iter()
+        .map(|change| (change.seq, change.key, change.value))
+        .collect();
+    assert_eq!(
+        seqs_and_keys,
+        vec![
+            (2, b"cache/a".to_vec(), Some(b"1".to_vec())),
+            (4, b"cache/b".to_vec(), Some(b"2".to_vec())),
+            (5, b"cache/a".to_vec(), None),
+        ]
+    );
+
+    Ok(())
+}
+
+// Should first stream the writes since the given sequence number from the log,
+// and refuse once they've been compacted away.
+#[test]
+fn watch_from_seq() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.watch("".to_owned(), Some(2))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let received: Vec<Change> = changes.try_iter().collect();
+    assert_eq!(
+        received
+            .iter()
+            .map(|change| (change.seq, change.key.as_slice()))
+            .collect::<Vec<_>>(),
+        vec![(2, &b"key2"[..]), (3, b"key1"), (4, b"key3")]
+    );
+    assert!(received.iter().all(|change| change.timestamp > 0));
+
+    store.compact()?;
+    match store.watch("".to_owned(), Some(4)) {
+        Err(KvStoreError::CompactedError(4)) => {}
+        res => panic!("expected a compacted error, got {:?}", res.map(|_| ())),
+    }
+    let changes = store.watch("".to_owned(), Some(5))?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(
+        changes
+            .try_iter()
+            .map(|change| change.seq)
+            .collect::<Vec<_>>(),
+        vec![5]
+    );
+
+    Ok(())
+}
+
+// Should end the stream of a watcher that falls too far behind, while still
+// replaying every write since the given sequence number.
+#[test]
+fn watch_drops_slow_watchers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1500 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    let replayed = store.watch("".to_owned(), Some(1))?;
+    let slow = store.watch("".to_owned(), None)?;
+    for i in 0..1500 {
+        store.set(format!("key{}", i), "changed".to_owned())?;
+    }
+
+    // The replay doesn't count towards the watcher's buffer.
+    assert_eq!(replayed.try_iter().count(), 1500 + 1024);
+    assert_eq!(slow.iter().count(), 1024);
+
+    let changes = store.watch("".to_owned(), None)?;
+    store.set("key0".to_owned(), "value".to_owned())?;
+    assert_eq!(changes.try_iter().count(), 1);
+
+    Ok(())
+}
+
 // Should overwrite existent value
 // #[test]
 // fn overwrite_value() -> Result<()> {
@@ -125,3 +759,485 @@ fn get_stored_value() -> Result<()> {
 
 //     panic!("No compaction detected");
 // }
+
+// Should scan the live keys under a prefix in key order.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    store.set_with_ttl(
+        "user:4".to_owned(),
+        "dave".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.remove("user:3".to_owned())?;
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(
+        store.scan("user:".to_owned())?,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan("".to_owned())?.len(), 3);
+
+    Ok(())
+}
+
+// Should refuse to restore a backup whose files don't match its manifest.
+#[test]
+fn restore_corrupt_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let manifest = store.backup(backup_dir.path())?;
+    assert_eq!(BackupManifest::load(backup_dir.path())?, manifest);
+    manifest.verify(backup_dir.path())?;
+
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(backup_dir.path().join("log.txt"))?;
+    log.write_all(b"garbage\n")?;
+    assert!(manifest.verify(backup_dir.path()).is_err());
+
+    let restored = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::restore_from(backup_dir.path(), restored.path()).is_err());
+    assert!(!restored.path().join("log.txt").exists());
+
+    Ok(())
+}
+
+// Should recover the store as of an earlier write from its archived logs,
+// undoing later writes.
+#[test]
+fn point_in_time_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.enable_archive()?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    let good = store.set("key1".to_owned(), "value3".to_owned())?;
+    thread::sleep(Duration::from_millis(10));
+    let good_time = now_millis();
+    thread::sleep(Duration::from_millis(10));
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1".to_owned());
+    batch.remove("key2".to_owned());
+    store.write_batch(batch)?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    for target in [
+        RecoveryTarget::Seq(good),
+        RecoveryTarget::Timestamp(good_time),
+    ] {
+        let snapshot = KvStore::open_at(temp_dir.path(), target)?;
+        assert_eq!(snapshot.seq(), good);
+        assert_eq!(
+            snapshot.scan("")?,
+            vec![
+                ("key1".to_owned(), "value3".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+            ]
+        );
+    }
+
+    // Before the first write every key is missing, and after the last one
+    // recovery matches the store.
+    assert_eq!(
+        KvStore::open_at(temp_dir.path(), RecoveryTarget::Seq(0))?.scan("")?,
+        vec![]
+    );
+    let latest = KvStore::open_at(temp_dir.path(), RecoveryTarget::Seq(u64::MAX))?;
+    assert_eq!(
+        latest.scan("")?,
+        vec![("key3".to_owned(), "value3".to_owned())]
+    );
+
+    let recovered_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open_at(temp_dir.path(), RecoveryTarget::Seq(good))?.write_to(recovered_dir.path())?;
+    let recovered = KvStore::open(recovered_dir.path())?;
+    assert_eq!(recovered.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(recovered.last_seq()?, good);
+
+    Ok(())
+}
+
+// Should refuse to recover to before the oldest log kept without archiving.
+#[test]
+fn point_in_time_recovery_without_archive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let seq = store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    assert!(KvStore::open_at(temp_dir.path(), RecoveryTarget::Seq(seq)).is_err());
+    let snapshot = KvStore::open_at(temp_dir.path(), RecoveryTarget::Seq(seq + 1))?;
+    assert_eq!(snapshot.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// Should refuse to open a log with a record that fails its checksum, and open
+// it again once repaired.
+#[test]
+fn verify_and_repair_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.txt");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let intact = fs::metadata(&log_path)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert_eq!(KvStore::verify(temp_dir.path())?, vec![]);
+
+    // Flip a byte of the second record's value.
+    let mut log = fs::read(&log_path)?;
+    let value = intact as usize
+        + log[intact as usize..]
+            .windows(6)
+            .position(|w| w == b"value2")
+            .unwrap();
+    log[value] = b'V';
+    fs::write(&log_path, &log)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::CorruptRecordError(offset)) => assert_eq!(offset, intact),
+        _ => panic!("expected a corrupt record error"),
+    }
+    let damaged = KvStore::verify(temp_dir.path())?;
+    assert_eq!(damaged.len(), 1);
+    assert_eq!(damaged[0].offset, intact);
+    assert_eq!(damaged[0].status, RecordStatus::Corrupt);
+
+    // The intact record after the corrupt one is only dropped if asked to.
+    assert!(KvStore::repair(temp_dir.path(), false).is_err());
+    assert_eq!(fs::read(&log_path)?, log);
+    let repair = KvStore::repair(temp_dir.path(), true)?.unwrap();
+    assert_eq!(repair.offset, intact);
+    assert_eq!(repair.intact, 1);
+    assert_eq!(KvStore::repair(temp_dir.path(), false)?, None);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.get("key2".to_owned()).is_err());
+    assert_eq!(store.set("key2".to_owned(), "value2".to_owned())?, 2);
+
+    Ok(())
+}
+
+// Should store binary keys and values in framed records, replayed on reopen
+// and kept by compaction, while text records stay lines of JSON.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.txt");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(fs::read(&log_path)?.starts_with(b"{"));
+
+    let key = [0xff, 0x00, b'\n'];
+    let value = [0x89, b'P', b'N', b'G', 0x00, 0xfe];
+    store.set_bytes(&key, &value)?;
+    store.set_bytes(b"image", &value)?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.to_vec()));
+    assert_eq!(store.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+    match store.get("image".to_owned()) {
+        Err(KvStoreError::StringUtf8Error(_)) => {}
+        res => panic!("expected a UTF-8 error, got {:?}", res),
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.to_vec()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.remove_bytes(b"image")?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan_bytes(b"")?,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (key.to_vec(), value.to_vec())
+        ]
+    );
+
+    // Binary pairs survive an export and import in either format.
+    for format in [ExportFormat::Jsonl, ExportFormat::Csv] {
+        let mut export = Vec::new();
+        store.export("".to_owned(), format, &mut export)?;
+        let imported_dir = TempDir::new().expect("unable to create temporary working directory");
+        let imported = KvStore::open(imported_dir.path())?;
+        assert_eq!(imported.import("".to_owned(), format, &export[..])?, 2);
+        assert_eq!(imported.scan_bytes(b"")?, store.scan_bytes(b"")?);
+    }
+
+    Ok(())
+}
+
+// Should compare binary values in conditional writes and transactions, and
+// watch binary prefixes, rather than failing on values that aren't UTF-8.
+#[test]
+fn binary_conditional_writes_and_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = [0x89, b'P', b'N', b'G', 0x00, 0xfe];
+    let changes = store.watch_bytes(&[0xff], None)?;
+    store.set_bytes(&[0xff, 0x00], &value)?;
+
+    assert_eq!(
+        store.compare_and_swap_bytes(&[0xff, 0x00], Some(b"other"), Some(b"new"))?,
+        CasOutcome::Mismatch(Some(value.to_vec()))
+    );
+    assert_eq!(
+        store.set_if_absent_bytes(&[0xff, 0x00], b"new")?,
+        CasOutcome::Mismatch(Some(value.to_vec()))
+    );
+    assert_eq!(
+        store.compare_and_swap_bytes(&[0xff, 0x00], Some(&value), Some(&[0xfe]))?,
+        CasOutcome::Swapped(2)
+    );
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get_bytes(&[0xff, 0x00])?, Some(vec![0xfe]));
+    txn.set_bytes(&[0xff, 0x01], &value);
+    txn.remove_bytes(&[0xff, 0x00]);
+    assert_eq!(txn.get_bytes(&[0xff, 0x00])?, None);
+    assert_eq!(txn.commit()?, 4);
+    assert_eq!(store.get_bytes(&[0xff, 0x01])?, Some(value.to_vec()));
+
+    let keys: Vec<(u64, Vec<u8>)> = changes
+        .iter()
+        .take(4)
+        .map(|change| (change.seq, change.key))
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            (1, vec![0xff, 0x00]),
+            (2, vec![0xff, 0x00]),
+            (3, vec![0xff, 0x01]),
+            (4, vec![0xff, 0x00])
+        ]
+    );
+
+    Ok(())
+}
+
+// Should report a binary record that fails its checksum as corrupt, and one cut
+// short by a crash as torn.
+#[test]
+fn verify_damaged_binary_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.txt");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let intact = fs::metadata(&log_path)?.len();
+    store.set_bytes(b"key2", &[0xff; 16])?;
+    drop(store);
+    assert_eq!(KvStore::verify(temp_dir.path())?, vec![]);
+
+    let mut log = fs::read(&log_path)?;
+    let len = log.len();
+    log[len - 8] ^= 0x01;
+    fs::write(&log_path, &log)?;
+    let damaged = KvStore::verify(temp_dir.path())?;
+    assert_eq!(damaged.len(), 1);
+    assert_eq!(damaged[0].offset, intact);
+    assert_eq!(damaged[0].status, RecordStatus::Corrupt);
+
+    fs::write(&log_path, &log[..len - 3])?;
+    let damaged = KvStore::verify(temp_dir.path())?;
+    assert_eq!(damaged.len(), 1);
+    assert_eq!(damaged[0].status, RecordStatus::Torn);
+
+    // A torn record at the end is dropped on open, as for text records.
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get_bytes(b"key2").is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+    avatar: Option<Vec<u8>>,
+}
+
+// Should read back a typed value set with each codec, failing to decode one
+// read as another type.
+#[test]
+fn typed_values() -> Result<()> {
+    fn round_trip<C: Codec + Copy>(store: &KvStore, codec: C) -> Result<()> {
+        let user = User {
+            name: "ada".to_owned(),
+            age: 36,
+            avatar: Some(vec![0x89, 0x00, 0xfe]),
+        };
+        store.set_typed("user:1".to_owned(), &user, codec)?;
+        assert_eq!(store.get_typed("user:1".to_owned(), codec)?, Some(user));
+        assert!(matches!(
+            store.get_typed::<User, _>("user:2".to_owned(), codec),
+            Err(KvStoreError::KeyNotFoundError)
+        ));
+
+        store.set_typed("count".to_owned(), &7u8, codec)?;
+        match store.get_typed::<User, _>("count".to_owned(), codec) {
+            Err(KvStoreError::DecodeError(_)) => Ok(()),
+            res => panic!("expected a decode error, got {:?}", res),
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    round_trip(&store, JsonCodec)?;
+    round_trip(&store, BincodeCodec)?;
+    round_trip(&store, MessagePackCodec)?;
+
+    // JSON values are readable as strings.
+    store.set_typed("tags".to_owned(), &["a", "b"], JsonCodec)?;
+    assert_eq!(
+        store.get("tags".to_owned())?,
+        Some(r#"["a","b"]"#.to_owned())
+    );
+
+    Ok(())
+}
+
+// Should count live and dead keys and bytes without changing the log.
+#[test]
+fn log_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = fs::read(temp_dir.path().join("log.txt"))?;
+    let stats = KvStore::stats(temp_dir.path())?;
+    assert_eq!(stats.seq, 5);
+    assert_eq!(stats.records, 5);
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.dead_keys, 1);
+    assert_eq!(stats.live_bytes + stats.dead_bytes, log.len() as u64);
+    assert_eq!(fs::read(temp_dir.path().join("log.txt"))?, log);
+
+    KvStore::open(temp_dir.path())?.compact()?;
+    let stats = KvStore::stats(temp_dir.path())?;
+    assert_eq!(stats.log_start, 6);
+    assert_eq!(stats.dead_keys, 0);
+
+    Ok(())
+}
+
+// Should keep when each key expires through an export and import, in either
+// format, skipping keys that expired before the import.
+#[test]
+fn export_and_import_keep_expiry() -> Result<()> {
+    for format in [ExportFormat::Jsonl, ExportFormat::Csv] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+
+        let mut export = Vec::new();
+        assert_eq!(store.export("".to_owned(), format, &mut export)?, 2);
+        assert!(String::from_utf8_lossy(&export).contains("expires_at"));
+
+        let imported_dir = TempDir::new().expect("unable to create temporary working directory");
+        let imported = KvStore::open(imported_dir.path())?;
+        assert_eq!(imported.import("".to_owned(), format, &export[..])?, 2);
+        assert_eq!(imported.ttl("key1".to_owned())?, None);
+        let ttl = imported.ttl("key2".to_owned())?.unwrap();
+        assert!(ttl <= Duration::from_secs(3600) && ttl > Duration::from_secs(3500));
+    }
+
+    // A key that expired before the import is skipped, and an export without
+    // expiry still imports.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let jsonl = "{\"key\":\"key1\",\"value\":\"value1\",\"expires_at\":1}\n";
+    assert_eq!(
+        store.import("".to_owned(), ExportFormat::Jsonl, jsonl.as_bytes())?,
+        0
+    );
+    let csv = "key,value\nkey2,value2\n";
+    assert_eq!(
+        store.import("".to_owned(), ExportFormat::Csv, csv.as_bytes())?,
+        1
+    );
+    assert_eq!(
+        store.scan("".to_owned())?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Should import an export of the keys with the prefix, in either format,
+// keeping values that need quoting or escaping intact.
+#[test]
+fn export_and_import() -> Result<()> {
+    for format in [ExportFormat::Jsonl, ExportFormat::Csv] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("user:1".to_owned(), "a, \"quoted\"\nvalue".to_owned())?;
+        store.set("user:2".to_owned(), "".to_owned())?;
+        store.set("other".to_owned(), "value".to_owned())?;
+
+        let mut export = Vec::new();
+        assert_eq!(store.export("user:".to_owned(), format, &mut export)?, 2);
+
+        let imported_dir = TempDir::new().expect("unable to create temporary working directory");
+        let imported = KvStore::open(imported_dir.path())?;
+        assert_eq!(imported.import("".to_owned(), format, &export[..])?, 2);
+        assert_eq!(
+            imported.scan("".to_owned())?,
+            store.scan("user:".to_owned())?
+        );
+
+        // Only keys with the prefix are imported.
+        let filtered_dir = TempDir::new().expect("unable to create temporary working directory");
+        let filtered = KvStore::open(filtered_dir.path())?;
+        assert_eq!(
+            filtered.import("user:2".to_owned(), format, &export[..])?,
+            1
+        );
+        assert_eq!(
+            filtered.scan("".to_owned())?,
+            vec![("user:2".to_owned(), "".to_owned())]
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+mod common;
+
+use common::start_server;
+use kvs::{KvStore, KvsClient, KvsServer, Result};
+use serde_json::Value;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Starts a server whose first connection refuses the client's wire format
+// request, so the client keeps to JSON, answers `answered` requests with
+// `{"Ok":null}` and is then closed, as if the server restarted, and whose
+// later connections are served normally.
+fn start_flaky_server(answered: usize) -> (SocketAddr, TempDir) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut incoming = listener.incoming();
+        let mut first = incoming.next().unwrap().unwrap();
+        let requests = serde_json::Deserializer::from_reader(first.try_clone().unwrap());
+        let mut requests = requests.into_iter::<Value>();
+        if requests.next().is_some() {
+            first.write_all(br#"{"Err":"JSON only"}"#).unwrap();
+        }
+        for _ in requests.take(answered) {
+            first.write_all(br#"{"Ok":null}"#).unwrap();
+        }
+        drop(first);
+
+        let server = KvsServer::new(store);
+        for stream in incoming {
+            let server = server.clone();
+            thread::spawn(move || server.handle_stream(stream.unwrap()));
+        }
+    });
+
+    (addr, temp_dir)
+}
+
+// Should fail a request the server never answers once the read timeout
+// passes, rather than hang.
+#[test]
+fn read_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (accepted, hung) = std::sync::mpsc::channel::<TcpStream>();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            accepted.send(stream.unwrap()).unwrap();
+        }
+    });
+
+    let mut client = KvsClient::builder(addr.to_string())
+        .with_connect_timeout(Duration::from_secs(1))
+        .with_read_timeout(Duration::from_millis(100))
+        .connect()?;
+    let _stream = hung.recv().unwrap();
+
+    let start = Instant::now();
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    Ok(())
+}
+
+// Should retry an idempotent request over a new connection when its
+// connection is closed, but not a write that may have been made.
+#[test]
+fn retries_idempotent_requests() -> Result<()> {
+    let (addr, _data) = start_flaky_server(0);
+    let pool = KvsClient::builder(addr.to_string())
+        .with_pool_size(1)
+        .with_retries(3, Duration::from_millis(10))
+        .build_pool()?;
+    assert!(pool.set("key1".to_owned(), "value1".to_owned()).is_err());
+    // The next request reconnects.
+    assert_eq!(pool.set("key1".to_owned(), "value1".to_owned())?, 1);
+
+    let (addr, _data) = start_flaky_server(0);
+    let pool = KvsClient::builder(addr.to_string())
+        .with_pool_size(1)
+        .with_retries(3, Duration::from_millis(10))
+        .build_pool()?;
+    assert_eq!(pool.scan("".to_owned())?, vec![]);
+    assert_eq!(pool.set("key1".to_owned(), "value1".to_owned())?, 1);
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should replace an idle connection the server closed before reusing it, so
+// even a write goes through.
+#[test]
+fn health_checks_idle_connections() -> Result<()> {
+    let (addr, _data) = start_flaky_server(1);
+    let pool = KvsClient::builder(addr.to_string())
+        .with_pool_size(1)
+        .with_retries(0, Duration::from_millis(10))
+        .with_health_check_interval(Duration::from_millis(0))
+        .build_pool()?;
+
+    // Answered by the first connection, which is then closed.
+    assert_eq!(pool.get("key1".to_owned())?, None);
+    assert_eq!(pool.set("key1".to_owned(), "value1".to_owned())?, 1);
+
+    Ok(())
+}
+
+// Should share the pool's connections between threads.
+#[test]
+fn shared_between_threads() -> Result<()> {
+    let (addr, _data) = start_server();
+    let pool = std::sync::Arc::new(
+        KvsClient::builder(addr.to_string())
+            .with_pool_size(2)
+            .build_pool()?,
+    );
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || pool.set(format!("key{}", i), "value".to_owned()))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut batch = kvs::WriteBatch::new();
+    batch.remove("key0".to_owned());
+    pool.write_batch(batch)?;
+    assert_eq!(pool.scan("key".to_owned())?.len(), 7);
+    assert_eq!(pool.with(|client| client.status())?.seq, 9);
+
+    Ok(())
+}
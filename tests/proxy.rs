@@ -0,0 +1,204 @@
+mod common;
+
+use kvs::{Credential, KvStoreError, KvsClient, KvsProxy, Result, WriteBatch};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use tempfile::TempDir;
+
+// Starts a server on a free port in the background, returning its address as
+// a string.
+fn start_server() -> (String, TempDir) {
+    let (addr, temp_dir) = common::start_server();
+    (addr.to_string(), temp_dir)
+}
+
+const ACL: &str = r#"{
+  "users": [
+    {
+      "name": "admin",
+      "password": "hunter2",
+      "grants": [{ "prefix": "", "read": true, "write": true }]
+    },
+    {
+      "name": "cache",
+      "tokens": ["8f14e45fceea167a"],
+      "grants": [{ "prefix": "cache/", "read": true, "write": true }]
+    }
+  ]
+}"#;
+
+// Starts a proxy for the servers on a free port in the background.
+fn start_proxy(backends: Vec<String>, pool_size: usize) -> SocketAddr {
+    serve_proxy(KvsProxy::new(backends, pool_size).unwrap())
+}
+
+// Serves the proxy on a free port in the background.
+fn serve_proxy(proxy: KvsProxy) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || proxy.serve(listener));
+
+    addr
+}
+
+// Should serve many clients through a pool of fewer upstream connections,
+// with the same responses as the server.
+#[test]
+fn proxy_forwards_requests() -> Result<()> {
+    let (server, _data) = start_server();
+    let proxy = start_proxy(vec![server.clone()], 2);
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(proxy)?;
+                for j in 0..10 {
+                    client.set(format!("key{}-{}", i, j), format!("value{}", j))?;
+                }
+                assert_eq!(
+                    client.get(format!("key{}-0", i))?,
+                    Some("value0".to_owned())
+                );
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(proxy)?;
+    assert!(client.get("missing".to_owned()).is_err());
+    assert_eq!(client.scan("key0-".to_owned())?.len(), 10);
+    assert_eq!(client.status()?.seq, 80);
+    assert_eq!(KvsClient::connect(server.as_str())?.status()?.seq, 80);
+
+    // Binary values are forwarded in MessagePack, streams included.
+    let mut changes = KvsClient::connect(proxy)?.watch_bytes(&[0xff], None)?;
+    client.set_bytes(&[0xff], &[0x00, 0xfe])?;
+    assert_eq!(client.get_bytes(&[0xff])?, Some(vec![0x00, 0xfe]));
+    assert_eq!(changes.next().unwrap()?.value, Some(vec![0x00, 0xfe]));
+    client.send_raw(r#"{"Get":{"key":{"base64":"/w=="}}}"#)?;
+    assert_eq!(
+        client.receive_raw()?,
+        serde_json::json!({ "Ok": { "base64": "AP4=" } })
+    );
+
+    Ok(())
+}
+
+// Should shard keys across several servers, rejecting batches that span
+// shards.
+#[test]
+fn proxy_shards_keys() -> Result<()> {
+    let (server1, _data1) = start_server();
+    let (server2, _data2) = start_server();
+    let proxy = start_proxy(vec![server1.clone(), server2.clone()], 2);
+
+    let mut client = KvsClient::connect(proxy)?;
+    for i in 0..50 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..50 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    client.remove("key0".to_owned())?;
+
+    let in_server1 = KvsClient::connect(server1.as_str())?.scan(String::new())?;
+    let in_server2 = KvsClient::connect(server2.as_str())?.scan(String::new())?;
+    assert!(!in_server1.is_empty() && !in_server2.is_empty());
+    assert_eq!(in_server1.len() + in_server2.len(), 49);
+
+    let mut all = client.scan(String::new())?;
+    let mut expected = [in_server1, in_server2].concat();
+    expected.sort();
+    all.sort();
+    assert_eq!(all, expected);
+
+    let mut batch = WriteBatch::new();
+    for i in 0..50 {
+        batch.set(format!("key{}", i), "new".to_owned());
+    }
+    assert!(client.write_batch(batch).is_err());
+    assert!(client.status().is_err());
+
+    Ok(())
+}
+
+fn is_denied<T>(res: Result<T>) -> bool {
+    matches!(res, Err(KvStoreError::PermissionDeniedError(_)))
+}
+
+// Should pass each client's authentication through to the servers, so their
+// ACL applies to the client's requests as if it were connected directly.
+#[test]
+fn proxy_passes_authentication_through() -> Result<()> {
+    let (server1, _data1) = common::start_server_with_acl(ACL);
+    let (server2, _data2) = common::start_server_with_acl(ACL);
+    let proxy = start_proxy(vec![server1.to_string(), server2.to_string()], 2);
+
+    let mut anonymous = KvsClient::connect(proxy)?;
+    assert!(is_denied(anonymous.get("key1".to_owned())));
+
+    let mut admin = KvsClient::connect(proxy)?;
+    match admin.authenticate("admin".to_owned(), Credential::Password("wrong".to_owned())) {
+        Err(KvStoreError::AuthenticationError(_)) => {}
+        res => panic!("expected an authentication error, got {:?}", res),
+    }
+    assert!(is_denied(admin.get("key1".to_owned())));
+    admin.authenticate(
+        "admin".to_owned(),
+        Credential::Password("hunter2".to_owned()),
+    )?;
+    for i in 0..10 {
+        admin.set(format!("cache/{}", i), "value".to_owned())?;
+    }
+    admin.set("config/mode".to_owned(), "fast".to_owned())?;
+    assert_eq!(admin.scan("".to_owned())?.len(), 11);
+
+    let mut cache = KvsClient::connect(proxy)?;
+    cache.authenticate(
+        "cache".to_owned(),
+        Credential::Token("8f14e45fceea167a".to_owned()),
+    )?;
+    assert_eq!(cache.get("cache/3".to_owned())?, Some("value".to_owned()));
+    assert!(is_denied(cache.get("config/mode".to_owned())));
+    assert!(is_denied(
+        cache.set("config/mode".to_owned(), "slow".to_owned())
+    ));
+
+    // Other clients still share the unauthenticated pools.
+    assert!(is_denied(anonymous.get("cache/3".to_owned())));
+
+    Ok(())
+}
+
+// Should authenticate the pooled connections as the proxy's user, whose
+// access clients that don't authenticate get.
+#[test]
+fn proxy_authenticates_pool() -> Result<()> {
+    let (server, _data) = common::start_server_with_acl(ACL);
+    let proxy = KvsProxy::new(vec![server.to_string()], 2)?.with_backend_credential(
+        "cache".to_owned(),
+        Credential::Token("8f14e45fceea167a".to_owned()),
+    );
+    let proxy = serve_proxy(proxy);
+
+    let mut client = KvsClient::connect(proxy)?;
+    client.set("cache/1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("cache/1".to_owned())?, Some("value1".to_owned()));
+    assert!(is_denied(
+        client.set("key1".to_owned(), "value1".to_owned())
+    ));
+
+    // Streams connect as the proxy's user too.
+    let mut changes = KvsClient::connect(proxy)?.watch("cache/".to_owned(), None)?;
+    client.set("cache/2".to_owned(), "value2".to_owned())?;
+    assert_eq!(changes.next().unwrap()?.key, b"cache/2".to_vec());
+
+    Ok(())
+}
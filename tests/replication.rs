@@ -0,0 +1,164 @@
+mod common;
+
+use kvs::{Credential, KvStoreError, KvsClient, LogReader, Result, WriteBatch};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Starts a server, a replica of the leader if one is given, on a free port in
+// the background.
+fn start_server(leader: Option<SocketAddr>) -> (SocketAddr, TempDir) {
+    common::start_server_with(|server, _| match leader {
+        Some(leader) => server.with_replica_of(leader.to_string()),
+        None => server,
+    })
+}
+
+// Waits until the replica has applied every write up to `seq`.
+fn wait_for_seq(replica: SocketAddr, seq: u64) -> Result<()> {
+    let mut client = KvsClient::connect(replica)?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    while client.status()?.seq < seq {
+        assert!(Instant::now() < deadline, "replica didn't catch up");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+// Should bootstrap replicas from the leader's keys, then apply the leader's
+// writes as they're made.
+#[test]
+fn replicas_follow_leader() -> Result<()> {
+    let (leader, _leader_data) = start_server(None);
+    let mut client = KvsClient::connect(leader)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key2".to_owned())?;
+
+    let (replica1, _replica1_data) = start_server(Some(leader));
+    let (replica2, _replica2_data) = start_server(Some(leader));
+    wait_for_seq(replica1, 3)?;
+
+    let mut reader = KvsClient::connect(replica1)?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(reader.get("key2".to_owned()).is_err());
+
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    let seq = client.remove("key1".to_owned())?;
+    for replica in [replica1, replica2] {
+        wait_for_seq(replica, seq)?;
+
+        let mut reader = KvsClient::connect(replica)?;
+        assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert!(reader.get("key1".to_owned()).is_err());
+    }
+
+    Ok(())
+}
+
+// Should apply each of the leader's batches on a replica as a single record.
+#[test]
+fn replicas_apply_batches_whole() -> Result<()> {
+    let (leader, _leader_data) = start_server(None);
+    let (replica, replica_data) = start_server(Some(leader));
+    let mut client = KvsClient::connect(leader)?;
+    wait_for_seq(replica, client.set("key1".to_owned(), "value1".to_owned())?)?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("account/a".to_owned(), "0".to_owned())
+        .set("account/b".to_owned(), "100".to_owned());
+    let seq = client.write_batch(batch)?;
+    wait_for_seq(replica, seq)?;
+
+    let last = LogReader::open(&replica_data.path().join("log.txt"))?
+        .last()
+        .unwrap()?;
+    let json = last.to_json()?;
+    assert!(json.starts_with(r#"{"Batch""#), "{}", json);
+    assert!(json.contains("account/a") && json.contains("account/b"));
+
+    Ok(())
+}
+
+// Should authenticate to a leader requiring it, and apply nothing from a
+// leader that rejects the replica.
+#[test]
+fn replica_authenticates_to_leader() -> Result<()> {
+    let (leader, _leader_data) = common::start_server_with_acl(
+        r#"{"users": [{"name": "replica", "password": "hunter2",
+            "grants": [{"prefix": "", "read": true, "write": true}]}]}"#,
+    );
+    let mut client = KvsClient::connect(leader)?;
+    client.authenticate(
+        "replica".to_owned(),
+        Credential::Password("hunter2".to_owned()),
+    )?;
+    let seq = client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let (replica, _replica_data) = common::start_server_with(|server, _| {
+        server.with_replica_of_client(KvsClient::builder(leader.to_string()).with_credential(
+            "replica".to_owned(),
+            Credential::Password("hunter2".to_owned()),
+        ))
+    });
+    wait_for_seq(replica, seq)?;
+    let mut reader = KvsClient::connect(replica)?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let (refused, _refused_data) = start_server(Some(leader));
+    thread::sleep(Duration::from_millis(200));
+    let status = KvsClient::connect(refused)?.status()?;
+    assert_eq!(status.seq, 0);
+    assert!(!status.replica.unwrap().connected);
+
+    Ok(())
+}
+
+// Should reject writes on a replica with the address of the leader.
+#[test]
+fn replica_redirects_writes() -> Result<()> {
+    let (leader, _leader_data) = start_server(None);
+    let (replica, _replica_data) = start_server(Some(leader));
+    let mut client = KvsClient::connect(replica)?;
+
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(KvStoreError::RedirectError(addr)) => assert_eq!(addr, leader.to_string()),
+        res => panic!("expected a redirect, got {:?}", res),
+    }
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvStoreError::RedirectError(_))
+    ));
+
+    let mut txn = client.begin();
+    txn.set("key1".to_owned(), "value1".to_owned());
+    assert!(matches!(txn.commit(), Err(KvStoreError::RedirectError(_))));
+
+    Ok(())
+}
+
+// Should report the leader, connection and lag of a replica.
+#[test]
+fn replica_status() -> Result<()> {
+    let (leader, _leader_data) = start_server(None);
+    let seq = KvsClient::connect(leader)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let (replica, _replica_data) = start_server(Some(leader));
+    wait_for_seq(replica, seq)?;
+
+    let status = KvsClient::connect(replica)?.status()?;
+    let replica_status = status.replica.unwrap();
+    assert_eq!(replica_status.leader, leader.to_string());
+    assert!(replica_status.connected);
+    assert_eq!(replica_status.applied_seq, seq);
+    assert_eq!(replica_status.lag(), 0);
+    assert!(replica_status.last_contact.is_some());
+
+    assert_eq!(KvsClient::connect(leader)?.status()?.replica, None);
+
+    Ok(())
+}
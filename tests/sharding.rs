@@ -0,0 +1,76 @@
+mod common;
+
+use kvs::{KvsClient, Result, ShardedKvsClient};
+use tempfile::TempDir;
+
+// Starts a server on a free port in the background, returning its address as
+// a string.
+fn start_server() -> (String, TempDir) {
+    let (addr, temp_dir) = common::start_server();
+    (addr.to_string(), temp_dir)
+}
+
+// Should store each key only on the server it hashes to, spreading keys
+// across every server.
+#[test]
+fn keys_spread_across_servers() -> Result<()> {
+    let (addr1, _data1) = start_server();
+    let (addr2, _data2) = start_server();
+    let mut client = ShardedKvsClient::connect(vec![addr1.clone(), addr2.clone()])?;
+
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    client.remove("key0".to_owned())?;
+    assert!(client.get("key0".to_owned()).is_err());
+
+    for addr in &[addr1, addr2] {
+        let keys = KvsClient::connect(addr.as_str())?.scan(String::new())?;
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|(key, _)| client.node_for(key) == addr));
+    }
+
+    Ok(())
+}
+
+// Should move only the keys that belong to a new server to it, leaving every
+// key readable.
+#[test]
+fn add_node_migrates_keys() -> Result<()> {
+    let (addr1, _data1) = start_server();
+    let (addr2, _data2) = start_server();
+    let mut client = ShardedKvsClient::connect(vec![addr1.clone(), addr2.clone()])?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let (addr3, _data3) = start_server();
+    let moved = client.add_node(addr3.clone())?;
+    assert!(moved > 0 && moved < 100);
+
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    let mut total = 0;
+    for addr in &[addr1, addr2, addr3.clone()] {
+        let keys = KvsClient::connect(addr.as_str())?.scan(String::new())?;
+        assert!(keys.iter().all(|(key, _)| client.node_for(key) == addr));
+        if *addr == addr3 {
+            assert_eq!(keys.len(), moved);
+        }
+        total += keys.len();
+    }
+    assert_eq!(total, 100);
+
+    Ok(())
+}
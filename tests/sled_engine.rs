@@ -0,0 +1,238 @@
+use kvs::{
+    read_engine_marker, CasOutcome, KvStore, KvStoreError, KvsEngine, KvsSnapshot, Result,
+    SledKvsEngine, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should persist keys and sequence numbers across reopening the database.
+#[test]
+fn persists_keys_and_sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.set("key1".to_owned(), "value1".to_owned())?, 1);
+    assert_eq!(engine.set("key2".to_owned(), "value2".to_owned())?, 2);
+    assert_eq!(engine.remove("key2".to_owned())?, 3);
+    assert!(engine.remove("key2".to_owned()).is_err());
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(engine.get("key2".to_owned()).is_err());
+    assert_eq!(engine.last_seq()?, 3);
+    assert_eq!(engine.set("key3".to_owned(), "value3".to_owned())?, 4);
+
+    Ok(())
+}
+
+// Should store binary keys and values across reopening the database.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let value = [0x89, b'P', b'N', b'G', 0x00, 0xfe];
+    engine.set_bytes(&[0xff, 0x00], &value)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get_bytes(&[0xff, 0x00])?, Some(value.to_vec()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        engine.scan_bytes(&[0xff])?,
+        vec![(vec![0xff, 0x00], value.to_vec())]
+    );
+    match engine.scan("".to_owned()) {
+        Err(KvStoreError::StringUtf8Error(_)) => {}
+        res => panic!("expected a UTF-8 error, got {:?}", res),
+    }
+    assert_eq!(
+        engine.set_if_absent_bytes(&[0xff, 0x00], b"other")?,
+        CasOutcome::Mismatch(Some(value.to_vec()))
+    );
+    let mut txn = engine.begin();
+    assert_eq!(txn.get_bytes(&[0xff, 0x00])?, Some(value.to_vec()));
+    txn.set_bytes(&[0xff, 0x00], &[0xfe]);
+    txn.commit()?;
+    assert_eq!(engine.get_bytes(&[0xff, 0x00])?, Some(vec![0xfe]));
+
+    Ok(())
+}
+
+// Should apply batches, conditional writes and transactions like `KvStore`.
+#[test]
+fn batches_and_conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_millis(50),
+        )
+        .remove("missing".to_owned());
+    assert_eq!(engine.write_batch(batch)?, 3);
+    assert!(engine.ttl("key2".to_owned())?.is_some());
+
+    assert_eq!(
+        engine.set_if_absent("key1".to_owned(), "other".to_owned())?,
+        CasOutcome::Mismatch(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        engine.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)?,
+        CasOutcome::Swapped(4)
+    );
+
+    let (_, version) = engine.get_versioned("key2".to_owned())?;
+    engine.set("key2".to_owned(), "changed".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    match engine.commit(vec![(b"key2".to_vec(), version)], batch) {
+        Err(KvStoreError::TransactionConflictError(key)) => assert_eq!(key, "key2"),
+        _ => panic!("expected a transaction conflict"),
+    }
+
+    // A key read as absent, then set and removed again, has still changed.
+    let (value, version) = engine.get_versioned("key4".to_owned())?;
+    assert_eq!(value, None);
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    engine.remove("key4".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    assert!(engine
+        .commit(vec![(b"key4".to_vec(), version)], batch)
+        .is_err());
+
+    assert_eq!(
+        engine.scan("key".to_owned())?,
+        vec![("key2".to_owned(), "changed".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Should take snapshots through `KvsEngine` that later writes don't change.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("user/1".to_owned(), "alice".to_owned())?;
+    engine.set("user/2".to_owned(), "bob".to_owned())?;
+
+    let snapshot = pin(&engine)?;
+    engine.set("user/1".to_owned(), "carol".to_owned())?;
+    engine.remove("user/2".to_owned())?;
+
+    assert_eq!(snapshot.seq(), 2);
+    assert_eq!(snapshot.get("user/1")?, Some("alice".to_owned()));
+    assert_eq!(
+        snapshot.scan("user/")?,
+        vec![
+            ("user/1".to_owned(), "alice".to_owned()),
+            ("user/2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert_eq!(
+        pin(&engine)?.scan("user/")?,
+        vec![("user/1".to_owned(), "carol".to_owned())]
+    );
+
+    Ok(())
+}
+
+/// Takes a snapshot of any engine.
+fn pin<E: KvsEngine>(engine: &E) -> Result<E::Snapshot> {
+    engine.snapshot()
+}
+
+// Should migrate every live key to a sled directory, keeping its version and
+// marking the directory as sled's.
+#[test]
+fn migrate_kvs_to_sled() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(src.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let version = store.get_versioned("key2".to_owned())?.1;
+    drop(store);
+
+    let migrate = |from: &str, to: &str| {
+        Command::new(env!("CARGO_BIN_EXE_kvs-admin"))
+            .args(["migrate", "--from", from, "--to", to])
+            .arg(src.path())
+            .arg(dst.path())
+            .output()
+            .expect("unable to run kvs-admin")
+    };
+
+    // The source holds kvs data, not sled.
+    assert!(!migrate("sled", "kvs").status.success());
+
+    let output = migrate("kvs", "sled");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Migrated 1 keys"));
+    assert_eq!(read_engine_marker(dst.path())?, Some("sled".to_owned()));
+
+    let engine = SledKvsEngine::open(dst.path())?;
+    assert_eq!(
+        engine.get_versioned("key2".to_owned())?,
+        (Some("value2".to_owned()), version)
+    );
+    assert!(engine.get("key1".to_owned()).is_err());
+    assert_eq!(engine.last_seq()?, 3);
+    drop(engine);
+
+    // The destination isn't empty any more.
+    assert!(!migrate("kvs", "sled").status.success());
+
+    Ok(())
+}
+
+// Should migrate without changing the source, leaving out keys that expired
+// and the record torn at the end of its log.
+#[test]
+fn migrate_leaves_source_unchanged() -> Result<()> {
+    let src = TempDir::new().expect("unable to create temporary working directory");
+    let dst = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(src.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    drop(store);
+    thread::sleep(Duration::from_millis(10));
+
+    let log = src.path().join("log.txt");
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(br#"{"Set":{"key""#)?;
+    let before = fs::read(&log)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_kvs-admin"))
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(src.path())
+        .arg(dst.path())
+        .output()
+        .expect("unable to run kvs-admin");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Migrated 1 keys"));
+    assert_eq!(fs::read(&log)?, before);
+
+    let engine = SledKvsEngine::open(dst.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(engine.get("key2".to_owned()).is_err());
+
+    Ok(())
+}
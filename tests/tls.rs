@@ -0,0 +1,181 @@
+mod common;
+
+use kvs::{tls, KvsClient, KvsProxy, Result};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// A self-signed CA and the PEM files of the certificates it signed, written to
+/// a temporary directory.
+struct Certs {
+    dir: TempDir,
+    ca: Certificate,
+    ca_key: KeyPair,
+}
+
+impl Certs {
+    fn new() -> Certs {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+
+        Certs { dir, ca, ca_key }
+    }
+
+    fn ca(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    // Signs a certificate for `name` and returns the paths to the cert and key.
+    fn sign(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (PathBuf, PathBuf) {
+        let mut params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+
+        let cert_path = self.dir.path().join(format!("{}.pem", name));
+        let key_path = self.dir.path().join(format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+
+        (cert_path, key_path)
+    }
+}
+
+// Starts a TLS server on a free port in the background.
+fn start_server(certs: &Certs, client_ca: Option<&Path>) -> (SocketAddr, TempDir) {
+    let (cert, key) = certs.sign("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+    let config = tls::server_config(&cert, &key, client_ca).unwrap();
+
+    common::start_server_with(|server, _| server.with_tls(config))
+}
+
+// Should be able to set, get and remove a key over TLS.
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let certs = Certs::new();
+    let (addr, _data) = start_server(&certs, None);
+
+    let config = tls::client_config(&certs.ca(), None)?;
+    let mut client = KvsClient::connect_tls(addr, "localhost", config)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(client.get("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should refuse a server certificate not signed by the trusted CA.
+#[test]
+fn untrusted_server_certificate() -> Result<()> {
+    let (addr, _data) = start_server(&Certs::new(), None);
+
+    let config = tls::client_config(&Certs::new().ca(), None)?;
+    let mut client = KvsClient::connect_tls(addr, "localhost", config)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should refuse a server certificate issued for a different name.
+#[test]
+fn wrong_server_name() -> Result<()> {
+    let certs = Certs::new();
+    let (addr, _data) = start_server(&certs, None);
+
+    let config = tls::client_config(&certs.ca(), None)?;
+    let mut client = KvsClient::connect_tls(addr, "example.com", config)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should only accept clients presenting a certificate signed by the client CA
+// when mutual TLS is enabled, and keep serving after rejecting one.
+#[test]
+fn mutual_tls() -> Result<()> {
+    let certs = Certs::new();
+    let (addr, _data) = start_server(&certs, Some(&certs.ca()));
+
+    let config = tls::client_config(&certs.ca(), None)?;
+    let mut client = KvsClient::connect_tls(addr, "localhost", config)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    let untrusted = Certs::new();
+    let (cert, key) = untrusted.sign("client", ExtendedKeyUsagePurpose::ClientAuth);
+    let config = tls::client_config(&certs.ca(), Some((&cert, &key)))?;
+    let mut client = KvsClient::connect_tls(addr, "localhost", config)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    let (cert, key) = certs.sign("client", ExtendedKeyUsagePurpose::ClientAuth);
+    let config = tls::client_config(&certs.ca(), Some((&cert, &key)))?;
+    let mut client = KvsClient::connect_tls(addr, "localhost", config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should replicate from a leader serving TLS.
+#[test]
+fn replica_of_tls_leader() -> Result<()> {
+    let certs = Certs::new();
+    let (leader, _leader_data) = start_server(&certs, None);
+
+    let config = tls::client_config(&certs.ca(), None)?;
+    let mut client = KvsClient::connect_tls(leader, "localhost", config.clone())?;
+    let seq = client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let (replica, _replica_data) = common::start_server_with(|server, _| {
+        server.with_replica_of_client(
+            KvsClient::builder(leader.to_string()).with_tls("localhost".to_owned(), config),
+        )
+    });
+    let mut reader = KvsClient::connect(replica)?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while reader.status()?.seq < seq {
+        assert!(Instant::now() < deadline, "replica didn't catch up");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should serve TLS through a proxy connecting to the server over TLS.
+#[test]
+fn proxy_tls() -> Result<()> {
+    let certs = Certs::new();
+    let (server, _data) = start_server(&certs, None);
+
+    let (cert, key) = certs.sign("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+    let backend = format!("localhost:{}", server.port());
+    let proxy = KvsProxy::new(vec![backend], 2)?
+        .with_tls(tls::server_config(&cert, &key, None)?)
+        .with_backend_tls(tls::client_config(&certs.ca(), None)?);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || proxy.serve(listener));
+
+    let config = tls::client_config(&certs.ca(), None)?;
+    let mut client = KvsClient::connect_tls(addr, "localhost", config.clone())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut direct = KvsClient::connect_tls(server, "localhost", config)?;
+    assert_eq!(direct.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}